@@ -0,0 +1,6884 @@
+//! Payments engine behind the pieuvre CLI: a `Ledger` applies deposits,
+//! withdrawals, disputes, resolves and chargebacks to client `Account`s.
+//!
+//! ```
+//! use pieuvre::{Ledger, Transaction, TransactionType};
+//! use rust_decimal_macros::dec;
+//!
+//! let mut ledger = Ledger::new();
+//! ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(2.5)))).unwrap();
+//! ledger.process(&Transaction::new(TransactionType::Withdrawal, 1, 2, Some(dec!(1.0)))).unwrap();
+//! assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.5));
+//! ```
+
+use std::fs::{File, OpenOptions};
+use csv::{ByteRecord, Reader, StringRecord, Writer, WriterBuilder};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use rust_decimal::{Decimal, RoundingStrategy};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal_macros::dec;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Output of dispute_graph.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+/// Format of the accounts output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+}
+
+/// Format of the transactions input: CSV, or one JSON object per line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    Jsonl,
+}
+
+/// Format of an Explanation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExplainFormat {
+    Text,
+    Json,
+}
+
+/// Rows of a client merged away by a merge row
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum MergedClients {
+    /// Applied to the account the client was merged into
+    Redirect,
+    #[default]
+    Reject,
+}
+
+/// Rows bearing id 0 in their client or tx column, which some upstreams use as
+/// a "not applicable" sentinel
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SentinelIds {
+    Reject,
+    /// Skipped without being reported
+    Ignore,
+    /// Applied as ordinary ids
+    #[default]
+    Allow,
+}
+
+// Decimal places of amounts and balances
+const AMOUNT_DECIMALS: u32 = 4;
+
+/// Rounds to AMOUNT_DECIMALS with ties to the even digit, so 0.00005 rounds
+/// down to 0 and 0.00015 up to 0.0002, without drifting either way on average
+pub fn round_amount(amount: Decimal) -> Decimal {
+    amount.round_dp_with_strategy(AMOUNT_DECIMALS, RoundingStrategy::MidpointNearestEven).normalize()
+}
+
+// Largest deposit or withdrawal amount, anything above is taken for a
+// corrupted field
+const MAX_AMOUNT: Decimal = dec!(1_000_000_000_000);
+
+// Input rows kept by the ledger for the crash report
+const RECENT_ROWS: usize = 8;
+
+/// Columns of a transactions input, in order.
+pub const INPUT_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+const REQUIRED_INPUT_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+
+const ACCOUNT_COLUMNS: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+/// Why a header is not the one of a transactions input.
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    /// The file is an accounts output of pieuvre rather than transactions
+    AccountsOutput,
+    MissingColumns(Vec<String>),
+}
+
+impl HeaderError {
+    fn code(&self) -> &'static str {
+        match self {
+            HeaderError::AccountsOutput => "ACCOUNTS_OUTPUT_AS_INPUT",
+            HeaderError::MissingColumns(_) => "MISSING_COLUMNS",
+        }
+    }
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeaderError::AccountsOutput => write!(
+                f,
+                "[{}] this looks like accounts output of pieuvre ({}), not a transactions file. Pass the transactions file instead, its header is {}",
+                self.code(),
+                ACCOUNT_COLUMNS.join(","),
+                INPUT_COLUMNS.join(","),
+            ),
+            HeaderError::MissingColumns(columns) => write!(
+                f,
+                "[{}] missing column(s) {}, the expected header is {}",
+                self.code(),
+                columns.join(","),
+                INPUT_COLUMNS.join(","),
+            ),
+        }
+    }
+}
+
+/// Checks that a header names the columns of a transactions input.
+pub fn validate_headers(headers: &StringRecord) -> Result<(), HeaderError> {
+    let columns: Vec<&str> = headers.iter().map(|column| column.trim()).collect();
+    if columns == ACCOUNT_COLUMNS {
+        return Err(HeaderError::AccountsOutput);
+    }
+    let missing: Vec<String> = REQUIRED_INPUT_COLUMNS.iter()
+        .filter(|required| !columns.contains(required))
+        .map(|required| required.to_string())
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(HeaderError::MissingColumns(missing))
+    }
+}
+
+/// Why an input is obviously not a text CSV, found by sniff_input.
+#[derive(Debug, PartialEq)]
+pub enum InputFormatError {
+    /// Name of the compression and of the tool undoing it
+    Compressed(&'static str, &'static str),
+    /// Name of a binary columnar format
+    Columnar(&'static str),
+    Utf16,
+    Binary,
+}
+
+impl InputFormatError {
+    fn code(&self) -> &'static str {
+        match self {
+            InputFormatError::Compressed(..) => "COMPRESSED_INPUT",
+            InputFormatError::Columnar(_) => "COLUMNAR_INPUT",
+            InputFormatError::Utf16 => "UTF16_INPUT",
+            InputFormatError::Binary => "BINARY_INPUT",
+        }
+    }
+}
+
+impl std::fmt::Display for InputFormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InputFormatError::Compressed(compression, tool) => write!(
+                f,
+                "[{}] this is a {} compressed file, decompress it first (e.g. with {})",
+                self.code(),
+                compression,
+                tool,
+            ),
+            InputFormatError::Columnar(format) => write!(
+                f,
+                "[{}] this file is in the {} format, export it to CSV first",
+                self.code(),
+                format,
+            ),
+            InputFormatError::Utf16 => write!(
+                f,
+                "[{}] this file is UTF-16 encoded, convert it to UTF-8 first (e.g. with iconv -f UTF-16 -t UTF-8)",
+                self.code(),
+            ),
+            InputFormatError::Binary => write!(f, "[{}] not a text CSV, the file is mostly binary", self.code()),
+        }
+    }
+}
+
+/// Recognizes inputs that are obviously not a text CSV from their first bytes,
+/// before their header is read as one
+pub fn sniff_input(head: &[u8]) -> Result<(), InputFormatError> {
+    if head.starts_with(&[0x1f, 0x8b]) {
+        return Err(InputFormatError::Compressed("gzip", "gunzip"));
+    }
+    if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        return Err(InputFormatError::Compressed("zstd", "unzstd"));
+    }
+    if head.starts_with(b"PAR1") {
+        return Err(InputFormatError::Columnar("Parquet"));
+    }
+    if head.starts_with(b"Obj\x01") {
+        return Err(InputFormatError::Columnar("Avro"));
+    }
+    if head.starts_with(&[0xff, 0xfe]) || head.starts_with(&[0xfe, 0xff]) {
+        return Err(InputFormatError::Utf16);
+    }
+    // A few stray NULs can be garbage in a row, more than 1% is no text
+    let nuls = head.iter().filter(|byte| **byte == 0).count();
+    if nuls * 100 > head.len() {
+        return Err(InputFormatError::Binary);
+    }
+    Ok(())
+}
+
+/// Maps the column names and type names of a partner's file onto ours
+#[derive(Debug)]
+pub struct InputMapping {
+    /// Our column name to the input's
+    pub columns: HashMap<String, String>,
+    /// The input's type name to ours
+    pub types: HashMap<String, String>,
+    /// Invalid UTF-8 is replaced rather than the row skipped
+    pub lossy_utf8: bool,
+    /// Separates the fields of the input, a comma unless told otherwise
+    pub delimiter: u8,
+    /// The input starts with a row, its fields in INPUT_COLUMNS order
+    pub no_headers: bool,
+}
+
+impl Default for InputMapping {
+    fn default() -> Self {
+        InputMapping {
+            columns: HashMap::new(),
+            types: HashMap::new(),
+            lossy_utf8: false,
+            delimiter: b',',
+            no_headers: false,
+        }
+    }
+}
+
+impl InputMapping {
+    /// Renames the mapped columns of the reader's header to ours
+    pub fn map_headers<R: Read>(&self, reader: &mut Reader<R>) -> csv::Result<()> {
+        let headers: StringRecord = reader.headers()?.iter()
+            .map(|column| {
+                self.columns.iter()
+                    .find(|(_, input_column)| input_column.as_str() == column.trim())
+                    .map_or(column, |(name, _)| name.as_str())
+            })
+            .collect();
+        reader.set_headers(headers);
+        Ok(())
+    }
+
+    /// Same as validate_headers, naming the input column of missing mapped columns
+    pub fn validate_headers(&self, headers: &StringRecord) -> Result<(), HeaderError> {
+        validate_headers(headers).map_err(|err| match err {
+            HeaderError::MissingColumns(columns) => HeaderError::MissingColumns(
+                columns.iter()
+                    .map(|column| match self.columns.get(column) {
+                        Some(input_column) => format!("{} (mapped to {})", column, input_column),
+                        None => column.clone(),
+                    })
+                    .collect(),
+            ),
+            err => err,
+        })
+    }
+
+    fn map_type(&self, record: &mut StringRecord, type_index: usize) {
+        if let Some(name) = record.get(type_index).and_then(|name| self.types.get(name.trim())) {
+            let position = record.position().cloned();
+            *record = record.iter()
+                .enumerate()
+                .map(|(index, field)| if index == type_index { name.as_str() } else { field })
+                .collect();
+            if let Some(position) = position {
+                record.set_position(Some(position));
+            }
+        }
+    }
+}
+
+/// Type of a transaction, read from the `type` column by name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Merges the client's account into the one of the client id in the amount column
+    Merge,
+    /// Puts the client's account under review, blocking its withdrawals
+    Review,
+    ClearReview,
+    /// Any other type name, processed by the handler registered under it
+    Custom(String),
+}
+
+impl TransactionType {
+    fn from_name(name: &str) -> TransactionType {
+        match name {
+            "deposit" => TransactionType::Deposit,
+            "withdrawal" => TransactionType::Withdrawal,
+            "dispute" => TransactionType::Dispute,
+            "resolve" => TransactionType::Resolve,
+            "chargeback" => TransactionType::Chargeback,
+            "merge" => TransactionType::Merge,
+            "review" => TransactionType::Review,
+            "clear_review" => TransactionType::ClearReview,
+            _ => TransactionType::Custom(name.to_string()),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Merge => "merge",
+            TransactionType::Review => "review",
+            TransactionType::ClearReview => "clear_review",
+            TransactionType::Custom(name) => name,
+        }
+    }
+}
+
+impl Serialize for TransactionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TransactionType, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(TransactionType::from_name(&name))
+    }
+}
+
+/// One row of a transactions file. Disputes, resolves and chargebacks
+/// reference an earlier deposit or withdrawal by its `transaction_id` and
+/// carry no amount.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+
+    #[serde(rename = "client")]
+    pub client_id: u16,
+
+    #[serde(rename(deserialize = "tx"))]
+    pub transaction_id: u32,
+
+    pub amount: Option<Decimal>,
+}
+
+impl Transaction {
+    /// A transaction of the type, client, id and amount, as read from a row.
+    pub fn new(transaction_type: TransactionType, client_id: u16, transaction_id: u32, amount: Option<Decimal>) -> Transaction {
+        Transaction {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+        }
+    }
+}
+
+// What disputes need of a retained deposit or withdrawal, much smaller than
+// the transaction itself
+#[derive(Debug, Clone, PartialEq)]
+struct StoredTx {
+    client_id: u16,
+    amount: Decimal,
+    kind: StoredKind,
+    state: DisputeState,
+    // A refused transaction is still retained, as not applied
+    applied: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StoredKind {
+    Deposit,
+    Withdrawal,
+}
+
+impl StoredKind {
+    fn transaction_type(&self) -> TransactionType {
+        match self {
+            StoredKind::Deposit => TransactionType::Deposit,
+            StoredKind::Withdrawal => TransactionType::Withdrawal,
+        }
+    }
+}
+
+/// Dispute state of a retained transaction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisputeState {
+    Normal,
+    Disputed,
+    ChargedBack,
+}
+
+// Every allowed transition, consulted by dispute, resolve and chargeback and
+// printed by the graph subcommand. Anything else is refused.
+const DISPUTE_TRANSITIONS: [(DisputeState, TransactionType, DisputeState); 3] = [
+    (DisputeState::Normal, TransactionType::Dispute, DisputeState::Disputed),
+    (DisputeState::Disputed, TransactionType::Resolve, DisputeState::Normal),
+    (DisputeState::Disputed, TransactionType::Chargeback, DisputeState::ChargedBack),
+];
+
+impl DisputeState {
+    fn name(&self) -> &'static str {
+        match self {
+            DisputeState::Normal => "normal",
+            DisputeState::Disputed => "disputed",
+            DisputeState::ChargedBack => "charged_back",
+        }
+    }
+
+    fn after(self, transaction_type: &TransactionType) -> Option<DisputeState> {
+        DISPUTE_TRANSITIONS.iter()
+            .find(|(from, on, _)| *from == self && on == transaction_type)
+            .map(|(_, _, to)| *to)
+    }
+}
+
+/// The dispute states and the transaction types moving between them, as a graph.
+pub fn dispute_graph(format: GraphFormat) -> String {
+    let mut graph = String::new();
+    match format {
+        GraphFormat::Dot => {
+            graph.push_str("digraph dispute {\n");
+            for (from, on, to) in &DISPUTE_TRANSITIONS {
+                graph.push_str(&format!("    {} -> {} [label=\"{}\"];\n", from.name(), to.name(), on.name()));
+            }
+            graph.push_str("}\n");
+        },
+        GraphFormat::Mermaid => {
+            graph.push_str("stateDiagram-v2\n");
+            for (from, on, to) in &DISPUTE_TRANSITIONS {
+                graph.push_str(&format!("    {} --> {}: {}\n", from.name(), to.name(), on.name()));
+            }
+        },
+    }
+    graph
+}
+
+/// Balances of a client. `total` is always `available + held`, and
+/// `locked` is set by a chargeback.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Account {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    /// Withdrawals are refused while set, never set on a locked account. Only
+    /// written by --review-column.
+    #[serde(skip)]
+    pub review_hold: bool,
+}
+
+impl Account {
+    fn new(client_id: u16) -> Account {
+        Account {
+            client_id,
+            available: dec!(0),
+            held: dec!(0),
+            total: dec!(0),
+            locked: false,
+            review_hold: false,
+        }
+    }
+
+    /// Rounds the balances with round_amount, which also strips the trailing
+    /// zeros Decimal accumulates across additions and subtractions. Called by
+    /// handlers after changing them.
+    pub fn round(&mut self) {
+        self.available = round_amount(self.available);
+        self.held = round_amount(self.held);
+        self.total = round_amount(self.total);
+    }
+}
+
+/// One bit per possible client id: 8KB whatever the input size
+#[derive(Debug)]
+pub struct ClientSet {
+    words: Box<[u64; 1024]>,
+}
+
+impl Default for ClientSet {
+    fn default() -> ClientSet {
+        ClientSet {
+            words: Box::new([0; 1024]),
+        }
+    }
+}
+
+impl ClientSet {
+    fn insert(&mut self, client_id: u16) {
+        self.words[client_id as usize / 64] |= 1 << (client_id % 64);
+    }
+
+    fn contains(&self, client_id: u16) -> bool {
+        self.words[client_id as usize / 64] & (1 << (client_id % 64)) != 0
+    }
+
+    // Yields client ids in increasing order
+    fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..=u16::MAX).filter(|client_id| self.contains(*client_id))
+    }
+}
+
+/// Counts every row of a client, whether or not it was applied
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Activity {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub tx_count: u64,
+    pub deposit_sum: Decimal,
+    pub withdrawal_sum: Decimal,
+    pub dispute_count: u64,
+}
+
+impl Activity {
+    fn new(client_id: u16) -> Activity {
+        Activity {
+            client_id,
+            tx_count: 0,
+            deposit_sum: dec!(0),
+            withdrawal_sum: dec!(0),
+            dispute_count: 0,
+        }
+    }
+
+    fn record(&mut self, transaction: &Transaction) {
+        self.tx_count += 1;
+        match transaction.transaction_type {
+            TransactionType::Deposit => self.deposit_sum += transaction.amount.unwrap_or_default(),
+            TransactionType::Withdrawal => self.withdrawal_sum += transaction.amount.unwrap_or_default(),
+            TransactionType::Dispute => self.dispute_count += 1,
+            _ => {},
+        }
+    }
+}
+
+/// Nearest-rank percentile over counts sorted in increasing order
+pub fn percentile(sorted_counts: &[u64], percent: usize) -> u64 {
+    if sorted_counts.is_empty() {
+        return 0;
+    }
+    let rank = (percent * sorted_counts.len()).div_ceil(100).max(1);
+    sorted_counts[rank - 1]
+}
+
+/// Activity level of a client sampled by --qa-sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stratum {
+    Heavy,
+    Medium,
+    Light,
+    /// Seen in the input but without an account, every transaction was rejected
+    RejectedOnly,
+}
+
+impl Stratum {
+    fn name(&self) -> &'static str {
+        match self {
+            Stratum::Heavy => "heavy",
+            Stratum::Medium => "medium",
+            Stratum::Light => "light",
+            Stratum::RejectedOnly => "rejected_only",
+        }
+    }
+}
+
+// splitmix64, so a --qa-seed picks the same sample on every machine
+struct SampleRng(u64);
+
+impl SampleRng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+/// Which deposits and withdrawals are retained so that later disputes can find them.
+#[derive(Default, Debug)]
+pub struct RetentionPolicy {
+    pub min_amount: Option<Decimal>,
+    pub clients: Option<HashSet<u16>>,
+}
+
+impl RetentionPolicy {
+    // The amount threshold is inclusive
+    fn retains(&self, transaction: &Transaction) -> bool {
+        if let Some(min_amount) = self.min_amount {
+            if transaction.amount.is_none_or(|amount| amount < min_amount) {
+                return false;
+            }
+        }
+        if let Some(clients) = &self.clients {
+            if !clients.contains(&transaction.client_id) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// An account whose balances differ from the ones recomputed by Ledger::recompute_check.
+#[derive(Debug, PartialEq)]
+pub struct BalanceMismatch {
+    pub client_id: u16,
+    pub available_delta: Decimal,
+    pub held_delta: Decimal,
+    pub total_delta: Decimal,
+}
+
+impl BalanceMismatch {
+    // Deltas are the live balance minus the expected one
+    fn between(live: &Account, expected: &Account) -> Option<BalanceMismatch> {
+        let mismatch = BalanceMismatch {
+            client_id: live.client_id,
+            available_delta: live.available - expected.available,
+            held_delta: live.held - expected.held,
+            total_delta: live.total - expected.total,
+        };
+        if mismatch.available_delta.is_zero() && mismatch.held_delta.is_zero() && mismatch.total_delta.is_zero() {
+            None
+        } else {
+            Some(mismatch)
+        }
+    }
+}
+
+/// Processes one transaction type. Handlers are looked up by type name, so
+/// registering one under a new name adds a transaction type, and registering
+/// one under a built-in name replaces that behavior.
+pub trait TransactionHandler: std::fmt::Debug + Send + Sync {
+    /// Checked before apply, an error skips the transaction with its message
+    fn validate(&self, _ledger: &Ledger, _transaction: &Transaction) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Applies the transaction through Ledger::account_mut,
+    /// Ledger::check_unique and Ledger::retain_credit
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError>;
+}
+
+#[derive(Debug)]
+struct DepositHandler;
+
+impl TransactionHandler for DepositHandler {
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+        ledger.deposit(transaction)
+    }
+}
+
+#[derive(Debug)]
+struct WithdrawalHandler;
+
+impl TransactionHandler for WithdrawalHandler {
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+        ledger.withdraw(transaction)
+    }
+}
+
+#[derive(Debug)]
+struct DisputeHandler;
+
+impl TransactionHandler for DisputeHandler {
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+        ledger.dispute(transaction)
+    }
+}
+
+#[derive(Debug)]
+struct ResolveHandler;
+
+impl TransactionHandler for ResolveHandler {
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+        ledger.resolve(transaction)
+    }
+}
+
+#[derive(Debug)]
+struct ChargebackHandler;
+
+impl TransactionHandler for ChargebackHandler {
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+        ledger.chargeback(transaction)
+    }
+}
+
+// Client id a merge row merges into, None unless the amount is one
+fn merge_target(transaction: &Transaction) -> Option<u16> {
+    transaction.amount.filter(|amount| amount.fract().is_zero()).and_then(|amount| amount.to_u16())
+}
+
+#[derive(Debug)]
+struct MergeHandler;
+
+impl TransactionHandler for MergeHandler {
+    fn validate(&self, ledger: &Ledger, transaction: &Transaction) -> Result<(), String> {
+        let target_id = merge_target(transaction)
+            .ok_or_else(|| format!("amount {:?} is not a client id to merge into", transaction.amount))?;
+        if target_id == transaction.client_id {
+            return Err("can't merge a client into itself".to_string());
+        }
+        if let Some(merged_id) = ledger.merged_into.get(&target_id) {
+            return Err(format!("client {} was merged into client {}", target_id, merged_id));
+        }
+        if !ledger.account_by_id.contains_key(&transaction.client_id) {
+            return Err("no account to merge".to_string());
+        }
+        Ok(())
+    }
+
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+        ledger.merge(transaction);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ReviewHandler;
+
+impl TransactionHandler for ReviewHandler {
+    fn validate(&self, ledger: &Ledger, transaction: &Transaction) -> Result<(), String> {
+        match ledger.account_by_id.get(&transaction.client_id) {
+            None => Err("no account to review".to_string()),
+            Some(account) if account.locked => Err("account is locked, which supersedes a review".to_string()),
+            Some(account) if account.review_hold => Err("account is already under review".to_string()),
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+        ledger.set_review_hold(transaction, true);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ClearReviewHandler;
+
+impl TransactionHandler for ClearReviewHandler {
+    fn validate(&self, ledger: &Ledger, transaction: &Transaction) -> Result<(), String> {
+        match ledger.account_by_id.get(&transaction.client_id) {
+            Some(account) if account.review_hold => Ok(()),
+            _ => Err("account is not under review".to_string()),
+        }
+    }
+
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+        ledger.set_review_hold(transaction, false);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct HandlerRegistry {
+    handler_by_type: HashMap<String, Arc<dyn TransactionHandler>>,
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> HandlerRegistry {
+        let mut registry = HandlerRegistry {
+            handler_by_type: HashMap::new(),
+        };
+        registry.register(TransactionType::Deposit, DepositHandler);
+        registry.register(TransactionType::Withdrawal, WithdrawalHandler);
+        registry.register(TransactionType::Dispute, DisputeHandler);
+        registry.register(TransactionType::Resolve, ResolveHandler);
+        registry.register(TransactionType::Chargeback, ChargebackHandler);
+        registry.register(TransactionType::Merge, MergeHandler);
+        registry.register(TransactionType::Review, ReviewHandler);
+        registry.register(TransactionType::ClearReview, ClearReviewHandler);
+        registry
+    }
+}
+
+impl HandlerRegistry {
+    fn register(&mut self, transaction_type: TransactionType, handler: impl TransactionHandler + 'static) {
+        self.handler_by_type.insert(transaction_type.name().to_string(), Arc::new(handler));
+    }
+
+    fn get(&self, transaction_type: &TransactionType) -> Option<Arc<dyn TransactionHandler>> {
+        self.handler_by_type.get(transaction_type.name()).cloned()
+    }
+}
+
+/// Field compared by a NearDuplicateWindow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NearDuplicateField {
+    Client,
+    Type,
+    Amount,
+}
+
+// Fields not compared are left out, so they always match
+#[derive(Debug, PartialEq)]
+struct NearDuplicateKey {
+    client_id: Option<u16>,
+    transaction_type: Option<TransactionType>,
+    amount: Option<Decimal>,
+}
+
+/// Heuristic catching a producer that sends the same deposit or withdrawal
+/// twice under different tx ids a few rows apart
+#[derive(Debug)]
+pub struct NearDuplicateWindow {
+    rows: u64,
+    fields: Vec<NearDuplicateField>,
+    row: u64,
+    // Row number, tx id and key of the deposits and withdrawals in the window
+    recent: VecDeque<(u64, u32, NearDuplicateKey)>,
+}
+
+impl NearDuplicateWindow {
+    /// A window over the last rows rows, matching on the fields.
+    pub fn new(rows: u64, fields: Vec<NearDuplicateField>) -> NearDuplicateWindow {
+        NearDuplicateWindow {
+            rows,
+            fields,
+            row: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    fn key(&self, transaction: &Transaction) -> NearDuplicateKey {
+        let compares = |field| self.fields.contains(&field);
+        NearDuplicateKey {
+            client_id: compares(NearDuplicateField::Client).then_some(transaction.client_id),
+            transaction_type: compares(NearDuplicateField::Type).then(|| transaction.transaction_type.clone()),
+            amount: if compares(NearDuplicateField::Amount) { transaction.amount } else { None },
+        }
+    }
+
+    // Called for every row, returns the tx id of the earlier transaction the
+    // row duplicates if any
+    fn check(&mut self, transaction: &Transaction) -> Option<u32> {
+        self.row += 1;
+        while self.recent.front().is_some_and(|(row, _, _)| self.row - row > self.rows) {
+            self.recent.pop_front();
+        }
+
+        if !matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal) {
+            return None;
+        }
+        let key = self.key(transaction);
+        if let Some((_, earlier_id, _)) = self.recent.iter()
+            .find(|(_, transaction_id, recent_key)| *recent_key == key && *transaction_id != transaction.transaction_id)
+        {
+            return Some(*earlier_id);
+        }
+        self.recent.push_back((self.row, transaction.transaction_id, key));
+        None
+    }
+}
+
+// Funds held by an open dispute
+#[derive(Debug, Clone, PartialEq)]
+struct Hold {
+    amount: Decimal,
+    // Ordinal of the dispute row among the rows processed by the ledger
+    opened_row: u64,
+    origin: TransactionType,
+}
+
+fn release_hold(open_holds_by_client: &mut HashMap<u16, BTreeMap<u32, Hold>>, client_id: u16, transaction_id: u32) {
+    if let Some(holds) = open_holds_by_client.get_mut(&client_id) {
+        holds.remove(&transaction_id);
+        if holds.is_empty() {
+            open_holds_by_client.remove(&client_id);
+        }
+    }
+}
+
+/// Open dispute hold, a row of Ledger::holds_report.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HoldRow {
+    client: u16,
+    tx: u32,
+    held: Decimal,
+    opened_row: u64,
+    origin: TransactionType,
+}
+
+/// Balances of an account with open disputes, as they are and as they would be
+/// if every open dispute were charged back or resolved
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ProjectionRow {
+    client: u16,
+    open_disputes: usize,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    chargeback_available: Decimal,
+    chargeback_held: Decimal,
+    chargeback_total: Decimal,
+    chargeback_locked: bool,
+    resolve_available: Decimal,
+    resolve_held: Decimal,
+    resolve_total: Decimal,
+    resolve_locked: bool,
+}
+
+// A hash map entry costs its key, its value and one control byte
+const fn entry_size<K, V>() -> usize {
+    size_of::<K>() + size_of::<V>() + 1
+}
+
+/// What a loaded ledger costs. Bytes are approximated from element counts
+/// and struct sizes, so the report is cheap enough for --memory-budget to
+/// compute it for every row. It ignores the spare capacity of the maps and
+/// the heap data of custom type names.
+#[derive(Debug, PartialEq)]
+pub struct MemoryReport {
+    accounts: usize,
+    retained_transactions: usize,
+    open_disputes: usize,
+    // The ledger itself and the clients seen bitset
+    fixed_bytes: usize,
+    transactions_bytes: usize,
+    accounts_bytes: usize,
+    unretained_bytes: usize,
+    activity_bytes: usize,
+    holds_bytes: usize,
+    retention_bytes: usize,
+}
+
+impl MemoryReport {
+    /// Estimated bytes of the whole ledger.
+    pub fn total_bytes(&self) -> usize {
+        self.fixed_bytes
+            + self.transactions_bytes
+            + self.accounts_bytes
+            + self.unretained_bytes
+            + self.activity_bytes
+            + self.holds_bytes
+            + self.retention_bytes
+    }
+}
+
+impl std::fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} accounts, {} retained transactions, {} open disputes, approximately {} bytes (fixed {}, transactions {}, accounts {}, unretained ids {}, activity {}, holds {}, retention {})",
+            self.accounts,
+            self.retained_transactions,
+            self.open_disputes,
+            self.total_bytes(),
+            self.fixed_bytes,
+            self.transactions_bytes,
+            self.accounts_bytes,
+            self.unretained_bytes,
+            self.activity_bytes,
+            self.holds_bytes,
+            self.retention_bytes,
+        )
+    }
+}
+
+/// What a run did, printed by --stats. The counters are kept by the ledger
+/// as it processes rows, the account figures are taken when asked for.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Transactions read by type name, whatever became of them
+    pub read_by_type: BTreeMap<String, u64>,
+    pub applied: u64,
+    /// Rejected and unreadable rows by reason code
+    pub rejected_by_reason: BTreeMap<String, u64>,
+    pub accounts: usize,
+    pub locked_accounts: usize,
+    pub total_held: Decimal,
+}
+
+impl Stats {
+    /// Rows rejected, whatever the reason.
+    pub fn rejected(&self) -> u64 {
+        self.rejected_by_reason.values().sum()
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let read: Vec<String> = self.read_by_type.iter().map(|(name, count)| format!("{} {}", count, name)).collect();
+        writeln!(f, "Read: {}", if read.is_empty() { "nothing".to_string() } else { read.join(", ") })?;
+        write!(f, "Applied: {}, rejected: {}", self.applied, self.rejected())?;
+        if !self.rejected_by_reason.is_empty() {
+            let reasons: Vec<String> = self.rejected_by_reason.iter().map(|(reason, count)| format!("{} {}", reason, count)).collect();
+            write!(f, " ({})", reasons.join(", "))?;
+        }
+        write!(
+            f,
+            "\nAccounts: {}, locked: {}, total held: {}",
+            self.accounts, self.locked_accounts, self.total_held.normalize(),
+        )
+    }
+}
+
+/// An input row that wasn't applied: a transaction the ledger rejected or a
+/// row that couldn't be read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub line: u64,
+    pub reason: &'static str,
+    pub message: String,
+}
+
+/// What a row did besides changing balances, for the CLI to report: the
+/// library itself never writes to stderr.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notice {
+    /// A row that isn't a transaction, skipped
+    Skipped(String),
+    /// A transaction the ledger didn't apply
+    Rejected(LedgerError),
+    ReviewHold { client: u16, transaction: u32, review_hold: bool },
+    Merged { client: u16, transaction: u32, target: u16 },
+}
+
+impl std::fmt::Display for Notice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Notice::Skipped(message) => write!(f, "{}, skipped", message),
+            Notice::Rejected(err) => write!(f, "{}", err),
+            Notice::ReviewHold { client, transaction, review_hold } => write!(
+                f,
+                "Client {} {} by transaction {}",
+                client,
+                if *review_hold { "put under review" } else { "cleared from review" },
+                transaction,
+            ),
+            Notice::Merged { client, transaction, target } => write!(
+                f,
+                "Client {} merged into client {} by transaction {}",
+                client, target, transaction,
+            ),
+        }
+    }
+}
+
+/// One rule of Ledger::rule_trace and whether the transaction passed it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleCheck {
+    pub rule: &'static str,
+    pub passed: bool,
+}
+
+/// Why Ledger::process didn't apply a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedgerError {
+    /// Skipped by --near-dup-window as a repeat of the earlier transaction
+    NearDuplicate { transaction: u32, client: u16, earlier: u32 },
+    /// The client was merged into target and merged clients are rejected
+    MergedClient { transaction: u32, client: u16, target: u16 },
+    UnknownType { transaction: u32, transaction_type: TransactionType },
+    /// Refused by the validate of the type's handler
+    Invalid { transaction: u32, transaction_type: TransactionType, client: u16, reason: String },
+    /// Client or tx id 0 refused by --sentinel-ids reject
+    SentinelId { transaction: u32, client: u16 },
+    /// A deposit or withdrawal without an amount
+    MissingAmount(u32),
+    /// A deposit or withdrawal amount that isn't positive or is above MAX_AMOUNT
+    InvalidAmount { transaction: u32, client: u16, amount: Decimal },
+    /// A deposit or withdrawal reusing the id of an earlier one, which is kept
+    DuplicateTransaction { transaction: u32, client: u16 },
+    UnknownAccount(u16),
+    /// The account was locked by a chargeback
+    AccountLocked { operation: TransactionType, transaction: u32, client: u16, amount: Decimal },
+    UnderReview { client: u16, requested: Decimal },
+    InsufficientFunds { operation: TransactionType, client: u16, requested: Decimal, available: Decimal },
+    InsufficientHeldFunds { operation: TransactionType, client: u16, requested: Decimal, held: Decimal },
+    /// The referenced transaction was dropped by the retention policy
+    NotRetained { operation: TransactionType, transaction: u32 },
+    UnknownTransaction { operation: TransactionType, transaction: u32 },
+    /// The referenced transaction belongs to owner, not to client
+    ClientMismatch { operation: TransactionType, transaction: u32, client: u16, owner: u16 },
+    /// The referenced transaction's dispute state doesn't allow the operation
+    InvalidDisputeState { operation: TransactionType, transaction: u32, client: u16, state: DisputeState },
+    /// The referenced transaction was refused, so there is nothing to dispute
+    NotApplied { operation: TransactionType, transaction: u32, client: u16 },
+}
+
+impl LedgerError {
+    /// Stable code of the error, for reports and statistics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LedgerError::NearDuplicate { .. } => "NEAR_DUPLICATE",
+            LedgerError::MergedClient { .. } => "MERGED_CLIENT",
+            LedgerError::UnknownType { .. } => "UNKNOWN_TYPE",
+            LedgerError::Invalid { .. } => "INVALID_TRANSACTION",
+            LedgerError::SentinelId { .. } => "SENTINEL_ID",
+            LedgerError::MissingAmount(_) => "MISSING_AMOUNT",
+            LedgerError::InvalidAmount { .. } => "INVALID_AMOUNT",
+            LedgerError::DuplicateTransaction { .. } => "DUPLICATE_TRANSACTION",
+            LedgerError::UnknownAccount(_) => "UNKNOWN_ACCOUNT",
+            LedgerError::AccountLocked { .. } => "ACCOUNT_LOCKED",
+            LedgerError::UnderReview { .. } => "UNDER_REVIEW",
+            LedgerError::InsufficientFunds { .. } => "INSUFFICIENT_FUNDS",
+            LedgerError::InsufficientHeldFunds { .. } => "INSUFFICIENT_HELD_FUNDS",
+            LedgerError::NotRetained { .. } => "NOT_RETAINED",
+            LedgerError::UnknownTransaction { .. } => "UNKNOWN_TRANSACTION",
+            LedgerError::ClientMismatch { .. } => "CLIENT_MISMATCH",
+            LedgerError::InvalidDisputeState { .. } => "INVALID_DISPUTE_STATE",
+            LedgerError::NotApplied { .. } => "NOT_APPLIED",
+        }
+    }
+}
+
+impl std::fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LedgerError::NearDuplicate { transaction, client, earlier } => write!(
+                f,
+                "Transaction {} for client {} looks like a near-duplicate of transaction {}, not applied",
+                transaction, client, earlier,
+            ),
+            LedgerError::MergedClient { transaction, client, target } => write!(
+                f,
+                "Transaction {} for client {} is rejected as the client was merged into client {}",
+                transaction, client, target,
+            ),
+            LedgerError::UnknownType { transaction, transaction_type } => write!(
+                f,
+                "Unknown transaction type {} for transaction {}",
+                transaction_type.name(), transaction,
+            ),
+            LedgerError::Invalid { transaction, transaction_type, client, reason } => write!(
+                f,
+                "Transaction {} of type {} for client {} is invalid: {}",
+                transaction, transaction_type.name(), client, reason,
+            ),
+            LedgerError::SentinelId { transaction, client } => write!(
+                f,
+                "Transaction {} for client {} bears the sentinel id 0, not applied",
+                transaction, client,
+            ),
+            LedgerError::MissingAmount(transaction) => write!(f, "Transaction {} has no amount", transaction),
+            LedgerError::InvalidAmount { transaction, client, amount } => write!(
+                f,
+                "Transaction {} for client {} has an invalid amount {}, expected more than 0 and at most {}",
+                transaction, client, amount, MAX_AMOUNT,
+            ),
+            LedgerError::DuplicateTransaction { transaction, client } => write!(
+                f,
+                "Transaction {} for client {} reuses the id of an earlier transaction, not applied",
+                transaction, client,
+            ),
+            LedgerError::UnknownAccount(client) => write!(f, "Client {} has no account", client),
+            LedgerError::AccountLocked { operation, transaction, client, amount } => write!(
+                f,
+                "{} {} of {} for client {} is refused as the account is locked",
+                operation.name(), transaction, amount, client,
+            ),
+            LedgerError::UnderReview { client, requested } => write!(
+                f,
+                "Withdrawal of {} from client {} is blocked as the account is under review",
+                requested, client,
+            ),
+            LedgerError::InsufficientFunds { operation, client, requested, available } => write!(
+                f,
+                "{} of {} for client {} is impossible due to insufficient available funds ({})",
+                operation.name(), requested, client, available,
+            ),
+            LedgerError::InsufficientHeldFunds { operation, client, requested, held } => write!(
+                f,
+                "{} of {} for client {} is impossible due to insufficient held funds ({})",
+                operation.name(), requested, client, held,
+            ),
+            LedgerError::NotRetained { operation, transaction } => write!(
+                f,
+                "Transaction id {} was not retained by policy, can't {} it",
+                transaction, operation.name(),
+            ),
+            LedgerError::UnknownTransaction { operation, transaction } => write!(
+                f,
+                "Can't find transaction id {} to {}",
+                transaction, operation.name(),
+            ),
+            LedgerError::ClientMismatch { operation, transaction, client, owner } => write!(
+                f,
+                "Can't {} transaction {} for client {} as it belongs to client {}",
+                operation.name(), transaction, client, owner,
+            ),
+            LedgerError::InvalidDisputeState { operation, transaction, client, state } => write!(
+                f,
+                "Can't {} transaction {} for client {} as it is {}",
+                operation.name(), transaction, client, state.name(),
+            ),
+            LedgerError::NotApplied { operation, transaction, client } => write!(
+                f,
+                "Can't {} transaction {} for client {} as it was never applied",
+                operation.name(), transaction, client,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+// Amount of a deposit or withdrawal, which must be there, positive and not
+// absurdly large. Rows failing this are neither applied nor retained.
+fn checked_amount(transaction: &Transaction) -> Result<Decimal, LedgerError> {
+    let amount = transaction.amount.ok_or(LedgerError::MissingAmount(transaction.transaction_id))?;
+    if amount <= Decimal::ZERO || amount > MAX_AMOUNT {
+        return Err(LedgerError::InvalidAmount {
+            transaction: transaction.transaction_id,
+            client: transaction.client_id,
+            amount,
+        });
+    }
+    Ok(amount)
+}
+
+/// What became of every input row of a run, one CSV row each:
+///   row        ordinal of the input row in the run, from 1
+///   line       line of the row in its input file
+///   type, client, tx, amount  as read, empty for a row that isn't a transaction
+///   outcome    applied, rejected, or ignored for a dispute, resolve or
+///              chargeback of an unknown transaction or another client's and
+///              for a sentinel id under --sentinel-ids ignore
+///   reason     code of the rejection or of why the row was ignored
+///   available, held  of the row's client right after it, empty without an account
+pub struct AuditLog {
+    writer: Writer<Box<dyn Write + Send>>,
+    rows: u64,
+    // First write that failed, the rows after it are dropped
+    error: Option<csv::Error>,
+}
+
+impl AuditLog {
+    /// An audit log written to the writer, starting with its header.
+    pub fn new(writer: Box<dyn Write + Send>) -> AuditLog {
+        let mut audit = AuditLog {
+            writer: WriterBuilder::new().has_headers(false).from_writer(writer),
+            rows: 0,
+            error: None,
+        };
+        let header = ["row", "line", "type", "client", "tx", "amount", "outcome", "reason", "available", "held"];
+        if let Err(err) = audit.writer.write_record(header) {
+            audit.error = Some(err);
+        }
+        audit
+    }
+
+    fn record(&mut self, line: u64, transaction: Option<&Transaction>, outcome: &str, reason: &str, account: Option<Account>) {
+        self.rows += 1;
+        if self.error.is_some() {
+            return;
+        }
+        let (transaction_type, client, tx, amount) = match transaction {
+            Some(transaction) => (
+                transaction.transaction_type.name().to_string(),
+                transaction.client_id.to_string(),
+                transaction.transaction_id.to_string(),
+                transaction.amount.map(|amount| amount.to_string()).unwrap_or_default(),
+            ),
+            None => Default::default(),
+        };
+        let (available, held) = match account {
+            Some(account) => (account.available.normalize().to_string(), account.held.normalize().to_string()),
+            None => Default::default(),
+        };
+        let written = self.writer.write_record([
+            &self.rows.to_string(), &line.to_string(), &transaction_type, &client, &tx, &amount, outcome, reason, &available, &held,
+        ]);
+        if let Err(err) = written {
+            self.error = Some(err);
+        }
+    }
+
+    /// Flushes the log, failing if any row couldn't be written
+    pub fn finish(&mut self) -> csv::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AuditLog").field("rows", &self.rows).finish()
+    }
+}
+
+/// Accounts of every client, built by processing transactions in order.
+#[derive(Default, Debug)]
+pub struct Ledger {
+    transactions_by_id: HashMap<u32, StoredTx>,
+    account_by_id: HashMap<u16, Account>,
+    retention: RetentionPolicy,
+    unretained_ids: HashSet<u32>,
+    pub clients_seen: ClientSet,
+    activity_by_id: HashMap<u16, Activity>,
+    handlers: HandlerRegistry,
+    pub near_duplicates: Option<NearDuplicateWindow>,
+    rows_processed: u64,
+    // Open disputes by client then transaction id, their amounts add up to
+    // the account's held funds
+    open_holds_by_client: HashMap<u16, BTreeMap<u32, Hold>>,
+    // Client merged away to the client it was merged into
+    merged_into: HashMap<u16, u16>,
+    // Last input rows read, the newest last, for the crash report
+    recent_rows: VecDeque<StringRecord>,
+    pub merged_clients: MergedClients,
+    pub sentinel_ids: SentinelIds,
+    /// Rows bearing a sentinel id, whatever was done with them
+    pub sentinel_rows: u64,
+    pub audit: Option<AuditLog>,
+    stats: Stats,
+    /// Rows not applied, collected when set, for --dry-run
+    pub rejections: Option<Vec<Rejection>>,
+    /// Rows skipped or rejected, reviews and merges, collected when set for
+    /// the CLI to print
+    pub notices: Option<Vec<Notice>>,
+    pub progress: Option<Progress>,
+}
+
+impl Ledger {
+    /// Empty ledger retaining every transaction for later disputes.
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    /// Empty ledger only retaining the transactions the policy accepts, so
+    /// disputes of the others are rejected.
+    pub fn with_retention(retention: RetentionPolicy) -> Ledger {
+        Ledger {
+            retention,
+            ..Default::default()
+        }
+    }
+
+    /// Applies one transaction. A transaction that can't be applied leaves
+    /// the accounts unchanged and returns why.
+    pub fn process(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        let result = self.apply_transaction(transaction);
+        *self.stats.read_by_type.entry(transaction.transaction_type.name().to_string()).or_default() += 1;
+        match &result {
+            // A sentinel row skipped by --sentinel-ids ignore is neither
+            Ok(()) if self.sentinel_ids == SentinelIds::Ignore
+                && (transaction.client_id == 0 || transaction.transaction_id == 0) => {},
+            Ok(()) => self.stats.applied += 1,
+            Err(err) => *self.stats.rejected_by_reason.entry(err.code().to_string()).or_default() += 1,
+        }
+        result
+    }
+
+    fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        self.rows_processed += 1;
+        if transaction.client_id == 0 || transaction.transaction_id == 0 {
+            self.sentinel_rows += 1;
+            match self.sentinel_ids {
+                SentinelIds::Reject => {
+                    return Err(LedgerError::SentinelId {
+                        transaction: transaction.transaction_id,
+                        client: transaction.client_id,
+                    });
+                },
+                SentinelIds::Ignore => return Ok(()),
+                SentinelIds::Allow => {},
+            }
+        }
+        self.clients_seen.insert(transaction.client_id);
+        self.activity_by_id
+            .entry(transaction.client_id)
+            .or_insert_with(|| Activity::new(transaction.client_id))
+            .record(transaction);
+
+        if let Some(window) = &mut self.near_duplicates {
+            if let Some(earlier_id) = window.check(transaction) {
+                return Err(LedgerError::NearDuplicate {
+                    transaction: transaction.transaction_id,
+                    client: transaction.client_id,
+                    earlier: earlier_id,
+                });
+            }
+        }
+
+        // Amounts are rounded before anything is applied or retained, so that a
+        // dispute holds what was deposited. Errors still report the amount read.
+        let input_amount = transaction.amount;
+        let rounded;
+        let transaction = match transaction.amount {
+            Some(amount) if amount.scale() > AMOUNT_DECIMALS => {
+                rounded = Transaction { amount: Some(round_amount(amount)), ..transaction.clone() };
+                &rounded
+            },
+            _ => transaction,
+        };
+
+        let redirected;
+        let transaction = match (self.merged_target(transaction.client_id), self.merged_clients) {
+            (Some(target_id), MergedClients::Redirect) => {
+                redirected = Transaction { client_id: target_id, ..transaction.clone() };
+                &redirected
+            },
+            (Some(target_id), MergedClients::Reject) => {
+                return Err(LedgerError::MergedClient {
+                    transaction: transaction.transaction_id,
+                    client: transaction.client_id,
+                    target: target_id,
+                });
+            },
+            (None, _) => transaction,
+        };
+
+        let handler = self.handlers.get(&transaction.transaction_type).ok_or_else(|| LedgerError::UnknownType {
+            transaction: transaction.transaction_id,
+            transaction_type: transaction.transaction_type.clone(),
+        })?;
+        handler.validate(self, transaction).map_err(|reason| LedgerError::Invalid {
+            transaction: transaction.transaction_id,
+            transaction_type: transaction.transaction_type.clone(),
+            client: transaction.client_id,
+            reason,
+        })?;
+        handler.apply(self, transaction).map_err(|err| match (err, input_amount) {
+            (LedgerError::InvalidAmount { transaction, client, .. }, Some(amount)) => {
+                LedgerError::InvalidAmount { transaction, client, amount }
+            },
+            (err, _) => err,
+        })
+    }
+
+    /// Processes the transactions of the type with the handler from now on,
+    /// e.g. a TransactionType::Custom("bonus") the built-in ones don't know,
+    /// or a built-in type whose behavior it replaces.
+    pub fn register_handler(&mut self, transaction_type: TransactionType, handler: impl TransactionHandler + 'static) {
+        self.handlers.register(transaction_type, handler);
+    }
+
+    /// Account of the client, opened empty if it doesn't exist yet, for a
+    /// handler to change. total must stay available + held.
+    pub fn account_mut(&mut self, client_id: u16) -> &mut Account {
+        self.account_by_id.entry(client_id).or_insert_with(|| Account::new(client_id))
+    }
+
+    /// Retains an applied transaction crediting amount like a deposit, as the
+    /// retention policy allows, so that later disputes, resolves and
+    /// chargebacks of its id hold, release and reverse amount.
+    pub fn retain_credit(&mut self, transaction: &Transaction, amount: Decimal) {
+        self.retain(transaction, StoredKind::Deposit, amount, true);
+    }
+
+    fn retain(&mut self, transaction: &Transaction, kind: StoredKind, amount: Decimal, applied: bool) {
+        if self.retention.retains(transaction) {
+            self.transactions_by_id.insert(transaction.transaction_id, StoredTx {
+                client_id: transaction.client_id,
+                amount,
+                kind,
+                state: DisputeState::Normal,
+                applied,
+            });
+        } else {
+            self.unretained_ids.insert(transaction.transaction_id);
+        }
+    }
+
+    /// Refuses a transaction whose id an earlier deposit or withdrawal
+    /// already used, retained or not.
+    pub fn check_unique(&self, transaction: &Transaction) -> Result<(), LedgerError> {
+        let id = transaction.transaction_id;
+        if self.transactions_by_id.contains_key(&id) || self.unretained_ids.contains(&id) {
+            return Err(LedgerError::DuplicateTransaction { transaction: id, client: transaction.client_id });
+        }
+        Ok(())
+    }
+
+    // A deposit refused on a locked account is still retained, as not applied
+    fn deposit(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        let amount = checked_amount(transaction)?;
+        self.check_unique(transaction)?;
+        if self.account_by_id.get(&transaction.client_id).is_some_and(|account| account.locked) {
+            self.retain(transaction, StoredKind::Deposit, amount, false);
+            return Err(LedgerError::AccountLocked {
+                operation: TransactionType::Deposit,
+                transaction: transaction.transaction_id,
+                client: transaction.client_id,
+                amount,
+            });
+        }
+        self.retain(transaction, StoredKind::Deposit, amount, true);
+
+        if let Some(account) = self.account_by_id.get_mut(&transaction.client_id) {
+            account.available += amount;
+            account.total = account.available + account.held;
+            account.round();
+        } else {
+            let mut account = Account::new(transaction.client_id);
+            account.available = amount;
+            account.total = account.available;
+            account.round();
+            self.account_by_id.insert(transaction.client_id, account);
+        }
+        Ok(())
+    }
+
+    // A refused withdrawal is still retained, as not applied
+    fn withdraw(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        let amount = checked_amount(transaction)?;
+        self.check_unique(transaction)?;
+
+        let outcome = match self.account_by_id.get_mut(&transaction.client_id) {
+            None => Err(LedgerError::UnknownAccount(transaction.client_id)),
+            Some(account) if account.locked => Err(LedgerError::AccountLocked {
+                operation: TransactionType::Withdrawal,
+                transaction: transaction.transaction_id,
+                client: transaction.client_id,
+                amount,
+            }),
+            Some(account) if account.review_hold => Err(LedgerError::UnderReview {
+                client: transaction.client_id,
+                requested: amount,
+            }),
+            Some(account) if account.available < amount => Err(LedgerError::InsufficientFunds {
+                operation: TransactionType::Withdrawal,
+                client: transaction.client_id,
+                requested: amount,
+                available: account.available,
+            }),
+            Some(account) => {
+                account.available -= amount;
+                account.total -= amount;
+                account.round();
+                Ok(())
+            },
+        };
+
+        self.retain(transaction, StoredKind::Withdrawal, amount, outcome.is_ok());
+        outcome
+    }
+
+    // The retained transaction a dispute, resolve or chargeback references,
+    // with the account of its client
+    fn disputed_transaction(
+        &mut self,
+        transaction: &Transaction,
+        operation: TransactionType,
+    ) -> Result<(&mut StoredTx, &mut Account), LedgerError> {
+        let Some(fetched_transaction) = self.transactions_by_id.get_mut(&transaction.transaction_id) else {
+            return Err(if self.unretained_ids.contains(&transaction.transaction_id) {
+                LedgerError::NotRetained { operation, transaction: transaction.transaction_id }
+            } else {
+                LedgerError::UnknownTransaction { operation, transaction: transaction.transaction_id }
+            });
+        };
+        if fetched_transaction.client_id != transaction.client_id {
+            return Err(LedgerError::ClientMismatch {
+                operation,
+                transaction: transaction.transaction_id,
+                client: transaction.client_id,
+                owner: fetched_transaction.client_id,
+            });
+        }
+        let account = self.account_by_id.get_mut(&transaction.client_id)
+            .ok_or(LedgerError::UnknownAccount(transaction.client_id))?;
+        Ok((fetched_transaction, account))
+    }
+
+    fn dispute(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        let rows_processed = self.rows_processed;
+        let (fetched_transaction, account) = self.disputed_transaction(transaction, TransactionType::Dispute)?;
+        // A refused deposit or withdrawal moved no funds a dispute could hold
+        if !fetched_transaction.applied {
+            return Err(LedgerError::NotApplied {
+                operation: TransactionType::Dispute,
+                transaction: transaction.transaction_id,
+                client: transaction.client_id,
+            });
+        }
+        let transaction_amount = fetched_transaction.amount;
+        let state = fetched_transaction.state;
+        let next = state.after(&TransactionType::Dispute).ok_or(LedgerError::InvalidDisputeState {
+            operation: TransactionType::Dispute,
+            transaction: transaction.transaction_id,
+            client: transaction.client_id,
+            state,
+        })?;
+        if account.locked {
+            return Err(LedgerError::AccountLocked {
+                operation: TransactionType::Dispute,
+                transaction: transaction.transaction_id,
+                client: transaction.client_id,
+                amount: transaction_amount,
+            });
+        }
+        let withdrawal = fetched_transaction.kind == StoredKind::Withdrawal;
+        // A deposit's funds are held out of available, a withdrawal's already
+        // left the account so the prospective refund is held on top of it
+        if !withdrawal && account.available <= transaction_amount {
+            return Err(LedgerError::InsufficientFunds {
+                operation: TransactionType::Dispute,
+                client: transaction.client_id,
+                requested: transaction_amount,
+                available: account.available,
+            });
+        }
+
+        fetched_transaction.state = next;
+        let origin = fetched_transaction.kind.transaction_type();
+        if withdrawal {
+            account.total += transaction_amount;
+        } else {
+            account.available -= transaction_amount;
+        }
+        account.held += transaction_amount;
+        account.round();
+        self.open_holds_by_client.entry(transaction.client_id).or_default().insert(
+            transaction.transaction_id,
+            Hold {
+                amount: transaction_amount,
+                opened_row: rows_processed,
+                origin,
+            },
+        );
+        Ok(())
+    }
+
+    // Resolves and chargebacks release the funds a dispute held
+    fn release(&mut self, transaction: &Transaction, operation: TransactionType) -> Result<(), LedgerError> {
+        let (fetched_transaction, account) = self.disputed_transaction(transaction, operation.clone())?;
+        let transaction_amount = fetched_transaction.amount;
+        let state = fetched_transaction.state;
+        let next = state.after(&operation).ok_or(LedgerError::InvalidDisputeState {
+            operation: operation.clone(),
+            transaction: transaction.transaction_id,
+            client: transaction.client_id,
+            state,
+        })?;
+        if account.held < transaction_amount {
+            return Err(LedgerError::InsufficientHeldFunds {
+                operation,
+                client: transaction.client_id,
+                requested: transaction_amount,
+                held: account.held,
+            });
+        }
+
+        fetched_transaction.state = next;
+        let withdrawal = fetched_transaction.kind == StoredKind::Withdrawal;
+        account.held -= transaction_amount;
+        // A charged back deposit leaves the account, a charged back withdrawal
+        // is refunded. Resolving gives back a deposit's funds and drops a
+        // withdrawal's refund.
+        match (operation == TransactionType::Chargeback, withdrawal) {
+            (true, false) | (false, true) => account.total -= transaction_amount,
+            (true, true) | (false, false) => account.available += transaction_amount,
+        }
+        if operation == TransactionType::Chargeback {
+            account.locked = true;
+            // The full lock supersedes a review
+            account.review_hold = false;
+        }
+        account.round();
+        release_hold(&mut self.open_holds_by_client, transaction.client_id, transaction.transaction_id);
+        Ok(())
+    }
+
+    fn resolve(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        self.release(transaction, TransactionType::Resolve)
+    }
+
+    fn chargeback(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        self.release(transaction, TransactionType::Chargeback)
+    }
+
+    // Once the ring is full its oldest record is refilled in place, so
+    // remembering a row doesn't allocate
+    fn remember_row(&mut self, record: &StringRecord) {
+        let mut slot = if self.recent_rows.len() == RECENT_ROWS {
+            self.recent_rows.pop_front().unwrap()
+        } else {
+            StringRecord::new()
+        };
+        slot.clear();
+        for field in record {
+            slot.push_field(field);
+        }
+        slot.set_position(record.position().cloned());
+        self.recent_rows.push_back(slot);
+    }
+
+    fn set_review_hold(&mut self, transaction: &Transaction, review_hold: bool) {
+        self.account_by_id.get_mut(&transaction.client_id).unwrap().review_hold = review_hold;
+        self.notify(Notice::ReviewHold {
+            client: transaction.client_id,
+            transaction: transaction.transaction_id,
+            review_hold,
+        });
+    }
+
+    fn notify(&mut self, notice: Notice) {
+        if let Some(notices) = self.notices.as_mut() {
+            notices.push(notice);
+        }
+    }
+
+    // Client the client's rows go to after one or more merges, if it was merged away
+    fn merged_target(&self, client_id: u16) -> Option<u16> {
+        let mut target_id = *self.merged_into.get(&client_id)?;
+        while let Some(next_id) = self.merged_into.get(&target_id) {
+            target_id = *next_id;
+        }
+        Some(target_id)
+    }
+
+    // Sums the client's account into the target's, which is locked if either
+    // was, and moves its retained transactions and open disputes along
+    fn merge(&mut self, transaction: &Transaction) {
+        let target_id = merge_target(transaction).unwrap();
+        let source = self.account_by_id.remove(&transaction.client_id).unwrap();
+        let target = self.account_by_id.entry(target_id).or_insert_with(|| Account::new(target_id));
+        target.available += source.available;
+        target.held += source.held;
+        target.total += source.total;
+        target.locked |= source.locked;
+        target.review_hold = (target.review_hold || source.review_hold) && !target.locked;
+        target.round();
+
+        for retained in self.transactions_by_id.values_mut().filter(|retained| retained.client_id == source.client_id) {
+            retained.client_id = target_id;
+        }
+        if let Some(holds) = self.open_holds_by_client.remove(&source.client_id) {
+            self.open_holds_by_client.entry(target_id).or_default().extend(holds);
+        }
+        self.merged_into.insert(source.client_id, target_id);
+        self.notify(Notice::Merged { client: source.client_id, transaction: transaction.transaction_id, target: target_id });
+    }
+
+    /// Open holds sorted by client then transaction id
+    pub fn holds_report(&self) -> Vec<HoldRow> {
+        let mut client_ids: Vec<&u16> = self.open_holds_by_client.keys().collect();
+        client_ids.sort_unstable();
+        client_ids.into_iter()
+            .flat_map(|client_id| {
+                self.open_holds_by_client[client_id].iter().map(|(transaction_id, hold)| HoldRow {
+                    client: *client_id,
+                    tx: *transaction_id,
+                    held: hold.amount,
+                    opened_row: hold.opened_row,
+                    origin: hold.origin.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Projections of the accounts with open holds sorted by client id, worked
+    /// out on copies of the accounts
+    pub fn projection_report(&self) -> Vec<ProjectionRow> {
+        let mut client_ids: Vec<&u16> = self.open_holds_by_client.keys().collect();
+        client_ids.sort_unstable();
+        client_ids.into_iter()
+            .filter_map(|client_id| {
+                let holds = &self.open_holds_by_client[client_id];
+                let account = self.account_by_id.get(client_id)?;
+                let mut chargeback = account.clone();
+                let mut resolve = account.clone();
+                for hold in holds.values() {
+                    chargeback.held -= hold.amount;
+                    resolve.held -= hold.amount;
+                    if hold.origin == TransactionType::Withdrawal {
+                        chargeback.available += hold.amount;
+                        resolve.total -= hold.amount;
+                    } else {
+                        chargeback.total -= hold.amount;
+                        resolve.available += hold.amount;
+                    }
+                }
+                chargeback.locked = true;
+                chargeback.round();
+                resolve.round();
+                Some(ProjectionRow {
+                    client: *client_id,
+                    open_disputes: holds.len(),
+                    available: account.available,
+                    held: account.held,
+                    total: account.total,
+                    locked: account.locked,
+                    chargeback_available: chargeback.available,
+                    chargeback_held: chargeback.held,
+                    chargeback_total: chargeback.total,
+                    chargeback_locked: chargeback.locked,
+                    resolve_available: resolve.available,
+                    resolve_held: resolve.held,
+                    resolve_total: resolve.total,
+                    resolve_locked: resolve.locked,
+                })
+            })
+            .collect()
+    }
+
+    /// Independently rebuilds every balance from the retained transactions and
+    /// returns the accounts that disagree with it, sorted by client id
+    pub fn recompute_check(&self) -> Vec<BalanceMismatch> {
+        let mut expected_by_id: HashMap<u16, Account> = HashMap::new();
+
+        for transaction in self.transactions_by_id.values().filter(|transaction| transaction.applied) {
+            let expected = expected_by_id
+                .entry(transaction.client_id)
+                .or_insert_with(|| Account::new(transaction.client_id));
+            let amount = transaction.amount;
+            let withdrawal = transaction.kind == StoredKind::Withdrawal;
+            if withdrawal {
+                expected.available -= amount;
+                expected.total -= amount;
+            } else {
+                expected.available += amount;
+                expected.total += amount;
+            }
+            let disputed = transaction.state == DisputeState::Disputed;
+            let charged_back = transaction.state == DisputeState::ChargedBack;
+            if disputed && withdrawal {
+                expected.held += amount;
+                expected.total += amount;
+            } else if disputed {
+                expected.available -= amount;
+                expected.held += amount;
+            }
+            if charged_back && withdrawal {
+                expected.available += amount;
+                expected.total += amount;
+            } else if charged_back {
+                expected.available -= amount;
+                expected.total -= amount;
+            }
+        }
+
+        let mut mismatches: Vec<BalanceMismatch> = self.account_by_id.values()
+            .filter_map(|account| {
+                let expected = expected_by_id.remove(&account.client_id).unwrap_or_else(|| Account::new(account.client_id));
+                BalanceMismatch::between(account, &expected)
+            })
+            .collect();
+        mismatches.extend(expected_by_id.values().filter_map(|expected| {
+            BalanceMismatch::between(&Account::new(expected.client_id), expected)
+        }));
+        mismatches.sort_by_key(|mismatch| mismatch.client_id);
+        mismatches
+    }
+
+    /// Busiest clients first, ties broken by client id
+    pub fn activity_report(&self) -> Vec<&Activity> {
+        let mut activities: Vec<&Activity> = self.activity_by_id.values().collect();
+        activities.sort_by(|a, b| b.tx_count.cmp(&a.tx_count).then(a.client_id.cmp(&b.client_id)));
+        activities
+    }
+
+    /// Clients with an account are split in thirds by transaction count, busiest
+    /// first. Each stratum is shuffled with the seed, then picked from in turn
+    /// so every stratum is represented before any gets a second pick.
+    pub fn qa_sample(&self, size: usize, seed: u64) -> Vec<(Stratum, u16)> {
+        let (active, rejected): (Vec<&Activity>, Vec<&Activity>) = self.activity_report()
+            .into_iter()
+            .partition(|activity| self.account_by_id.contains_key(&activity.client_id));
+        let mut strata: Vec<(Stratum, Vec<u16>)> = [Stratum::Heavy, Stratum::Medium, Stratum::Light]
+            .into_iter()
+            .zip(active.chunks(active.len().div_ceil(3).max(1)))
+            .map(|(stratum, activities)| (stratum, activities.iter().map(|activity| activity.client_id).collect()))
+            .collect();
+        strata.push((Stratum::RejectedOnly, rejected.iter().map(|activity| activity.client_id).collect()));
+
+        let mut rng = SampleRng(seed);
+        for (_, clients) in &mut strata {
+            rng.shuffle(clients);
+        }
+
+        let mut sample = Vec::new();
+        let mut index = 0;
+        while sample.len() < size && strata.iter().any(|(_, clients)| index < clients.len()) {
+            for (stratum, clients) in &strata {
+                if let Some(&client_id) = clients.get(index).filter(|_| sample.len() < size) {
+                    sample.push((*stratum, client_id));
+                }
+            }
+            index += 1;
+        }
+        sample
+    }
+
+    // Upper bound of what processing a single row can add to approximate_memory
+    const MAX_ROW_MEMORY: usize = entry_size::<u32, StoredTx>()
+        + entry_size::<u16, Account>()
+        + entry_size::<u16, Activity>();
+
+    /// Estimated memory held by the ledger, by structure.
+    pub fn memory_report(&self) -> MemoryReport {
+        let open_disputes = self.open_holds_by_client.values().map(|holds| holds.len()).sum();
+        MemoryReport {
+            accounts: self.account_by_id.len(),
+            retained_transactions: self.transactions_by_id.len(),
+            open_disputes,
+            fixed_bytes: size_of::<Ledger>() + size_of::<[u64; 1024]>(),
+            transactions_bytes: self.transactions_by_id.len() * entry_size::<u32, StoredTx>(),
+            accounts_bytes: self.account_by_id.len() * entry_size::<u16, Account>()
+                + self.merged_into.len() * entry_size::<u16, u16>(),
+            unretained_bytes: self.unretained_ids.len() * entry_size::<u32, ()>(),
+            activity_bytes: self.activity_by_id.len() * entry_size::<u16, Activity>(),
+            holds_bytes: open_disputes * entry_size::<u32, Hold>(),
+            retention_bytes: self.retention.clients.as_ref().map_or(0, |clients| clients.len() * entry_size::<u16, ()>()),
+        }
+    }
+
+    fn approximate_memory(&self) -> usize {
+        self.memory_report().total_bytes()
+    }
+
+    /// Every account, sorted by client id so the output never depends on
+    /// HashMap iteration order.
+    pub fn sorted_accounts(&self) -> Vec<&Account> {
+        let mut accounts: Vec<&Account> = self.account_by_id.values().collect();
+        accounts.sort_by_key(|account| account.client_id);
+        accounts
+    }
+
+    /// Account of the client, None if no transaction of the client opened one.
+    pub fn get_account(&self, client_id: u16) -> Option<Account> {
+        self.account_by_id.get(&client_id).cloned()
+    }
+
+    // Records what became of an input row: counted for the progress, and for
+    // the stats when it isn't a transaction, collected for --dry-run when not
+    // applied and written to the audit log, if any. A row that isn't a
+    // transaction comes without one.
+    fn record_outcome(&mut self, line: u64, transaction: Option<&Transaction>, result: Result<(), (&'static str, &dyn std::fmt::Display)>) {
+        if let Some(progress) = self.progress.as_mut() {
+            progress.row();
+        }
+        // Rows of a transaction were counted by process, not the unreadable ones
+        if let (None, Err((reason, _))) = (transaction, result) {
+            *self.stats.rejected_by_reason.entry(reason.to_string()).or_default() += 1;
+        }
+        if let (Some(rejections), Err((reason, message))) = (self.rejections.as_mut(), result) {
+            rejections.push(Rejection { line, reason, message: message.to_string() });
+        }
+        let result = result.map_err(|(reason, _)| reason);
+        let Some(audit) = self.audit.as_mut() else {
+            return;
+        };
+        let sentinel = transaction.is_some_and(|transaction| transaction.client_id == 0 || transaction.transaction_id == 0);
+        let (outcome, reason) = match result {
+            Ok(()) if sentinel && self.sentinel_ids == SentinelIds::Ignore => ("ignored", "SENTINEL_ID"),
+            Ok(()) => ("applied", ""),
+            Err(reason @ ("UNKNOWN_TRANSACTION" | "CLIENT_MISMATCH")) => ("ignored", reason),
+            Err(reason) => ("rejected", reason),
+        };
+        let account = transaction.and_then(|transaction| self.account_by_id.get(&transaction.client_id).cloned());
+        audit.record(line, transaction, outcome, reason, account);
+    }
+
+    /// Every rule process checks for the transaction against the current
+    /// state, in the order process checks them, without applying it. Rules
+    /// that need what an earlier failed one looked for, like the balance of
+    /// a missing account, are left out.
+    pub fn rule_trace(&self, transaction: &Transaction) -> Vec<RuleCheck> {
+        let mut rules = Vec::new();
+        let mut check = |rule: &'static str, passed: bool| {
+            rules.push(RuleCheck { rule, passed });
+            passed
+        };
+        let sentinel = transaction.client_id == 0 || transaction.transaction_id == 0;
+        if !check("sentinel_id", !sentinel || self.sentinel_ids != SentinelIds::Reject) {
+            return rules;
+        }
+        let merged_target = self.merged_target(transaction.client_id);
+        if !check("not_merged", merged_target.is_none() || self.merged_clients == MergedClients::Redirect) {
+            return rules;
+        }
+        let client_id = merged_target.unwrap_or(transaction.client_id);
+        let transaction = Transaction { client_id, amount: transaction.amount.map(round_amount), ..transaction.clone() };
+        let Some(handler) = self.handlers.get(&transaction.transaction_type) else {
+            check("known_type", false);
+            return rules;
+        };
+        check("known_type", true);
+        if !check("handler_validate", handler.validate(self, &transaction).is_ok()) {
+            return rules;
+        }
+        let account = self.account_by_id.get(&client_id);
+        match transaction.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let withdrawal = transaction.transaction_type == TransactionType::Withdrawal;
+                let Some(amount) = transaction.amount else {
+                    check("amount_present", false);
+                    return rules;
+                };
+                check("amount_present", true);
+                check("amount_in_range", amount > Decimal::ZERO && amount <= MAX_AMOUNT);
+                let id = transaction.transaction_id;
+                check("unique_tx", !self.transactions_by_id.contains_key(&id) && !self.unretained_ids.contains(&id));
+                if withdrawal && !check("account_exists", account.is_some()) {
+                    return rules;
+                }
+                check("account_not_locked", account.is_none_or(|account| !account.locked));
+                if let (true, Some(account)) = (withdrawal, account) {
+                    check("not_under_review", !account.review_hold);
+                    check("sufficient_available", account.available >= amount);
+                }
+            },
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                let id = transaction.transaction_id;
+                if !check("tx_known", self.transactions_by_id.contains_key(&id) || self.unretained_ids.contains(&id)) {
+                    return rules;
+                }
+                let Some(stored) = self.transactions_by_id.get(&id) else {
+                    check("tx_retained", false);
+                    return rules;
+                };
+                check("tx_retained", true);
+                if !check("same_client", stored.client_id == client_id) {
+                    return rules;
+                }
+                let Some(account) = account else {
+                    check("account_exists", false);
+                    return rules;
+                };
+                check("account_exists", true);
+                if transaction.transaction_type == TransactionType::Dispute && !check("tx_applied", stored.applied) {
+                    return rules;
+                }
+                check("dispute_state_allows", stored.state.after(&transaction.transaction_type).is_some());
+                if transaction.transaction_type == TransactionType::Dispute {
+                    check("account_not_locked", !account.locked);
+                    if stored.kind == StoredKind::Deposit {
+                        check("sufficient_available", account.available > stored.amount);
+                    }
+                } else {
+                    check("sufficient_held", account.held >= stored.amount);
+                }
+            },
+            // Merges, reviews and custom types only have their handler's validate
+            _ => {},
+        }
+        rules
+    }
+
+    /// Counters of the rows processed so far, with the current accounts.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            accounts: self.account_by_id.len(),
+            locked_accounts: self.account_by_id.values().filter(|account| account.locked).count(),
+            total_held: self.account_by_id.values().map(|account| account.held).sum(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Writes everything later rows depend on: accounts, retained
+    /// transactions with their dispute state, open holds, merges and
+    /// activity. The policies are not saved, they come from the options of
+    /// the run loading the state.
+    pub fn save_state<W: Write>(&self, writer: W) -> csv::Result<()> {
+        let mut writer = WriterBuilder::new().flexible(true).has_headers(false).from_writer(writer);
+        writer.write_record([STATE_MAGIC, &STATE_VERSION.to_string()])?;
+        writer.write_record(["rows", &self.rows_processed.to_string()])?;
+        for account in self.sorted_accounts() {
+            writer.write_record([
+                "account",
+                &account.client_id.to_string(),
+                &account.available.to_string(),
+                &account.held.to_string(),
+                &account.total.to_string(),
+                &account.locked.to_string(),
+                &account.review_hold.to_string(),
+            ])?;
+        }
+        let mut transaction_ids: Vec<&u32> = self.transactions_by_id.keys().collect();
+        transaction_ids.sort_unstable();
+        for transaction_id in transaction_ids {
+            let stored = &self.transactions_by_id[transaction_id];
+            writer.write_record([
+                "transaction",
+                &transaction_id.to_string(),
+                &stored.client_id.to_string(),
+                stored.kind.transaction_type().name(),
+                &stored.amount.to_string(),
+                stored.state.name(),
+                &stored.applied.to_string(),
+            ])?;
+        }
+        let mut unretained_ids: Vec<&u32> = self.unretained_ids.iter().collect();
+        unretained_ids.sort_unstable();
+        for transaction_id in unretained_ids {
+            writer.write_record(["unretained", &transaction_id.to_string()])?;
+        }
+        let holds: BTreeMap<&u16, &BTreeMap<u32, Hold>> = self.open_holds_by_client.iter().collect();
+        for (client_id, holds) in holds {
+            for (transaction_id, hold) in holds {
+                writer.write_record([
+                    "hold",
+                    &client_id.to_string(),
+                    &transaction_id.to_string(),
+                    &hold.amount.to_string(),
+                    &hold.opened_row.to_string(),
+                    hold.origin.name(),
+                ])?;
+            }
+        }
+        let merged_into: BTreeMap<&u16, &u16> = self.merged_into.iter().collect();
+        for (client_id, into) in merged_into {
+            writer.write_record(["merged", &client_id.to_string(), &into.to_string()])?;
+        }
+        let activities: BTreeMap<&u16, &Activity> = self.activity_by_id.iter().collect();
+        for activity in activities.into_values() {
+            writer.write_record([
+                "activity",
+                &activity.client_id.to_string(),
+                &activity.tx_count.to_string(),
+                &activity.deposit_sum.to_string(),
+                &activity.withdrawal_sum.to_string(),
+                &activity.dispute_count.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Replaces the state of the ledger by one written by save_state,
+    /// keeping its policies. A file of another format or version is refused.
+    pub fn load_state<R: Read>(&mut self, reader: R) -> Result<(), String> {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).has_headers(false).from_reader(reader);
+        let mut records = reader.records();
+        let header = records.next().transpose().map_err(|err| err.to_string())?.unwrap_or_default();
+        if header.get(0) != Some(STATE_MAGIC) {
+            return Err("not a ledger state file".to_string());
+        }
+        if header.get(1) != Some(STATE_VERSION.to_string().as_str()) {
+            return Err(format!("state version {} is not supported, expected {}", header.get(1).unwrap_or_default(), STATE_VERSION));
+        }
+
+        let mut ledger = Ledger::default();
+        for record in records {
+            let record = record.map_err(|err| err.to_string())?;
+            let line = record.position().map_or(0, |position| position.line());
+            let parsed = (|| -> Result<(), String> {
+                let field = |index: usize| record.get(index).ok_or_else(|| format!("missing field {}", index + 1));
+                let number = |index: usize| field(index)?.parse::<u64>().map_err(|err| format!("field {}: {}", index + 1, err));
+                let client = |index: usize| field(index)?.parse::<u16>().map_err(|err| format!("field {}: {}", index + 1, err));
+                let transaction = |index: usize| field(index)?.parse::<u32>().map_err(|err| format!("field {}: {}", index + 1, err));
+                let amount = |index: usize| field(index)?.parse::<Decimal>().map_err(|err| format!("field {}: {}", index + 1, err));
+                let flag = |index: usize| field(index)?.parse::<bool>().map_err(|err| format!("field {}: {}", index + 1, err));
+                match field(0)? {
+                    "rows" => {
+                        ledger.rows_processed = number(1)?;
+                    },
+                    "account" => {
+                        let account = Account {
+                            client_id: client(1)?,
+                            available: amount(2)?,
+                            held: amount(3)?,
+                            total: amount(4)?,
+                            locked: flag(5)?,
+                            review_hold: flag(6)?,
+                        };
+                        ledger.account_by_id.insert(account.client_id, account);
+                    },
+                    "transaction" => {
+                        let kind = match TransactionType::from_name(field(3)?) {
+                            TransactionType::Deposit => StoredKind::Deposit,
+                            TransactionType::Withdrawal => StoredKind::Withdrawal,
+                            _ => return Err(format!("invalid transaction kind {}", field(3)?)),
+                        };
+                        let state = [DisputeState::Normal, DisputeState::Disputed, DisputeState::ChargedBack].into_iter()
+                            .find(|state| state.name() == field(5).unwrap_or_default())
+                            .ok_or_else(|| format!("invalid dispute state {}", field(5).unwrap_or_default()))?;
+                        ledger.transactions_by_id.insert(transaction(1)?, StoredTx {
+                            client_id: client(2)?,
+                            amount: amount(4)?,
+                            kind,
+                            state,
+                            applied: flag(6)?,
+                        });
+                    },
+                    "unretained" => {
+                        ledger.unretained_ids.insert(transaction(1)?);
+                    },
+                    "hold" => {
+                        ledger.open_holds_by_client.entry(client(1)?).or_default().insert(transaction(2)?, Hold {
+                            amount: amount(3)?,
+                            opened_row: number(4)?,
+                            origin: TransactionType::from_name(field(5)?),
+                        });
+                    },
+                    "merged" => {
+                        ledger.merged_into.insert(client(1)?, client(2)?);
+                    },
+                    "activity" => {
+                        let activity = Activity {
+                            client_id: client(1)?,
+                            tx_count: number(2)?,
+                            deposit_sum: amount(3)?,
+                            withdrawal_sum: amount(4)?,
+                            dispute_count: number(5)?,
+                        };
+                        ledger.clients_seen.insert(activity.client_id);
+                        ledger.activity_by_id.insert(activity.client_id, activity);
+                    },
+                    other => return Err(format!("unknown record {}", other)),
+                }
+                Ok(())
+            })();
+            parsed.map_err(|err| format!("line {}: {}", line, err))?;
+        }
+
+        self.transactions_by_id = ledger.transactions_by_id;
+        self.account_by_id = ledger.account_by_id;
+        self.unretained_ids = ledger.unretained_ids;
+        self.clients_seen = ledger.clients_seen;
+        self.activity_by_id = ledger.activity_by_id;
+        self.rows_processed = ledger.rows_processed;
+        self.open_holds_by_client = ledger.open_holds_by_client;
+        self.merged_into = ledger.merged_into;
+        Ok(())
+    }
+}
+
+// First record of a ledger state file, bumped whenever its records change
+const STATE_MAGIC: &str = "pieuvre-state";
+const STATE_VERSION: u32 = 1;
+
+/// Reads a file of client ids, one per line.
+pub fn read_clients_file(path: &str) -> std::io::Result<HashSet<u16>> {
+    let mut clients = HashSet::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let client_id = line.parse::<u16>().map_err(|err| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid client id {}: {}", line, err))
+        })?;
+        clients.insert(client_id);
+    }
+    Ok(clients)
+}
+
+/// Writes the client ids, one per line, in increasing order.
+pub fn write_clients_seen(path: &str, clients: &ClientSet) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for client_id in clients.iter() {
+        writeln!(writer, "{}", client_id)?;
+    }
+    writer.flush()
+}
+
+/// Limits of a run of process_records or process_json_lines.
+#[derive(Default, Debug)]
+pub struct Budget {
+    pub max_rows: Option<u64>,
+    pub max_duration: Option<Duration>,
+    pub max_memory: Option<usize>,
+    /// Set from another thread to stop before the next row
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Rows with a longer field or line are skipped
+    pub max_field_bytes: Option<usize>,
+    pub max_record_bytes: Option<usize>,
+    /// Stop at the first row that isn't a transaction instead of skipping it
+    pub strict: bool,
+}
+
+impl Budget {
+    fn exhausted(&self, rows: u64, started: &Instant) -> bool {
+        self.max_rows.is_some_and(|max_rows| rows >= max_rows)
+            || self.max_duration.is_some_and(|max_duration| started.elapsed() >= max_duration)
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    fn memory_exceeded_by_next_row(&self, ledger: &Ledger) -> bool {
+        self.max_memory
+            .is_some_and(|max_memory| ledger.approximate_memory() + Ledger::MAX_ROW_MEMORY > max_memory)
+    }
+}
+
+/// Caps the lines read from the inner reader to max_record_bytes + 1 bytes
+/// before their newline, dropping the rest. A pathological line never reaches
+/// the CSV reader's buffers whole, and the row it cuts is still longer than
+/// max_record_bytes for process_records or process_json_lines to skip.
+pub struct RecordLimit<R> {
+    inner: R,
+    max_record_bytes: usize,
+    line_bytes: usize,
+}
+
+impl<R: Read> RecordLimit<R> {
+    /// Reads inner, cutting the lines longer than max_record_bytes.
+    pub fn new(inner: R, max_record_bytes: usize) -> RecordLimit<R> {
+        RecordLimit { inner, max_record_bytes, line_bytes: 0 }
+    }
+}
+
+impl<R: Read> Read for RecordLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.inner.read(buf)?;
+            if read == 0 {
+                return Ok(0);
+            }
+            let mut kept = 0;
+            for index in 0..read {
+                let byte = buf[index];
+                if byte == b'\n' {
+                    self.line_bytes = 0;
+                } else {
+                    self.line_bytes += 1;
+                    if self.line_bytes > self.max_record_bytes.saturating_add(1) {
+                        continue;
+                    }
+                }
+                buf[kept] = byte;
+                kept += 1;
+            }
+            if kept > 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+/// Counts the bytes read from the inner reader into a counter shared with
+/// Progress, which can't see through the CSV reader's buffers
+pub struct CountingReader<R> {
+    inner: R,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes_read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+// Rows between two --progress lines when stderr isn't a terminal
+const PROGRESS_LINE_ROWS: u64 = 1_000_000;
+
+// Rows between two looks at the clock on a terminal
+const PROGRESS_CLOCK_ROWS: u64 = 1024;
+
+const PROGRESS_REFRESH: Duration = Duration::from_millis(500);
+
+/// Reports --progress on stderr: rows processed, bytes read out of the
+/// input size, rows per second and the time left. On a terminal one line is
+/// refreshed in place twice a second, elsewhere a line is printed every
+/// million rows. Counted by the ledger for every row once set.
+#[derive(Debug)]
+pub struct Progress {
+    bytes_read: Arc<AtomicU64>,
+    // Size of the input files, unknown when one is the standard input
+    total_bytes: Option<u64>,
+    terminal: bool,
+    rows: u64,
+    started: Instant,
+    refreshed: Instant,
+}
+
+impl Progress {
+    /// Progress over total_bytes when known, redrawn in place when on a terminal.
+    pub fn new(total_bytes: Option<u64>, terminal: bool) -> Progress {
+        Progress {
+            bytes_read: Arc::new(AtomicU64::new(0)),
+            total_bytes,
+            terminal,
+            rows: 0,
+            started: Instant::now(),
+            refreshed: Instant::now(),
+        }
+    }
+
+    /// Wraps an input file so its bytes count towards the progress.
+    pub fn counting<R: Read>(&self, inner: R) -> CountingReader<R> {
+        CountingReader { inner, bytes_read: Arc::clone(&self.bytes_read) }
+    }
+
+    fn row(&mut self) {
+        self.rows += 1;
+        if self.terminal {
+            if self.rows.is_multiple_of(PROGRESS_CLOCK_ROWS) && self.refreshed.elapsed() >= PROGRESS_REFRESH {
+                self.refreshed = Instant::now();
+                eprint!("\r{}", self.status(self.started.elapsed()));
+            }
+        } else if self.rows.is_multiple_of(PROGRESS_LINE_ROWS) {
+            eprintln!("{}", self.status(self.started.elapsed()));
+        }
+    }
+
+    /// Prints the final figures, ending the line refreshed on a terminal.
+    pub fn finish(&self) {
+        eprintln!("{}{}", if self.terminal { "\r" } else { "" }, self.status(self.started.elapsed()));
+    }
+
+    fn status(&self, elapsed: Duration) -> String {
+        let bytes_read = self.bytes_read.load(Ordering::Relaxed);
+        let seconds = elapsed.as_secs_f64().max(0.001);
+        let mut status = format!("Progress: {} rows, {:.1} MB", self.rows, bytes_read as f64 / 1e6);
+        if let Some(total_bytes) = self.total_bytes.filter(|total_bytes| *total_bytes > 0) {
+            status.push_str(&format!(
+                " of {:.1} MB ({:.1}%)",
+                total_bytes as f64 / 1e6,
+                100.0 * bytes_read.min(total_bytes) as f64 / total_bytes as f64,
+            ));
+        }
+        status.push_str(&format!(", {:.0} rows/s", self.rows as f64 / seconds));
+        if let Some(total_bytes) = self.total_bytes.filter(|_| bytes_read > 0) {
+            let left = (total_bytes.saturating_sub(bytes_read) as f64 * seconds / bytes_read as f64).round() as u64;
+            status.push_str(&format!(", ETA {}:{:02}:{:02}", left / 3600, left / 60 % 60, left % 60));
+        }
+        status
+    }
+}
+
+/// Why process_records skipped a row before parsing it
+#[derive(Debug, PartialEq)]
+pub enum RowError {
+    OverlongRecord { line: u64, max_bytes: usize },
+    /// Index of the field, from 0
+    OverlongField { line: u64, field: usize, bytes: usize, max_bytes: usize },
+    /// Index of the field, from 0, none for a JSON line
+    InvalidUtf8 { line: u64, field: Option<usize> },
+    UnparsableJson { line: u64, reason: String },
+    /// Byte offset of the start of the line
+    TruncatedRecord { line: u64, byte: u64 },
+    /// A row with a missing field, or one that doesn't read as a transaction,
+    /// with its fields as read
+    UnparsableRow { line: u64, record: String, reason: String },
+}
+
+impl RowError {
+    /// Line of the input the row starts at.
+    pub fn line(&self) -> u64 {
+        match self {
+            RowError::OverlongRecord { line, .. }
+            | RowError::OverlongField { line, .. }
+            | RowError::InvalidUtf8 { line, .. }
+            | RowError::UnparsableJson { line, .. }
+            | RowError::TruncatedRecord { line, .. }
+            | RowError::UnparsableRow { line, .. } => *line,
+        }
+    }
+
+    /// Stable code of the error, for reports and statistics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RowError::OverlongRecord { .. } => "OVERLONG_RECORD",
+            RowError::OverlongField { .. } => "OVERLONG_FIELD",
+            RowError::InvalidUtf8 { .. } => "INVALID_UTF8",
+            RowError::UnparsableJson { .. } => "UNPARSABLE_JSON",
+            RowError::TruncatedRecord { .. } => "TRUNCATED_RECORD",
+            RowError::UnparsableRow { .. } => UNPARSABLE_ROW,
+        }
+    }
+}
+
+impl std::fmt::Display for RowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RowError::OverlongRecord { line, max_bytes } => write!(
+                f,
+                "[{}] line {} is longer than {} bytes",
+                self.code(), line, max_bytes,
+            ),
+            RowError::OverlongField { line, field, bytes, max_bytes } => write!(
+                f,
+                "[{}] field {} of line {} is {} bytes long, more than {}",
+                self.code(), field + 1, line, bytes, max_bytes,
+            ),
+            RowError::InvalidUtf8 { line, field: Some(field) } => write!(
+                f,
+                "[{}] field {} of line {} is not valid UTF-8",
+                self.code(), field + 1, line,
+            ),
+            RowError::InvalidUtf8 { line, field: None } => write!(f, "[{}] line {} is not valid UTF-8", self.code(), line),
+            RowError::UnparsableJson { line, reason } => write!(
+                f,
+                "[{}] line {} is not a transaction: {}",
+                self.code(), line, reason,
+            ),
+            RowError::TruncatedRecord { line, byte } => write!(
+                f,
+                "[{}] last line {}, at byte {}, is missing fields",
+                self.code(), line, byte,
+            ),
+            RowError::UnparsableRow { line, record, reason } => write!(
+                f,
+                "[{}] line {} ({}) is not a transaction: {}",
+                self.code(), line, record, reason,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RowError {}
+
+// Checks a raw row against the budget's byte limits, raw_bytes being its
+// length in the input with its line terminator, and decodes it
+fn decode_row(record: &ByteRecord, raw_bytes: u64, mapping: &InputMapping, budget: &Budget) -> Result<StringRecord, RowError> {
+    let line = record.position().map_or(0, |position| position.line());
+    if let Some(max_bytes) = budget.max_record_bytes {
+        if raw_bytes > max_bytes as u64 + 1 {
+            return Err(RowError::OverlongRecord { line, max_bytes });
+        }
+    }
+    if let Some(max_bytes) = budget.max_field_bytes {
+        if let Some((field, bytes)) = record.iter().map(<[u8]>::len).enumerate().find(|(_, bytes)| *bytes > max_bytes) {
+            return Err(RowError::OverlongField { line, field, bytes, max_bytes });
+        }
+    }
+    let mut decoded = if mapping.lossy_utf8 {
+        record.iter().map(|field| String::from_utf8_lossy(field)).collect::<StringRecord>()
+    } else {
+        let field = record.iter().position(|field| std::str::from_utf8(field).is_err());
+        if let Some(field) = field {
+            return Err(RowError::InvalidUtf8 { line, field: Some(field) });
+        }
+        StringRecord::from_byte_record(record.clone()).unwrap()
+    };
+    decoded.set_position(record.position().cloned());
+    Ok(decoded)
+}
+
+// Fields of a raw row as read, for messages
+fn record_text(record: &ByteRecord) -> String {
+    record.iter().map(String::from_utf8_lossy).collect::<Vec<_>>().join(",")
+}
+
+// A row that isn't a transaction is skipped with the error, or under a
+// strict budget stops the run. Returns whether to go on.
+fn skip_row(ledger: &mut Ledger, progress: &mut RunProgress, budget: &Budget, line: u64, err: RowError) -> bool {
+    ledger.record_outcome(line, None, Err((err.code(), &err)));
+    if budget.strict {
+        progress.malformed = Some(err.to_string());
+        return false;
+    }
+    ledger.notify(Notice::Skipped(err.to_string()));
+    true
+}
+
+// A row of a type without a handler isn't a transaction either, reported
+// with its line and record
+fn unknown_type(result: &Result<(), LedgerError>, line: u64, record: &str) -> Option<String> {
+    match result {
+        Err(err @ LedgerError::UnknownType { .. }) => Some(format!("[{}] line {} ({}): {}", err.code(), line, record, err)),
+        _ => None,
+    }
+}
+
+/// How far a run of process_records or process_json_lines went.
+#[derive(Debug, PartialEq)]
+pub struct RunProgress {
+    pub rows: u64,
+    /// Line of the last processed row, 0 when nothing was read
+    pub last_line: u64,
+    /// Set whenever the budget stopped reading before the end of the input
+    /// was seen, even if no row was actually left
+    pub partial: bool,
+    pub memory_exceeded: bool,
+    /// Stopped by the budget's cancel flag, rows is then the count of applied rows
+    pub cancelled: bool,
+    /// What stopped a strict budget: the row that isn't a transaction
+    pub malformed: Option<String>,
+}
+
+/// Header of a transactions reader. One built without headers reads its
+/// fields in INPUT_COLUMNS order, its first row being a transaction.
+pub fn input_headers<R: Read>(reader: &mut Reader<R>) -> csv::Result<StringRecord> {
+    if reader.has_headers() {
+        Ok(reader.headers()?.clone())
+    } else {
+        Ok(StringRecord::from(INPUT_COLUMNS.to_vec()))
+    }
+}
+
+/// Feeds the reader's rows to the ledger until the input or the budget runs
+/// out. Rows that can't be read are skipped, an input that can't be read any
+/// further returns its error, the rows before it staying applied.
+pub fn process_records<R: Read>(ledger: &mut Ledger, reader: &mut Reader<R>, mapping: &InputMapping, budget: &Budget) -> csv::Result<RunProgress> {
+    let started = Instant::now();
+    let headers = input_headers(reader)?;
+    let type_index = headers.iter().position(|column| column.trim() == "type");
+    let mut raw = ByteRecord::new();
+    // What reading past a row missing fields found, handled as the next read
+    let mut read_ahead: Option<csv::Result<bool>> = None;
+    let mut progress = RunProgress {
+        rows: 0,
+        last_line: 0,
+        partial: false,
+        memory_exceeded: false,
+        cancelled: false,
+        malformed: None,
+    };
+
+    loop {
+        if budget.cancelled() {
+            progress.cancelled = true;
+            progress.partial = read_ahead.is_some() || !reader.is_done();
+            break;
+        }
+        if budget.exhausted(progress.rows, &started) {
+            progress.partial = read_ahead.is_some() || !reader.is_done();
+            break;
+        }
+        if budget.memory_exceeded_by_next_row(ledger) {
+            progress.memory_exceeded = true;
+            break;
+        }
+        match read_ahead.take().unwrap_or_else(|| reader.read_byte_record(&mut raw)) {
+            Ok(true) => {},
+            Ok(false) => break,
+            // A line cut by RecordLimit may have lost fields, and so may the
+            // last line of a file cut short by its exporter, the only case
+            // where reading on finds the end of the input
+            Err(err) if matches!(err.kind(), csv::ErrorKind::UnequalLengths { .. }) => {
+                let position = err.position().unwrap().clone();
+                let raw_bytes = reader.position().byte() - position.byte();
+                let err = if let Some(max_bytes) = budget.max_record_bytes.filter(|max_bytes| raw_bytes > *max_bytes as u64 + 1) {
+                    RowError::OverlongRecord { line: position.line(), max_bytes }
+                } else {
+                    let record = record_text(&raw);
+                    match reader.read_byte_record(&mut raw) {
+                        Ok(false) => RowError::TruncatedRecord { line: position.line(), byte: position.byte() },
+                        next => {
+                            read_ahead = Some(next);
+                            let reason = match err.kind() {
+                                csv::ErrorKind::UnequalLengths { expected_len, len, .. } => {
+                                    format!("expected {} fields, found {}", expected_len, len)
+                                },
+                                _ => unreachable!(),
+                            };
+                            RowError::UnparsableRow { line: position.line(), record, reason }
+                        },
+                    }
+                };
+                progress.rows += 1;
+                progress.last_line = position.line();
+                if !skip_row(ledger, &mut progress, budget, position.line(), err) {
+                    break;
+                }
+                continue;
+            },
+            Err(err) => return Err(err),
+        }
+        let raw_bytes = reader.position().byte() - raw.position().unwrap().byte();
+        progress.rows += 1;
+        progress.last_line = raw.position().unwrap().line();
+        let mut record = match decode_row(&raw, raw_bytes, mapping, budget) {
+            Ok(record) => record,
+            Err(err) => {
+                let line = progress.last_line;
+                if !skip_row(ledger, &mut progress, budget, line, err) {
+                    break;
+                }
+                continue;
+            },
+        };
+        if let Some(type_index) = type_index {
+            mapping.map_type(&mut record, type_index);
+        }
+        ledger.remember_row(&record);
+        let transaction: Transaction = match record.deserialize(Some(&headers)) {
+            Ok(transaction) => transaction,
+            Err(err) => {
+                let reason = match err.kind() {
+                    csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+                    _ => err.to_string(),
+                };
+                let err = RowError::UnparsableRow { line: progress.last_line, record: record_text(&raw), reason };
+                let line = progress.last_line;
+                if !skip_row(ledger, &mut progress, budget, line, err) {
+                    break;
+                }
+                continue;
+            },
+        };
+        let result = ledger.process(&transaction);
+        let outcome = result.as_ref().map_err(|err| (err.code(), err as _)).copied();
+        ledger.record_outcome(progress.last_line, Some(&transaction), outcome);
+        match (unknown_type(&result, progress.last_line, &record_text(&raw)), &result) {
+            (Some(malformed), _) if budget.strict => {
+                progress.malformed = Some(malformed);
+                break;
+            },
+            (Some(malformed), _) => ledger.notify(Notice::Skipped(malformed)),
+            (None, Err(err)) => ledger.notify(Notice::Rejected(err.clone())),
+            (None, Ok(())) => {},
+        }
+    }
+    Ok(progress)
+}
+
+// A JSON number written as a plain decimal, the only numbers amounts and ids
+// take: an exponent would turn 1.5e2 into a deposit of 150
+fn plain_decimal(value: &str) -> bool {
+    let unsigned = value.strip_prefix('-').unwrap_or(value);
+    let (integer, fraction) = match unsigned.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (unsigned, None),
+    };
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+    digits(integer) && (integer == "0" || !integer.starts_with('0')) && fraction.is_none_or(digits)
+}
+
+// Fields of a JSON object in INPUT_COLUMNS order, other keys ignored whatever
+// their value and missing or null ones left empty. Numbers are kept as written,
+// and strings are taken for ids and amounts alike. A key of INPUT_COLUMNS
+// given twice is refused rather than one of its values picked. Hand-written,
+// as is json_string, since the crate builds without serde_json.
+fn json_record(line: &str) -> Result<StringRecord, String> {
+    let mut fields = vec![String::new(); INPUT_COLUMNS.len()];
+    let mut seen = [false; INPUT_COLUMNS.len()];
+    let mut chars = line.trim().chars().peekable();
+    if chars.next() != Some('{') {
+        return Err("expected an object".to_string());
+    }
+
+    fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn hex(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<u32, String> {
+        let hex: String = chars.by_ref().take(4).collect();
+        match u32::from_str_radix(&hex, 16) {
+            Ok(code) if hex.len() == 4 => Ok(code),
+            _ => Err(format!("invalid escape \\u{}", hex)),
+        }
+    }
+
+    // An object or array, only checked for balanced brackets as none of its
+    // content is kept
+    fn skip_nested(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<(), String> {
+        let mut closers = Vec::new();
+        loop {
+            match chars.peek() {
+                Some('"') => {
+                    string(chars)?;
+                    continue;
+                },
+                Some('{') => closers.push('}'),
+                Some('[') => closers.push(']'),
+                Some(c @ ('}' | ']')) => {
+                    if closers.pop() != Some(*c) {
+                        return Err(format!("unexpected {}", c));
+                    }
+                    if closers.is_empty() {
+                        chars.next();
+                        return Ok(());
+                    }
+                },
+                Some(_) => {},
+                None => return Err("unterminated object".to_string()),
+            }
+            chars.next();
+        }
+    }
+
+    fn string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+        if chars.next() != Some('"') {
+            return Err("expected a string".to_string());
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match chars.next() {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('/') => value.push('/'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some('u') => {
+                        let mut code = hex(chars)?;
+                        // A character outside the BMP is escaped as a surrogate pair
+                        if (0xD800..0xDC00).contains(&code) {
+                            if chars.next() != Some('\\') || chars.next() != Some('u') {
+                                return Err("unpaired surrogate escape".to_string());
+                            }
+                            let low = hex(chars)?;
+                            if !(0xDC00..0xE000).contains(&low) {
+                                return Err("unpaired surrogate escape".to_string());
+                            }
+                            code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                        }
+                        value.push(char::from_u32(code).ok_or("unpaired surrogate escape")?);
+                    },
+                    _ => return Err("invalid escape".to_string()),
+                },
+                Some(c) if c < ' ' => return Err(format!("unescaped control character {:?} in a string", c)),
+                Some(c) => value.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_none() {
+        loop {
+            skip_whitespace(&mut chars);
+            let key = string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            if chars.next() != Some(':') {
+                return Err(format!("expected : after key {}", key));
+            }
+            skip_whitespace(&mut chars);
+            let column = INPUT_COLUMNS.iter().position(|column| *column == key);
+            let value = match chars.peek() {
+                Some('"') => string(&mut chars)?,
+                Some('{' | '[') if column.is_none() => {
+                    skip_nested(&mut chars)?;
+                    String::new()
+                },
+                Some('{' | '[') => return Err(format!("expected a string, number or null for key {}", key)),
+                Some(_) => {
+                    let mut value = String::new();
+                    while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != ',' && *c != '}') {
+                        value.push(c);
+                    }
+                    match value.as_str() {
+                        "null" => String::new(),
+                        "true" | "false" => value,
+                        _ if plain_decimal(&value) => value,
+                        _ if value.parse::<f64>().is_ok() => {
+                            return Err(format!("number {} for key {} is not a plain decimal", value, key));
+                        },
+                        _ => return Err(format!("invalid value {} for key {}", value, key)),
+                    }
+                },
+                None => return Err("unterminated object".to_string()),
+            };
+            if let Some(index) = column {
+                if std::mem::replace(&mut seen[index], true) {
+                    return Err(format!("duplicate key {}", key));
+                }
+                fields[index] = value;
+            }
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                _ => return Err("expected , or } after a value".to_string()),
+            }
+        }
+    }
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return Err("unexpected characters after the object".to_string());
+    }
+    Ok(StringRecord::from(fields))
+}
+
+/// Processes transactions written one JSON object per line, like
+/// {"type":"deposit","client":1,"tx":1,"amount":"1.5"}, under the same budget
+/// as process_records. Lines that aren't a transaction or are longer than the
+/// budget's max_record_bytes are reported and skipped.
+pub fn process_json_lines<R: BufRead>(ledger: &mut Ledger, reader: &mut R, mapping: &InputMapping, budget: &Budget) -> csv::Result<RunProgress> {
+    let started = Instant::now();
+    let headers = StringRecord::from(INPUT_COLUMNS.to_vec());
+    let mut raw = Vec::new();
+    let mut line_number = 0;
+    let mut progress = RunProgress {
+        rows: 0,
+        last_line: 0,
+        partial: false,
+        memory_exceeded: false,
+        cancelled: false,
+        malformed: None,
+    };
+
+    loop {
+        if budget.cancelled() {
+            progress.cancelled = true;
+            progress.partial = !reader.fill_buf().map_or(true, |buf| buf.is_empty());
+            break;
+        }
+        if budget.exhausted(progress.rows, &started) {
+            progress.partial = !reader.fill_buf().map_or(true, |buf| buf.is_empty());
+            break;
+        }
+        if budget.memory_exceeded_by_next_row(ledger) {
+            progress.memory_exceeded = true;
+            break;
+        }
+        raw.clear();
+        match reader.read_until(b'\n', &mut raw) {
+            Ok(0) => break,
+            Ok(_) => {},
+            Err(err) => return Err(err.into()),
+        }
+        line_number += 1;
+        // Checked before decoding, as a line cut by RecordLimit may end inside a character
+        if let Some(max_bytes) = budget.max_record_bytes.filter(|max_bytes| raw.len() > max_bytes.saturating_add(1)) {
+            progress.rows += 1;
+            progress.last_line = line_number;
+            if !skip_row(ledger, &mut progress, budget, line_number, RowError::OverlongRecord { line: line_number, max_bytes }) {
+                break;
+            }
+            continue;
+        }
+        let line = match std::str::from_utf8(&raw) {
+            Ok(line) => Cow::Borrowed(line),
+            Err(_) if mapping.lossy_utf8 => String::from_utf8_lossy(&raw),
+            Err(_) => {
+                progress.rows += 1;
+                progress.last_line = line_number;
+                if !skip_row(ledger, &mut progress, budget, line_number, RowError::InvalidUtf8 { line: line_number, field: None }) {
+                    break;
+                }
+                continue;
+            },
+        };
+        // A byte order mark starts the input, as the CSV reader skips it
+        let line = if line_number == 1 { line.strip_prefix('\u{feff}').unwrap_or(&line) } else { &line };
+        if line.trim().is_empty() {
+            continue;
+        }
+        progress.rows += 1;
+        progress.last_line = line_number;
+        let mut record = match json_record(line) {
+            Ok(record) => record,
+            Err(reason) => {
+                if !skip_row(ledger, &mut progress, budget, line_number, RowError::UnparsableJson { line: line_number, reason }) {
+                    break;
+                }
+                continue;
+            },
+        };
+        mapping.map_type(&mut record, 0);
+        ledger.remember_row(&record);
+        let transaction: Transaction = match record.deserialize(Some(&headers)) {
+            Ok(transaction) => transaction,
+            Err(err) => {
+                let err = RowError::UnparsableJson { line: line_number, reason: err.to_string() };
+                if !skip_row(ledger, &mut progress, budget, line_number, err) {
+                    break;
+                }
+                continue;
+            },
+        };
+        let result = ledger.process(&transaction);
+        let outcome = result.as_ref().map_err(|err| (err.code(), err as _)).copied();
+        ledger.record_outcome(line_number, Some(&transaction), outcome);
+        match (unknown_type(&result, line_number, line.trim()), &result) {
+            (Some(malformed), _) if budget.strict => {
+                progress.malformed = Some(malformed);
+                break;
+            },
+            (Some(malformed), _) => ledger.notify(Notice::Skipped(malformed)),
+            (None, Err(err)) => ledger.notify(Notice::Rejected(err.clone())),
+            (None, Ok(())) => {},
+        }
+    }
+    Ok(progress)
+}
+
+// Reason code of a row that doesn't deserialize into a transaction
+const UNPARSABLE_ROW: &str = "UNPARSABLE_ROW";
+
+// Reads the next row into raw and decodes it, None at the end of the input.
+// A row process_records would skip, too long, not valid UTF-8 or with a
+// field count other than the header's, is read as the RowError it would
+// report, only a last line missing fields not being told apart.
+fn next_row<R: Read>(
+    reader: &mut Reader<R>,
+    raw: &mut ByteRecord,
+    mapping: &InputMapping,
+    budget: &Budget,
+) -> csv::Result<Option<Result<StringRecord, RowError>>> {
+    match reader.read_byte_record(raw) {
+        Ok(false) => Ok(None),
+        Ok(true) => {
+            let raw_bytes = reader.position().byte() - raw.position().unwrap().byte();
+            Ok(Some(decode_row(raw, raw_bytes, mapping, budget)))
+        },
+        Err(err) => match err.kind() {
+            csv::ErrorKind::UnequalLengths { pos: Some(position), expected_len, len } => {
+                let line = position.line();
+                let raw_bytes = reader.position().byte() - position.byte();
+                Ok(Some(Err(match budget.max_record_bytes.filter(|max_bytes| raw_bytes > *max_bytes as u64 + 1) {
+                    Some(max_bytes) => RowError::OverlongRecord { line, max_bytes },
+                    None => RowError::UnparsableRow {
+                        line,
+                        record: record_text(raw),
+                        reason: format!("expected {} fields, found {}", expected_len, len),
+                    },
+                })))
+            },
+            _ => Err(err),
+        },
+    }
+}
+
+/// Copies the input rows, their fields untouched, adding the outcome of each
+/// row, why it was rejected and its client's balances right after it. Rows
+/// are skipped for the same reasons as by process_records under the budget's
+/// byte limits, a row missing fields padded with empty ones so that its
+/// outcome lines up with the header. Every row is annotated, budget.strict
+/// doesn't stop at a row that isn't a transaction. Balances are rounded as
+/// in the accounts output.
+pub fn annotate<R: Read, W: Write>(ledger: &mut Ledger, reader: &mut Reader<R>, mapping: &InputMapping, budget: &Budget, writer: W) -> csv::Result<()> {
+    let headers = input_headers(reader)?;
+    let type_index = headers.iter().position(|column| column.trim() == "type");
+    let mut writer = WriterBuilder::new().flexible(true).from_writer(writer);
+    writer.write_record(headers.iter().chain(["outcome", "reason", "available", "held", "total"]))?;
+
+    let mut raw = ByteRecord::new();
+    while let Some(decoded) = next_row(reader, &mut raw, mapping, budget)? {
+        let transaction = decoded.and_then(|mut record| {
+            if let Some(type_index) = type_index {
+                mapping.map_type(&mut record, type_index);
+            }
+            record.deserialize::<Transaction>(Some(&headers)).map_err(|err| RowError::UnparsableRow {
+                line: record.position().map_or(0, |position| position.line()),
+                record: record_text(&raw),
+                reason: err.to_string(),
+            })
+        });
+        let (outcome, reason, client_id) = match transaction {
+            Ok(transaction) => match ledger.process(&transaction) {
+                Ok(()) => ("applied", "", Some(transaction.client_id)),
+                Err(err) => ("rejected", err.code(), Some(transaction.client_id)),
+            },
+            Err(err) => ("rejected", err.code(), None),
+        };
+        let balances = match client_id.and_then(|client_id| ledger.get_account(client_id)) {
+            Some(mut account) => {
+                account.round();
+                [account.available.to_string(), account.held.to_string(), account.total.to_string()]
+            },
+            None => Default::default(),
+        };
+        for _ in raw.len()..headers.len() {
+            raw.push_field(b"");
+        }
+        for field in [outcome, reason].into_iter().chain(balances.iter().map(String::as_str)) {
+            raw.push_field(field.as_bytes());
+        }
+        writer.write_byte_record(&raw)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Row the explain subcommand stops at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExplainTarget {
+    Line(u64),
+    /// First row that is a transaction with this tx id
+    Transaction(u32),
+}
+
+/// How the row an explain replay stopped at was decided.
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub line: u64,
+    /// Fields of the row by column name, as read
+    pub fields: Vec<(String, String)>,
+    /// None for a row that isn't a transaction
+    pub transaction: Option<Transaction>,
+    /// Account of the row's client right before it
+    pub account_before: Option<Account>,
+    pub rules: Vec<RuleCheck>,
+    pub outcome: &'static str,
+    pub reason: &'static str,
+    pub message: String,
+}
+
+impl Explanation {
+    /// The explanation as a single JSON object.
+    pub fn to_json(&self) -> String {
+        let fields: Vec<String> = self.fields.iter()
+            .map(|(column, value)| format!("{}: {}", json_string(column), json_string(value)))
+            .collect();
+        let rules: Vec<String> = self.rules.iter()
+            .map(|check| format!("{{\"rule\": {}, \"passed\": {}}}", json_string(check.rule), check.passed))
+            .collect();
+        format!(
+            "{{\n  \"line\": {},\n  \"row\": {{{}}},\n  \"account_before\": {},\n  \"rules\": [{}],\n  \"outcome\": {},\n  \"reason\": {},\n  \"message\": {}\n}}\n",
+            self.line,
+            fields.join(", "),
+            self.account_before.as_ref().map_or("null".to_string(), account_json),
+            rules.join(", "),
+            json_string(self.outcome),
+            json_string(self.reason),
+            json_string(&self.message),
+        )
+    }
+}
+
+impl std::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let fields: Vec<String> = self.fields.iter().map(|(column, value)| format!("{}={}", column, value)).collect();
+        writeln!(f, "Line {}: {}", self.line, fields.join(", "))?;
+        match &self.account_before {
+            Some(account) => writeln!(
+                f,
+                "Account before: client {}, available {}, held {}, total {}, locked {}",
+                account.client_id, account.available.normalize(), account.held.normalize(), account.total.normalize(), account.locked,
+            )?,
+            None => writeln!(f, "Account before: none")?,
+        }
+        writeln!(f, "Rules:")?;
+        for check in &self.rules {
+            writeln!(f, "  {} {}", if check.passed { "pass" } else { "FAIL" }, check.rule)?;
+        }
+        write!(f, "Outcome: {}", self.outcome)?;
+        if !self.reason.is_empty() {
+            write!(f, " {}: {}", self.reason, self.message)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Replays the rows before the target through the ledger, skipping those
+/// process_records would under the budget's byte limits, then explains the
+/// target row and applies it. None if the input has no such row.
+pub fn explain<R: Read>(
+    ledger: &mut Ledger,
+    reader: &mut Reader<R>,
+    mapping: &InputMapping,
+    budget: &Budget,
+    target: ExplainTarget,
+) -> csv::Result<Option<Explanation>> {
+    let headers = input_headers(reader)?;
+    let type_index = headers.iter().position(|column| column.trim() == "type");
+    let mut raw = ByteRecord::new();
+    while let Some(decoded) = next_row(reader, &mut raw, mapping, budget)? {
+        let line = match &decoded {
+            Ok(record) => record.position().map_or(0, |position| position.line()),
+            Err(err) => err.line(),
+        };
+        let (transaction, skipped) = match decoded {
+            Ok(mut record) => {
+                if let Some(type_index) = type_index {
+                    mapping.map_type(&mut record, type_index);
+                }
+                match record.deserialize::<Transaction>(Some(&headers)) {
+                    Ok(transaction) => (Some(transaction), None),
+                    Err(err) => (None, Some(RowError::UnparsableRow { line, record: record_text(&raw), reason: err.to_string() })),
+                }
+            },
+            Err(err) => (None, Some(err)),
+        };
+        let is_target = match target {
+            ExplainTarget::Line(target_line) => line == target_line,
+            ExplainTarget::Transaction(id) => transaction.as_ref().is_some_and(|transaction| transaction.transaction_id == id),
+        };
+        if !is_target {
+            if let Some(transaction) = &transaction {
+                let _ = ledger.process(transaction);
+            }
+            continue;
+        }
+
+        let fields = headers.iter()
+            .zip(raw.iter())
+            .map(|(column, value)| (column.to_string(), String::from_utf8_lossy(value).into_owned()))
+            .collect();
+        let Some(transaction) = transaction else {
+            let err = skipped.unwrap();
+            return Ok(Some(Explanation {
+                line,
+                fields,
+                transaction: None,
+                account_before: None,
+                rules: Vec::new(),
+                outcome: "rejected",
+                reason: err.code(),
+                message: err.to_string(),
+            }));
+        };
+        let account_before = ledger.get_account(transaction.client_id);
+        let rules = ledger.rule_trace(&transaction);
+        let (outcome, reason, message) = match ledger.process(&transaction) {
+            Ok(()) => ("applied", "", String::new()),
+            Err(err) => ("rejected", err.code(), err.to_string()),
+        };
+        return Ok(Some(Explanation { line, fields, transaction: Some(transaction), account_before, rules, outcome, reason, message }));
+    }
+    Ok(None)
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Best effort after a panic: the accounts as they were, which may include
+/// part of the row that panicked, and a JSON report of the panic with the
+/// last rows read, the one that panicked last
+pub fn write_crash_artifacts(ledger: &Ledger, directory: &str, panic: &str) -> std::io::Result<()> {
+    let directory = std::path::Path::new(directory);
+    let snapshot = directory.join("crash-accounts.csv");
+    write_accounts(ledger, File::create(&snapshot)?)?;
+
+    let recent_rows: Vec<String> = ledger.recent_rows.iter()
+        .map(|record| {
+            let fields: Vec<String> = record.iter().map(json_string).collect();
+            format!(
+                "{{\"line\": {}, \"fields\": [{}]}}",
+                record.position().map_or(0, |position| position.line()),
+                fields.join(", "),
+            )
+        })
+        .collect();
+    let report = format!(
+        "{{\n  \"panic\": {},\n  \"rows_processed\": {},\n  \"snapshot\": {},\n  \"recent_rows\": [\n    {}\n  ]\n}}\n",
+        json_string(panic),
+        ledger.rows_processed,
+        json_string(&snapshot.to_string_lossy()),
+        recent_rows.join(",\n    "),
+    );
+    std::fs::write(directory.join("crash-report.json"), report)
+}
+
+/// Writes Ledger::holds_report as CSV.
+pub fn write_holds_report(path: &str, holds: &[HoldRow]) -> Result<(), csv::Error> {
+    let mut writer = WriterBuilder::new().has_headers(false).from_path(path)?;
+    writer.write_record(["client", "tx", "held", "opened_row", "origin"])?;
+    for hold in holds {
+        writer.serialize(hold)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes Ledger::projection_report as CSV.
+pub fn write_projection_report(path: &str, projections: &[ProjectionRow]) -> Result<(), csv::Error> {
+    let mut writer = WriterBuilder::new().has_headers(false).from_path(path)?;
+    writer.write_record([
+        "client", "open_disputes", "available", "held", "total", "locked",
+        "chargeback_available", "chargeback_held", "chargeback_total", "chargeback_locked",
+        "resolve_available", "resolve_held", "resolve_total", "resolve_locked",
+    ])?;
+    for projection in projections {
+        writer.serialize(projection)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads again the first rows of an input to extract the transactions of the
+/// given clients, in input order. Rows are counted and skipped as by
+/// process_records under the budget's byte limits.
+pub fn read_histories<R: Read>(
+    reader: &mut Reader<R>,
+    mapping: &InputMapping,
+    budget: &Budget,
+    rows: u64,
+    clients: &HashSet<u16>,
+) -> Result<HashMap<u16, Vec<Transaction>>, csv::Error> {
+    let headers = input_headers(reader)?;
+    let type_index = headers.iter().position(|column| column.trim() == "type");
+    let mut raw = ByteRecord::new();
+    let mut histories: HashMap<u16, Vec<Transaction>> = HashMap::new();
+    for _ in 0..rows {
+        let Some(decoded) = next_row(reader, &mut raw, mapping, budget)? else {
+            break;
+        };
+        let Ok(mut record) = decoded else {
+            continue;
+        };
+        if let Some(type_index) = type_index {
+            mapping.map_type(&mut record, type_index);
+        }
+        let Ok(transaction) = record.deserialize::<Transaction>(Some(&headers)) else {
+            continue;
+        };
+        if clients.contains(&transaction.client_id) {
+            histories.entry(transaction.client_id).or_default().push(transaction);
+        }
+    }
+    Ok(histories)
+}
+
+/// One account row per sampled client, followed by its transaction rows
+pub fn write_qa_sample(
+    path: &str,
+    ledger: &Ledger,
+    sample: &[(Stratum, u16)],
+    histories: &HashMap<u16, Vec<Transaction>>,
+) -> Result<(), csv::Error> {
+    let mut writer = Writer::from_path(path)?;
+    writer.write_record(["record", "client", "stratum", "available", "held", "total", "locked", "type", "tx", "amount"])?;
+    for (stratum, client_id) in sample {
+        let client = client_id.to_string();
+        let balances = ledger.account_by_id.get(client_id).map_or_else(
+            || [String::new(), String::new(), String::new(), String::new()],
+            |account| [
+                account.available.to_string(),
+                account.held.to_string(),
+                account.total.to_string(),
+                account.locked.to_string(),
+            ],
+        );
+        writer.write_record([
+            "account", &client, stratum.name(), &balances[0], &balances[1], &balances[2], &balances[3], "", "", "",
+        ])?;
+        for transaction in histories.get(client_id).into_iter().flatten() {
+            writer.write_record([
+                "transaction",
+                &client,
+                stratum.name(),
+                "",
+                "",
+                "",
+                "",
+                transaction.transaction_type.name(),
+                &transaction.transaction_id.to_string(),
+                &transaction.amount.map(|amount| amount.to_string()).unwrap_or_default(),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes Ledger::activity_report as CSV.
+pub fn write_activity_report(path: &str, activities: &[&Activity]) -> Result<(), csv::Error> {
+    let mut writer = Writer::from_path(path)?;
+    for activity in activities {
+        writer.serialize(activity)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the accounts as CSV, sorted by client id.
+pub fn write_accounts<W: Write>(ledger: &Ledger, writer: W) -> Result<(), csv::Error> {
+    write_delimited_accounts(ledger, b',', writer)
+}
+
+/// write_accounts with another field delimiter.
+pub fn write_delimited_accounts<W: Write>(ledger: &Ledger, delimiter: u8, writer: W) -> Result<(), csv::Error> {
+    let mut wrtr = WriterBuilder::new().delimiter(delimiter).from_writer(writer);
+    for account in ledger.sorted_accounts() {
+        let mut account = account.clone();
+        account.round();
+        wrtr.serialize(account)?;
+    }
+    wrtr.flush()?;
+    Ok(())
+}
+
+/// Writes to a temporary file next to path, renamed over it once write
+/// succeeded and the data is synced. Whatever fails, path is left as it was
+/// and the temporary file is removed.
+pub fn write_atomically<E, F>(path: &str, write: F) -> Result<(), E>
+where
+    E: From<std::io::Error>,
+    F: FnOnce(&mut File) -> Result<(), E>,
+{
+    let target = std::path::Path::new(path);
+    let file_name = target.file_name().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} is not a file path", path))
+    })?;
+    let temporary = target.with_file_name(format!(".{}.tmp-{}", file_name.to_string_lossy(), std::process::id()));
+    let written = File::create(&temporary)
+        .map_err(E::from)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()?;
+            Ok(())
+        })
+        .and_then(|()| std::fs::rename(&temporary, target).map_err(E::from));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    written
+}
+
+fn account_json(account: &Account) -> String {
+    let mut account = account.clone();
+    account.round();
+    format!(
+        "{{\"client\": {}, \"available\": {}, \"held\": {}, \"total\": {}, \"locked\": {}}}",
+        account.client_id,
+        json_string(&account.available.to_string()),
+        json_string(&account.held.to_string()),
+        json_string(&account.total.to_string()),
+        account.locked,
+    )
+}
+
+/// Same accounts as write_accounts as a JSON array, decimals as strings so
+/// they are read back without going through floats
+pub fn write_accounts_json<W: Write>(ledger: &Ledger, mut writer: W) -> Result<(), csv::Error> {
+    let accounts: Vec<String> = ledger.sorted_accounts().into_iter().map(account_json).collect();
+    if accounts.is_empty() {
+        writeln!(writer, "[]")?;
+    } else {
+        writeln!(writer, "[\n  {}\n]", accounts.join(",\n  "))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Marker written last to a published run directory, what consumers key off
+pub const SUCCESS_MARKER: &str = "_SUCCESS";
+
+/// Outputs of a run written to a staging directory next to the target, then
+/// published all at once by renaming it over the target with a
+/// `SUCCESS_MARKER` inside. A run that fails before publishing leaves the
+/// staging directory for inspection and never touches the target.
+#[derive(Debug)]
+pub struct StagedOutputs {
+    target: std::path::PathBuf,
+    staging: std::path::PathBuf,
+}
+
+impl StagedOutputs {
+    /// Creates the staging directory, refusing a target already published.
+    pub fn new(target: &str) -> std::io::Result<StagedOutputs> {
+        let target = std::path::PathBuf::from(target);
+        if target.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists, outputs are never published over it", target.display()),
+            ));
+        }
+        let name = target.file_name().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} is not a directory path", target.display()))
+        })?;
+        let staging = target.with_file_name(format!(".{}.tmp-{}", name.to_string_lossy(), std::process::id()));
+        std::fs::create_dir_all(&staging)?;
+        Ok(StagedOutputs { target, staging })
+    }
+
+    /// Where to write an artifact of the run.
+    pub fn path(&self, artifact: &str) -> std::path::PathBuf {
+        self.staging.join(artifact)
+    }
+
+    /// Directory the outputs are written to before being published.
+    pub fn staging(&self) -> &std::path::Path {
+        &self.staging
+    }
+
+    /// Writes the marker and renames the staging directory to the target.
+    pub fn publish(self) -> std::io::Result<()> {
+        File::create(self.path(SUCCESS_MARKER))?.sync_all()?;
+        std::fs::rename(&self.staging, &self.target)
+    }
+}
+
+// Export files kept open at once while splitting the audit log by client;
+// past this they are all closed and reopened as needed
+const OPEN_EXPORTS: usize = 256;
+
+// Transaction of a client export, from an audit log row
+fn export_json(row: &StringRecord) -> String {
+    let amount = match &row[5] {
+        "" => "null".to_string(),
+        amount => json_string(amount),
+    };
+    let mut json = format!(
+        "{{\"type\": {}, \"tx\": {}, \"amount\": {}, \"outcome\": {}",
+        json_string(&row[2]), &row[4], amount, json_string(&row[6]),
+    );
+    if &row[6] == "rejected" {
+        json.push_str(&format!(", \"reason\": {}", json_string(&row[7])));
+    }
+    json.push('}');
+    json
+}
+
+/// Writes `<client>.json` to the directory for every client with an applied
+/// or rejected transaction in the audit log, its transactions in input order
+/// then its closing account, `null` if it has none. Returns the number of
+/// files written.
+///
+/// The audit log is split into a `<client>.json.part` file per client in a
+/// single pass, so memory doesn't grow with the input, then each part is
+/// wrapped into its export and removed.
+pub fn write_client_exports<R: Read>(ledger: &Ledger, audit: R, directory: &str) -> csv::Result<usize> {
+    let directory = std::path::Path::new(directory);
+    let part_path = |client_id: u16| directory.join(format!("{}.json.part", client_id));
+    let mut clients = ClientSet::default();
+    let mut parts: HashMap<u16, BufWriter<File>> = HashMap::new();
+    let mut reader = Reader::from_reader(audit);
+    for row in reader.records() {
+        let row = row?;
+        let Ok(client_id) = row[3].parse::<u16>() else {
+            continue;
+        };
+        if &row[6] == "ignored" {
+            continue;
+        }
+        if !parts.contains_key(&client_id) {
+            if parts.len() == OPEN_EXPORTS {
+                for (_, mut part) in parts.drain() {
+                    part.flush()?;
+                }
+            }
+            // Truncates what an earlier run may have left behind
+            let append = clients.contains(client_id);
+            clients.insert(client_id);
+            let file = OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(part_path(client_id))?;
+            parts.insert(client_id, BufWriter::new(file));
+        }
+        writeln!(parts.get_mut(&client_id).unwrap(), "{}", export_json(&row))?;
+    }
+    for (_, mut part) in parts.drain() {
+        part.flush()?;
+    }
+
+    let mut exports = 0;
+    for client_id in clients.iter() {
+        let part = part_path(client_id);
+        let path = directory.join(format!("{}.json", client_id));
+        write_atomically(&path.to_string_lossy(), |file| -> csv::Result<()> {
+            let mut writer = BufWriter::new(file);
+            write!(writer, "{{\n  \"client\": {},\n  \"transactions\": [", client_id)?;
+            for (index, line) in BufReader::new(File::open(&part)?).lines().enumerate() {
+                write!(writer, "{}\n    {}", if index == 0 { "" } else { "," }, line?)?;
+            }
+            let account = ledger.get_account(client_id).map_or("null".to_string(), |account| account_json(&account));
+            writeln!(writer, "\n  ],\n  \"account\": {}\n}}", account)?;
+            writer.flush()?;
+            Ok(())
+        })?;
+        std::fs::remove_file(&part)?;
+        exports += 1;
+    }
+    Ok(exports)
+}
+
+/// Appends the accounts to a history file shared by many runs, each row
+/// tagged with the run label. The header is only written to a new file, an
+/// existing file must have the same columns. The file stays exclusively
+/// locked while appending so concurrent runs don't interleave their rows.
+pub fn append_accounts(ledger: &Ledger, path: &str, run_label: &str) -> std::io::Result<()> {
+    let header = format!("{},run_label", ACCOUNT_COLUMNS.join(","));
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    file.lock()?;
+
+    let length = file.metadata()?.len();
+    let mut writer = WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+    if length == 0 {
+        writer.write_record(header.split(','))?;
+    } else {
+        let mut existing_header = String::new();
+        BufReader::new(&file).read_line(&mut existing_header)?;
+        if existing_header.trim_end() != header {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("existing columns {} differ from {}", existing_header.trim_end(), header),
+            ));
+        }
+        let mut last_byte = [0];
+        file.seek(SeekFrom::Start(length - 1))?;
+        file.read_exact(&mut last_byte)?;
+        if last_byte[0] != b'\n' {
+            writer.write_record(None::<&[u8]>)?;
+        }
+    }
+
+    for account in ledger.sorted_accounts() {
+        let mut account = account.clone();
+        account.round();
+        writer.write_record([
+            account.client_id.to_string(),
+            account.available.to_string(),
+            account.held.to_string(),
+            account.total.to_string(),
+            account.locked.to_string(),
+            run_label.to_string(),
+        ])?;
+    }
+    let rows = writer.into_inner().map_err(|err| std::io::Error::other(err.to_string()))?;
+    file.write_all(&rows)?;
+    file.sync_all()
+}
+
+/// Static attributes of clients, appended to their account rows
+#[derive(Debug, PartialEq)]
+pub struct Enrichment {
+    columns: Vec<String>,
+    // Values of the columns, in order, by client id
+    values_by_id: HashMap<u16, Vec<String>>,
+}
+
+/// Reads the columns of a file keyed by client id, to be appended to the accounts.
+pub fn read_enrichment<R: Read>(reader: &mut Reader<R>, columns: &[String], last_wins: bool) -> Result<Enrichment, String> {
+    let headers = reader.headers().map_err(|err| err.to_string())?.clone();
+    let position = |name: &str| headers.iter().position(|header| header.trim() == name);
+
+    let client_position = position("client").ok_or("missing client column")?;
+    let positions: Vec<usize> = columns.iter()
+        .map(|column| position(column).ok_or_else(|| format!("missing column {}", column)))
+        .collect::<Result<_, _>>()?;
+
+    let mut values_by_id = HashMap::new();
+    for record in reader.records() {
+        let record = record.map_err(|err| err.to_string())?;
+        let line = record.position().map_or(0, |position| position.line());
+        let client_value = record.get(client_position).unwrap_or_default().trim();
+        let client_id = client_value.parse::<u16>()
+            .map_err(|_| format!("invalid value {} on line {}", client_value, line))?;
+        let values = positions.iter().map(|index| record.get(*index).unwrap_or_default().to_string()).collect();
+        if values_by_id.insert(client_id, values).is_some() && !last_wins {
+            return Err(format!("duplicate client {} on line {}", client_id, line));
+        }
+    }
+    Ok(Enrichment { columns: columns.to_vec(), values_by_id })
+}
+
+/// Enriched clients never found in the input, sorted
+pub fn unseen_enriched_clients(enrichment: &Enrichment, clients_seen: &ClientSet) -> Vec<u16> {
+    let mut client_ids: Vec<u16> = enrichment.values_by_id.keys()
+        .copied()
+        .filter(|client_id| !clients_seen.contains(*client_id))
+        .collect();
+    client_ids.sort_unstable();
+    client_ids
+}
+
+/// Same rows as write_accounts followed by the review_hold column if asked
+/// for, then by the enrichment columns, left blank for clients missing from
+/// the enrichment
+pub fn write_extended_accounts<W: Write>(
+    ledger: &Ledger,
+    review_column: bool,
+    enrichment: Option<&Enrichment>,
+    writer: W,
+) -> Result<(), csv::Error> {
+    let mut writer = WriterBuilder::new().has_headers(false).from_writer(writer);
+    let enrichment_columns = enrichment.map_or(&[][..], |enrichment| &enrichment.columns);
+    writer.write_record(
+        ACCOUNT_COLUMNS.iter().copied()
+            .chain(review_column.then_some("review_hold"))
+            .chain(enrichment_columns.iter().map(String::as_str)),
+    )?;
+    let blanks = vec![String::new(); enrichment_columns.len()];
+    for account in ledger.sorted_accounts() {
+        let mut account = account.clone();
+        account.round();
+        let values = enrichment
+            .and_then(|enrichment| enrichment.values_by_id.get(&account.client_id))
+            .unwrap_or(&blanks);
+        writer.write_record(
+            [
+                account.client_id.to_string(),
+                account.available.to_string(),
+                account.held.to_string(),
+                account.total.to_string(),
+                account.locked.to_string(),
+            ]
+            .iter()
+            .chain(review_column.then(|| account.review_hold.to_string()).iter())
+            .chain(values),
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// SHA-256 of the accounts CSV: rows sorted by client id, normalized
+/// decimals, so two machines processing the same input print the same hash
+pub fn accounts_checksum(ledger: &Ledger) -> String {
+    let mut output = Vec::new();
+    write_accounts(ledger, &mut output).unwrap();
+    Sha256::digest(&output).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Rows of a repaired output, salvaged as they were or regenerated.
+#[derive(Debug, PartialEq)]
+pub struct RepairReport {
+    pub salvaged_rows: usize,
+    pub regenerated_rows: usize,
+}
+
+/// Regenerates the accounts output and counts the rows of the partial output
+/// that can be trusted: the leading lines identical to the regenerated ones.
+/// A last line without its newline may be cut mid-row, so it never is.
+pub fn repair_output(ledger: &Ledger, partial: &[u8]) -> (Vec<u8>, RepairReport) {
+    let mut output = Vec::new();
+    write_accounts(ledger, &mut output).unwrap();
+    let lines = || output.split_inclusive(|byte| *byte == b'\n');
+    let salvaged_lines = lines()
+        .zip(partial.split_inclusive(|byte| *byte == b'\n'))
+        .take_while(|(line, partial_line)| line == partial_line && line.ends_with(b"\n"))
+        .count();
+    // The header line isn't a row
+    let rows = lines().count().saturating_sub(1);
+    let salvaged_rows = salvaged_lines.saturating_sub(1);
+    let report = RepairReport {
+        salvaged_rows,
+        regenerated_rows: rows - salvaged_rows,
+    };
+    (output, report)
+}
+
+const RECONCILED_FIELDS: [&str; 3] = ["available", "held", "total"];
+
+/// A balance differing from the external one by more than the tolerance.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ReconcileDelta {
+    #[serde(rename = "client")]
+    client_id: u16,
+    field: &'static str,
+    computed: Decimal,
+    external: Decimal,
+    // computed minus external
+    delta: Decimal,
+}
+
+fn balance(account: &Account, field: &str) -> Decimal {
+    match field {
+        "available" => account.available,
+        "held" => account.held,
+        _ => account.total,
+    }
+}
+
+/// Joins the external balances against the ledger's accounts and returns the
+/// differences larger than the tolerance, sorted by client id. A client
+/// missing on either side counts as zero balances there. Only the balance
+/// fields present in the external header are compared.
+pub fn reconcile<R: Read>(
+    ledger: &Ledger,
+    reader: &mut Reader<R>,
+    column_map: &HashMap<String, String>,
+    tolerance: Decimal,
+) -> Result<Vec<ReconcileDelta>, String> {
+    let column_name = |name: &str| column_map.get(name).map_or(name, |column| column.as_str()).to_string();
+    let headers = reader.headers().map_err(|err| err.to_string())?.clone();
+    let position = |name: &str| headers.iter().position(|header| header.trim() == column_name(name));
+
+    let client_position = position("client")
+        .ok_or_else(|| format!("missing client column {}", column_name("client")))?;
+    let fields: Vec<(&'static str, usize)> = RECONCILED_FIELDS.iter()
+        .filter_map(|field| position(field).map(|index| (*field, index)))
+        .collect();
+    if fields.is_empty() {
+        return Err("no balance column to compare (available, held or total)".to_string());
+    }
+
+    let mut external_by_id: HashMap<u16, Account> = HashMap::new();
+    for record in reader.records() {
+        let record = record.map_err(|err| err.to_string())?;
+        let line = record.position().map_or(0, |position| position.line());
+        let parse_error = |value: &str| format!("invalid value {} on line {}", value, line);
+        let client_value = record.get(client_position).unwrap_or_default().trim();
+        let client_id = client_value.parse::<u16>().map_err(|_| parse_error(client_value))?;
+        let mut external = Account::new(client_id);
+        for (field, index) in &fields {
+            let value = record.get(*index).unwrap_or_default().trim();
+            let amount = value.parse::<Decimal>().map_err(|_| parse_error(value))?;
+            match *field {
+                "available" => external.available = amount,
+                "held" => external.held = amount,
+                _ => external.total = amount,
+            }
+        }
+        if external_by_id.insert(client_id, external).is_some() {
+            return Err(format!("duplicate client {} on line {}", client_id, line));
+        }
+    }
+
+    let mut client_ids: Vec<u16> = external_by_id.keys().copied()
+        .chain(ledger.account_by_id.keys().copied())
+        .collect();
+    client_ids.sort_unstable();
+    client_ids.dedup();
+
+    let mut deltas = Vec::new();
+    for client_id in client_ids {
+        let computed = ledger.account_by_id.get(&client_id).cloned().unwrap_or_else(|| Account::new(client_id));
+        let external = external_by_id.get(&client_id).cloned().unwrap_or_else(|| Account::new(client_id));
+        for (field, _) in &fields {
+            let delta = balance(&computed, field) - balance(&external, field);
+            if delta.abs() > tolerance {
+                deltas.push(ReconcileDelta {
+                    client_id,
+                    field,
+                    computed: balance(&computed, field),
+                    external: balance(&external, field),
+                    delta,
+                });
+            }
+        }
+    }
+    Ok(deltas)
+}
+
+/// The header is written even when there is no difference to report
+pub fn write_reconcile_report(path: &str, deltas: &[ReconcileDelta]) -> Result<(), csv::Error> {
+    let mut writer = WriterBuilder::new().has_headers(false).from_path(path)?;
+    writer.write_record(["client", "field", "computed", "external", "delta"])?;
+    for delta in deltas {
+        writer.serialize(delta)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// The --dry-run report, every row not applied with the input it came from
+pub fn write_rejections<W: Write>(rejections: &[(String, Rejection)], writer: W) -> Result<(), csv::Error> {
+    let mut writer = WriterBuilder::new().has_headers(false).from_writer(writer);
+    writer.write_record(["input", "line", "reason", "message"])?;
+    for (input, rejection) in rejections {
+        writer.write_record([input, &rejection.line.to_string(), rejection.reason, &rejection.message])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Sums of the accounts of one business unit, or of all of them
+#[derive(Serialize, Debug, PartialEq)]
+pub struct UnitSubtotal {
+    unit: String,
+    accounts: usize,
+    locked: usize,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+}
+
+impl UnitSubtotal {
+    fn new(unit: &str) -> UnitSubtotal {
+        UnitSubtotal {
+            unit: unit.to_string(),
+            accounts: 0,
+            locked: 0,
+            available: dec!(0),
+            held: dec!(0),
+            total: dec!(0),
+        }
+    }
+
+    fn add(&mut self, account: &Account) {
+        self.accounts += 1;
+        self.locked += usize::from(account.locked);
+        self.available += account.available;
+        self.held += account.held;
+        self.total += account.total;
+    }
+}
+
+/// A client found in several units, its balances summed over them
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CrossUnitRow {
+    client: u16,
+    // Labels of the units, in the order they were given, separated by ;
+    units: String,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked_units: usize,
+}
+
+/// Reads back the accounts output of a run
+pub fn read_accounts<R: Read>(reader: &mut Reader<R>) -> csv::Result<Vec<Account>> {
+    reader.deserialize().collect()
+}
+
+/// Subtotals of each unit followed by the grand total, and the clients found
+/// in more than one unit sorted by client id. Accounts are only read.
+pub fn consolidate(units: &[(String, Vec<Account>)]) -> (Vec<UnitSubtotal>, Vec<CrossUnitRow>) {
+    let mut subtotals = Vec::new();
+    let mut grand_total = UnitSubtotal::new("total");
+    let mut by_client: BTreeMap<u16, Vec<(&str, &Account)>> = BTreeMap::new();
+    for (unit, accounts) in units {
+        let mut subtotal = UnitSubtotal::new(unit);
+        for account in accounts {
+            subtotal.add(account);
+            grand_total.add(account);
+            by_client.entry(account.client_id).or_default().push((unit, account));
+        }
+        subtotals.push(subtotal);
+    }
+    subtotals.push(grand_total);
+
+    let cross_units = by_client.into_iter()
+        .filter(|(_, accounts)| accounts.len() > 1)
+        .map(|(client, accounts)| CrossUnitRow {
+            client,
+            units: accounts.iter().map(|(unit, _)| *unit).collect::<Vec<_>>().join(";"),
+            available: accounts.iter().map(|(_, account)| account.available).sum::<Decimal>().normalize(),
+            held: accounts.iter().map(|(_, account)| account.held).sum::<Decimal>().normalize(),
+            total: accounts.iter().map(|(_, account)| account.total).sum::<Decimal>().normalize(),
+            locked_units: accounts.iter().filter(|(_, account)| account.locked).count(),
+        })
+        .collect();
+    for subtotal in &mut subtotals {
+        subtotal.available = subtotal.available.normalize();
+        subtotal.held = subtotal.held.normalize();
+        subtotal.total = subtotal.total.normalize();
+    }
+    (subtotals, cross_units)
+}
+
+/// Writes the subtotals of consolidate as CSV.
+pub fn write_consolidation(path: &str, subtotals: &[UnitSubtotal]) -> Result<(), csv::Error> {
+    let mut writer = Writer::from_path(path)?;
+    for subtotal in subtotals {
+        writer.serialize(subtotal)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// The header is written even when no client is in several units
+pub fn write_cross_unit_report(path: &str, rows: &[CrossUnitRow]) -> Result<(), csv::Error> {
+    let mut writer = WriterBuilder::new().has_headers(false).from_path(path)?;
+    writer.write_record(["client", "units", "available", "held", "total", "locked_units"])?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Line asking a server connection for the accounts
+const ACCOUNTS_QUERY: &str = "ACCOUNTS";
+
+// Reads one headerless CSV row, type,client,tx and an optional amount
+fn parse_transaction_line(line: &str) -> Result<Transaction, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_bytes());
+    let mut record = StringRecord::new();
+    if !reader.read_record(&mut record).map_err(|err| err.to_string())? {
+        return Err("empty line".to_string());
+    }
+    if record.len() == 3 {
+        record.push_field("");
+    }
+    record.deserialize(Some(&StringRecord::from(INPUT_COLUMNS.to_vec()))).map_err(|err| err.to_string())
+}
+
+// The ledger of a server, even when a thread panicked holding it: only the
+// connection that panicked is lost, the others keep being served
+fn lock_ledger(ledger: &std::sync::Mutex<Ledger>) -> std::sync::MutexGuard<'_, Ledger> {
+    ledger.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Applies the rows a client sends, one per line, to the shared ledger and
+/// answers each with OK or ERROR and why. An ACCOUNTS line is answered with
+/// the accounts CSV followed by an empty line.
+pub fn serve_connection<R: BufRead, W: Write>(ledger: &std::sync::Mutex<Ledger>, reader: R, mut writer: W) -> std::io::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == ACCOUNTS_QUERY {
+            let mut accounts = Vec::new();
+            write_accounts(&lock_ledger(ledger), &mut accounts).map_err(std::io::Error::other)?;
+            writer.write_all(&accounts)?;
+            writeln!(writer)?;
+        } else {
+            match parse_transaction_line(line) {
+                Ok(transaction) => match lock_ledger(ledger).process(&transaction) {
+                    Ok(()) => writeln!(writer, "OK")?,
+                    Err(err) => writeln!(writer, "ERROR [{}] {}", err.code(), err)?,
+                },
+                Err(err) => writeln!(writer, "ERROR [{}] {}", UNPARSABLE_ROW, err)?,
+            }
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Serves each connection on its own thread, all against the same ledger,
+/// until the listener fails. With http, connections speak HTTP rather than
+/// CSV lines.
+pub fn serve(listener: std::net::TcpListener, ledger: Arc<std::sync::Mutex<Ledger>>, http: bool) -> std::io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let ledger = Arc::clone(&ledger);
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().map_or_else(|_| "unknown peer".to_string(), |peer| peer.to_string());
+            let served = stream.try_clone().and_then(|reader| if http {
+                serve_http_connection(&ledger, reader, BufWriter::new(stream))
+            } else {
+                serve_connection(&ledger, BufReader::new(reader), BufWriter::new(stream))
+            });
+            if let Err(err) = served {
+                eprintln!("Connection from {} failed: {}", peer, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+// Status and JSON body of an HTTP error
+fn http_error(status: u16, code: &str, message: &str) -> (u16, String) {
+    (status, format!("{{\"error\": {}, \"message\": {}}}", json_string(code), json_string(message)))
+}
+
+/// Status and JSON body of an HTTP request to the ledger:
+/// POST /transactions with a transaction object like the JSON Lines input,
+/// GET /accounts and GET /accounts/{client}
+pub fn route_http(ledger: &std::sync::Mutex<Ledger>, method: &str, path: &str, body: &str) -> (u16, String) {
+    let error = http_error;
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("POST", ["transactions"]) => {
+            let transaction = json_record(body)
+                .and_then(|record| record.deserialize::<Transaction>(Some(&StringRecord::from(INPUT_COLUMNS.to_vec())))
+                    .map_err(|err| err.to_string()));
+            match transaction {
+                Ok(transaction) => match lock_ledger(ledger).process(&transaction) {
+                    Ok(()) => (200, "{\"outcome\": \"applied\"}".to_string()),
+                    Err(err) => error(422, err.code(), &err.to_string()),
+                },
+                Err(err) => error(400, UNPARSABLE_ROW, &err),
+            }
+        },
+        ("GET", ["accounts"]) => {
+            let mut accounts = Vec::new();
+            match write_accounts_json(&lock_ledger(ledger), &mut accounts) {
+                Ok(()) => (200, String::from_utf8_lossy(&accounts).into_owned()),
+                Err(err) => error(500, "INTERNAL_ERROR", &err.to_string()),
+            }
+        },
+        ("GET", ["accounts", client]) => {
+            let account = client.parse().ok().and_then(|client| lock_ledger(ledger).get_account(client));
+            match account {
+                Some(account) => (200, account_json(&account)),
+                None => error(404, "UNKNOWN_ACCOUNT", &format!("no account for client {}", client)),
+            }
+        },
+        (_, ["transactions"] | ["accounts"] | ["accounts", _]) => error(405, "METHOD_NOT_ALLOWED", &format!("{} is not allowed on {}", method, path)),
+        _ => error(404, "NOT_FOUND", &format!("nothing at {}", path)),
+    }
+}
+
+// Longest request or header line and largest body of an HTTP request
+const MAX_HTTP_BYTES: usize = 64 * 1024;
+
+// Next line of an HTTP request without its line break, None at the end
+fn read_http_line<R: BufRead>(reader: &mut R) -> std::io::Result<Option<String>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn write_http_response<W: Write>(writer: &mut W, (status, response): (u16, String)) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status, reason, response.len(), response,
+    )?;
+    writer.flush()
+}
+
+/// Answers the HTTP/1.1 requests of one connection with route_http, until
+/// the client closes it or asks to. A request with a line or a body longer
+/// than 64KB is answered with 431 or 413 and the connection closed.
+pub fn serve_http_connection<R: Read, W: Write>(ledger: &std::sync::Mutex<Ledger>, reader: R, mut writer: W) -> std::io::Result<()> {
+    // Longer lines arrive cut, still longer than the limit, so that an
+    // endless one never grows the buffers
+    let mut reader = BufReader::new(RecordLimit::new(reader, MAX_HTTP_BYTES));
+    let too_large = |what: &str| http_error(431, "HEADER_TOO_LARGE", &format!("{} longer than {} bytes", what, MAX_HTTP_BYTES));
+    loop {
+        let Some(request_line) = read_http_line(&mut reader)? else {
+            return Ok(());
+        };
+        if request_line.len() > MAX_HTTP_BYTES {
+            return write_http_response(&mut writer, too_large("request line"));
+        }
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        let (method, path) = (method.to_string(), path.to_string());
+
+        let mut content_length = 0;
+        let mut close = false;
+        loop {
+            let header = match read_http_line(&mut reader)? {
+                Some(header) if !header.trim().is_empty() => header,
+                _ => break,
+            };
+            if header.len() > MAX_HTTP_BYTES {
+                return write_http_response(&mut writer, too_large("header line"));
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                    "connection" => close = value.trim().eq_ignore_ascii_case("close"),
+                    _ => {},
+                }
+            }
+        }
+        if content_length > MAX_HTTP_BYTES {
+            let message = format!("body of {} bytes, expected at most {}", content_length, MAX_HTTP_BYTES);
+            return write_http_response(&mut writer, http_error(413, "PAYLOAD_TOO_LARGE", &message));
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        write_http_response(&mut writer, route_http(ledger, &method, &path, &String::from_utf8_lossy(&body)))?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+/// One tier of a fee schedule: deposits or withdrawals of at least from are
+/// charged percent of their amount plus fixed
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeTier {
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub from: Decimal,
+    pub percent: Decimal,
+    pub fixed: Decimal,
+}
+
+/// Tiers by type, each type's sorted by from
+#[derive(Debug, Default, PartialEq)]
+pub struct FeeSchedule {
+    tiers: Vec<FeeTier>,
+}
+
+impl FeeSchedule {
+    /// A schedule of the tiers, refused when one is not a deposit or withdrawal
+    /// tier, has a negative value or starts where another of its type does.
+    pub fn new(tiers: Vec<FeeTier>) -> Result<FeeSchedule, String> {
+        for tier in &tiers {
+            if !matches!(tier.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal) {
+                return Err(format!("invalid fee type {}, expected deposit or withdrawal", tier.transaction_type.name()));
+            }
+            if tier.from.is_sign_negative() || tier.percent.is_sign_negative() || tier.fixed.is_sign_negative() {
+                return Err(format!("negative value in the {} tier from {}", tier.transaction_type.name(), tier.from));
+            }
+        }
+        let mut tiers = tiers;
+        tiers.sort_by(|a, b| a.transaction_type.name().cmp(b.transaction_type.name()).then(a.from.cmp(&b.from)));
+        if let Some(tier) = tiers.windows(2).find(|pair| pair[0].transaction_type == pair[1].transaction_type && pair[0].from == pair[1].from) {
+            return Err(format!("two {} tiers from {}", tier[0].transaction_type.name(), tier[0].from));
+        }
+        Ok(FeeSchedule { tiers })
+    }
+
+    /// Fee of the tier the amount falls in, the one with the highest from not
+    /// above it. None when no tier of the type starts low enough.
+    pub fn fee(&self, transaction_type: &TransactionType, amount: Decimal) -> Option<Decimal> {
+        self.tiers.iter()
+            .rev()
+            .find(|tier| tier.transaction_type == *transaction_type && tier.from <= amount)
+            .map(|tier| round_amount(amount * tier.percent / dec!(100) + tier.fixed))
+    }
+}
+
+/// Reads a fee schedule, one tier per row.
+pub fn read_fee_schedule<R: Read>(reader: &mut Reader<R>) -> Result<FeeSchedule, String> {
+    let tiers = reader.deserialize().collect::<csv::Result<Vec<FeeTier>>>().map_err(|err| err.to_string())?;
+    FeeSchedule::new(tiers)
+}
+
+/// Fees one client would have been charged
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ClientFees {
+    client: u16,
+    transactions: u64,
+    fees: Decimal,
+}
+
+/// Processes the rows like process_records under the budget's byte limits, and
+/// computes the fees the schedule would have charged on the applied deposits
+/// and withdrawals, by client id. The balances are left as without fees.
+pub fn simulate_fees<R: Read>(
+    ledger: &mut Ledger,
+    reader: &mut Reader<R>,
+    mapping: &InputMapping,
+    budget: &Budget,
+    schedule: &FeeSchedule,
+) -> csv::Result<Vec<ClientFees>> {
+    let headers = input_headers(reader)?;
+    let type_index = headers.iter().position(|column| column.trim() == "type");
+    let mut fees_by_client: BTreeMap<u16, ClientFees> = BTreeMap::new();
+    let mut raw = ByteRecord::new();
+    while let Some(decoded) = next_row(reader, &mut raw, mapping, budget)? {
+        let Ok(mut record) = decoded else {
+            continue;
+        };
+        if let Some(type_index) = type_index {
+            mapping.map_type(&mut record, type_index);
+        }
+        let Ok(transaction) = record.deserialize::<Transaction>(Some(&headers)) else {
+            continue;
+        };
+        if ledger.process(&transaction).is_err() {
+            continue;
+        }
+        let fee = transaction.amount
+            .and_then(|amount| schedule.fee(&transaction.transaction_type, round_amount(amount)));
+        if let Some(fee) = fee {
+            let client_fees = fees_by_client.entry(transaction.client_id).or_insert(ClientFees {
+                client: transaction.client_id,
+                transactions: 0,
+                fees: dec!(0),
+            });
+            client_fees.transactions += 1;
+            client_fees.fees += fee;
+        }
+    }
+    Ok(fees_by_client.into_values()
+        .map(|client_fees| ClientFees { fees: client_fees.fees.normalize(), ..client_fees })
+        .collect())
+}
+
+/// One row per charged client then a total row. The header is written even
+/// when nothing would have been charged.
+pub fn write_fee_report(path: &str, fees: &[ClientFees]) -> Result<(), csv::Error> {
+    let mut writer = WriterBuilder::new().has_headers(false).from_path(path)?;
+    writer.write_record(["client", "transactions", "fees"])?;
+    for client_fees in fees {
+        writer.serialize(client_fees)?;
+    }
+    let transactions: u64 = fees.iter().map(|client_fees| client_fees.transactions).sum();
+    let total: Decimal = fees.iter().map(|client_fees| client_fees.fees).sum();
+    writer.write_record(["total".to_string(), transactions.to_string(), total.normalize().to_string()])?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Deliberately naive ledger rules, the executable spec the engine is checked
+// against: every transaction is kept in a list searched from the start and
+// accounts are looked up the same way. Only the default policies and the
+// deposit, withdrawal, dispute, resolve and chargeback types are covered. A
+// rule changed in Ledger must be changed here too, or reference_test fails.
+#[cfg(test)]
+mod reference {
+    use super::*;
+
+    struct Recorded {
+        transaction_id: u32,
+        client_id: u16,
+        amount: Decimal,
+        withdrawal: bool,
+        state: DisputeState,
+        applied: bool,
+    }
+
+    #[derive(Default)]
+    pub struct ReferenceLedger {
+        recorded: Vec<Recorded>,
+        accounts: Vec<Account>,
+    }
+
+    impl ReferenceLedger {
+        fn account(&mut self, client_id: u16) -> Option<&mut Account> {
+            self.accounts.iter_mut().find(|account| account.client_id == client_id)
+        }
+
+        /// Ok, or the code of the LedgerError the engine must return
+        pub fn process(&mut self, transaction: &Transaction) -> Result<(), &'static str> {
+            let amount = transaction.amount.map(round_amount);
+            match transaction.transaction_type {
+                TransactionType::Deposit | TransactionType::Withdrawal => {
+                    let withdrawal = transaction.transaction_type == TransactionType::Withdrawal;
+                    let amount = amount.ok_or("MISSING_AMOUNT")?;
+                    if amount <= dec!(0) || amount > MAX_AMOUNT {
+                        return Err("INVALID_AMOUNT");
+                    }
+                    if self.recorded.iter().any(|recorded| recorded.transaction_id == transaction.transaction_id) {
+                        return Err("DUPLICATE_TRANSACTION");
+                    }
+                    // Refused deposits and withdrawals are recorded too, so
+                    // that their disputes are refused as never applied
+                    self.recorded.push(Recorded {
+                        transaction_id: transaction.transaction_id,
+                        client_id: transaction.client_id,
+                        amount,
+                        withdrawal,
+                        state: DisputeState::Normal,
+                        applied: false,
+                    });
+                    if self.account(transaction.client_id).is_none() {
+                        if withdrawal {
+                            return Err("UNKNOWN_ACCOUNT");
+                        }
+                        self.accounts.push(Account::new(transaction.client_id));
+                    }
+                    let account = self.account(transaction.client_id).unwrap();
+                    if account.locked {
+                        return Err("ACCOUNT_LOCKED");
+                    }
+                    if withdrawal {
+                        if account.available < amount {
+                            return Err("INSUFFICIENT_FUNDS");
+                        }
+                        account.available -= amount;
+                    } else {
+                        account.available += amount;
+                    }
+                    self.recorded.last_mut().unwrap().applied = true;
+                },
+                TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                    let index = self.recorded.iter()
+                        .position(|recorded| recorded.transaction_id == transaction.transaction_id)
+                        .ok_or("UNKNOWN_TRANSACTION")?;
+                    if self.recorded[index].client_id != transaction.client_id {
+                        return Err("CLIENT_MISMATCH");
+                    }
+                    let (amount, withdrawal, state, applied) = {
+                        let recorded = &self.recorded[index];
+                        (recorded.amount, recorded.withdrawal, recorded.state, recorded.applied)
+                    };
+                    let account = self.account(transaction.client_id).ok_or("UNKNOWN_ACCOUNT")?;
+                    if transaction.transaction_type == TransactionType::Dispute && !applied {
+                        return Err("NOT_APPLIED");
+                    }
+                    let next = match (&transaction.transaction_type, state) {
+                        (TransactionType::Dispute, DisputeState::Normal) => DisputeState::Disputed,
+                        (TransactionType::Resolve, DisputeState::Disputed) => DisputeState::Normal,
+                        (TransactionType::Chargeback, DisputeState::Disputed) => DisputeState::ChargedBack,
+                        _ => return Err("INVALID_DISPUTE_STATE"),
+                    };
+                    match (&transaction.transaction_type, withdrawal) {
+                        (TransactionType::Dispute, _) if account.locked => return Err("ACCOUNT_LOCKED"),
+                        (TransactionType::Dispute, false) if account.available <= amount => return Err("INSUFFICIENT_FUNDS"),
+                        (TransactionType::Dispute, false) => {
+                            account.available -= amount;
+                            account.held += amount;
+                        },
+                        // The withdrawn funds are held for a refund
+                        (TransactionType::Dispute, true) => account.held += amount,
+                        _ if account.held < amount => return Err("INSUFFICIENT_HELD_FUNDS"),
+                        (TransactionType::Resolve, false) | (TransactionType::Chargeback, true) => {
+                            account.held -= amount;
+                            account.available += amount;
+                        },
+                        _ => account.held -= amount,
+                    }
+                    if transaction.transaction_type == TransactionType::Chargeback {
+                        account.locked = true;
+                    }
+                    self.recorded[index].state = next;
+                },
+                _ => return Err("UNKNOWN_TYPE"),
+            }
+            Ok(())
+        }
+
+        /// Sorted by client, total computed rather than tracked
+        pub fn accounts(&self) -> Vec<Account> {
+            let mut accounts: Vec<Account> = self.accounts.iter()
+                .map(|account| Account { total: account.available + account.held, ..account.clone() })
+                .collect();
+            accounts.sort_by_key(|account| account.client_id);
+            accounts
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    // Counts the bytes allocated by each test thread, to check
+    // Ledger::memory_report against what is really allocated
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATED.with(|allocated| allocated.set(allocated.get() + layout.size() as isize));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            ALLOCATED.with(|allocated| allocated.set(allocated.get() - layout.size() as isize));
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // Column or type mapping of an InputMapping
+    fn pairs(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn deposit_test() {
+        let mut ledger = Ledger::default();
+        let mut transaction = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(dec!(1.5)),
+        };
+
+        ledger.deposit(&transaction).unwrap();
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.5));
+        assert_eq!(ledger.get_account(1).unwrap().total, dec!(1.5));
+
+        transaction.transaction_id = 2;
+        transaction.amount = Some(dec!(4.5));
+
+        ledger.deposit(&transaction).unwrap();
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(6.0));
+        assert_eq!(ledger.get_account(1).unwrap().total, dec!(6.0));
+    }
+
+    #[test]
+    fn withdraw_test() {
+        let mut ledger = Ledger::default();
+        let transaction_deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(dec!(1.5)),
+        };
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        let mut transaction_withdrawal = Transaction {
+            transaction_type: TransactionType::Withdrawal,
+            client_id: 1,
+            transaction_id: 2,
+            amount: Some(dec!(0.5)),
+        };
+
+
+        ledger.withdraw(&transaction_withdrawal).unwrap();
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.0));
+        assert_eq!(ledger.get_account(1).unwrap().total, dec!(1.0));
+
+        transaction_withdrawal.transaction_id = 3;
+        transaction_withdrawal.amount = Some(dec!(2.0));
+
+        assert_eq!(ledger.withdraw(&transaction_withdrawal), Err(LedgerError::InsufficientFunds {
+            operation: TransactionType::Withdrawal,
+            client: 1,
+            requested: dec!(2.0),
+            available: dec!(1.0),
+        }));
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.0));
+        assert_eq!(ledger.get_account(1).unwrap().total, dec!(1.0));
+    }
+
+    #[test]
+    fn dispute_test() {
+        let mut ledger = Ledger::default();
+        let mut transaction_deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(dec!(1.5)),
+        };
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        transaction_deposit.transaction_id = 2;
+        transaction_deposit.amount = Some(dec!(10.0));
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        let dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+        };
+
+        ledger.dispute(&dispute).unwrap();
+
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10.0));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(1.5));
+        assert_eq!(ledger.get_account(1).unwrap().total, dec!(11.5));
+    }
+
+    #[test]
+    fn resolve_test() {
+        let mut ledger = Ledger::default();
+        let mut transaction_deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(dec!(1.5)),
+        };
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        transaction_deposit.transaction_id = 2;
+        transaction_deposit.amount = Some(dec!(10.0));
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        let dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+        };
+
+        ledger.dispute(&dispute).unwrap();
+
+        let resolve = Transaction {
+            transaction_type: TransactionType::Resolve,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+        };
+
+        ledger.resolve(&resolve).unwrap();
+
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(11.5));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(0));
+        assert_eq!(ledger.get_account(1).unwrap().total, dec!(11.5));
+    }
+
+    #[test]
+    fn chargeback_test() {
+        let mut ledger = Ledger::default();
+        let mut transaction_deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(dec!(1.5)),
+        };
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        transaction_deposit.transaction_id = 2;
+        transaction_deposit.amount = Some(dec!(10.0));
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        let dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+        };
+
+        ledger.dispute(&dispute).unwrap();
+
+        let chargeback = Transaction {
+            transaction_type: TransactionType::Chargeback,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+        };
+
+        ledger.chargeback(&chargeback).unwrap();
+
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(0));
+        assert_eq!(ledger.get_account(1).unwrap().total, dec!(10));
+        assert!(ledger.get_account(1).unwrap().locked);
+    }
+
+    #[test]
+    fn invalid_amount_test() {
+        let mut ledger = Ledger::default();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(10.0)))).unwrap();
+        for (transaction_type, transaction_id, amount) in [
+            (TransactionType::Deposit, 2, dec!(-100.0)),
+            (TransactionType::Withdrawal, 3, dec!(-5.0)),
+            (TransactionType::Deposit, 4, dec!(0)),
+            (TransactionType::Withdrawal, 5, dec!(0.0)),
+            (TransactionType::Deposit, 6, dec!(1_000_000_000_000.0001)),
+        ] {
+            assert_eq!(
+                ledger.process(&Transaction::new(transaction_type, 1, transaction_id, Some(amount))),
+                Err(LedgerError::InvalidAmount { transaction: transaction_id, client: 1, amount }),
+            );
+        }
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Deposit, 1, 7, None)),
+            Err(LedgerError::MissingAmount(7)),
+        );
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10));
+        assert_eq!(ledger.get_account(1).unwrap().total, dec!(10));
+
+        // Not stored for a later dispute
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Dispute, 1, 2, None)),
+            Err(LedgerError::UnknownTransaction { operation: TransactionType::Dispute, transaction: 2 }),
+        );
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 8, Some(dec!(1_000_000_000_000)))).unwrap();
+        assert_eq!(
+            LedgerError::InvalidAmount { transaction: 2, client: 1, amount: dec!(-100.0) }.to_string(),
+            "Transaction 2 for client 1 has an invalid amount -100.0, expected more than 0 and at most 1000000000000",
+        );
+    }
+
+    #[test]
+    fn duplicate_transaction_test() {
+        let mut ledger = Ledger::with_retention(RetentionPolicy { min_amount: Some(dec!(1.0)), clients: None });
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(10.0)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 2, Some(dec!(0.5)))).unwrap();
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(50.0)))),
+            Err(LedgerError::DuplicateTransaction { transaction: 1, client: 1 }),
+        );
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Withdrawal, 2, 1, Some(dec!(5.0)))),
+            Err(LedgerError::DuplicateTransaction { transaction: 1, client: 2 }),
+        );
+        // tx 2 was not retained, its id is still taken
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Withdrawal, 1, 2, Some(dec!(5.0)))),
+            Err(LedgerError::DuplicateTransaction { transaction: 2, client: 1 }),
+        );
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10.5));
+
+        ledger.process(&Transaction::new(TransactionType::Dispute, 1, 1, None)).unwrap();
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(0.5));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(10.0));
+        assert_eq!(
+            LedgerError::DuplicateTransaction { transaction: 1, client: 2 }.to_string(),
+            "Transaction 1 for client 2 reuses the id of an earlier transaction, not applied",
+        );
+    }
+
+    #[test]
+    fn sentinel_ids_test() {
+        let process = |sentinel_ids: SentinelIds| {
+            let mut ledger = Ledger { sentinel_ids, ..Default::default() };
+            let outcomes: Vec<Result<(), LedgerError>> = [
+                Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(10.0))),
+                Transaction::new(TransactionType::Deposit, 0, 2, Some(dec!(5.0))),
+                Transaction::new(TransactionType::Deposit, 1, 0, Some(dec!(3.0))),
+                Transaction::new(TransactionType::Dispute, 1, 0, None),
+            ]
+            .iter()
+            .map(|transaction| ledger.process(transaction))
+            .collect();
+            (ledger, outcomes)
+        };
+
+        let (ledger, outcomes) = process(SentinelIds::Reject);
+        assert_eq!(outcomes, vec![
+            Ok(()),
+            Err(LedgerError::SentinelId { transaction: 2, client: 0 }),
+            Err(LedgerError::SentinelId { transaction: 0, client: 1 }),
+            Err(LedgerError::SentinelId { transaction: 0, client: 1 }),
+        ]);
+        assert_eq!(ledger.sentinel_rows, 3);
+        assert!(ledger.get_account(0).is_none());
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10));
+        assert!(!ledger.clients_seen.contains(0));
+
+        let (ledger, outcomes) = process(SentinelIds::Ignore);
+        assert_eq!(outcomes, vec![Ok(()); 4]);
+        assert_eq!(ledger.sentinel_rows, 3);
+        assert!(ledger.get_account(0).is_none());
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10));
+        assert!(!ledger.clients_seen.contains(0));
+
+        // tx 0 is an ordinary id that a later dispute can reference
+        let (ledger, outcomes) = process(SentinelIds::Allow);
+        assert_eq!(outcomes, vec![Ok(()); 4]);
+        assert_eq!(ledger.sentinel_rows, 3);
+        assert_eq!(ledger.get_account(0).unwrap().available, dec!(5));
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(3));
+    }
+
+    #[test]
+    fn withdrawal_dispute_test() {
+        let mut ledger = Ledger::default();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(100.0)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Withdrawal, 1, 2, Some(dec!(30.0)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Withdrawal, 1, 3, Some(dec!(60.0)))).unwrap();
+        let balances = |ledger: &Ledger| {
+            let account = ledger.get_account(1).unwrap();
+            (account.available, account.held, account.total, account.locked)
+        };
+        assert_eq!(balances(&ledger), (dec!(10), dec!(0), dec!(10), false));
+
+        // The refund of a withdrawal larger than the account now holds
+        ledger.process(&Transaction::new(TransactionType::Dispute, 1, 3, None)).unwrap();
+        assert_eq!(balances(&ledger), (dec!(10), dec!(60), dec!(70), false));
+        assert_eq!(ledger.recompute_check(), vec![]);
+        ledger.process(&Transaction::new(TransactionType::Resolve, 1, 3, None)).unwrap();
+        assert_eq!(balances(&ledger), (dec!(10), dec!(0), dec!(10), false));
+
+        ledger.process(&Transaction::new(TransactionType::Dispute, 1, 2, None)).unwrap();
+        assert_eq!(balances(&ledger), (dec!(10), dec!(30), dec!(40), false));
+        ledger.process(&Transaction::new(TransactionType::Chargeback, 1, 2, None)).unwrap();
+        assert_eq!(balances(&ledger), (dec!(40), dec!(0), dec!(40), true));
+        assert_eq!(ledger.recompute_check(), vec![]);
+    }
+
+    #[test]
+    fn refused_withdrawal_dispute_test() {
+        let mut ledger = Ledger::default();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(10)))).unwrap();
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Withdrawal, 1, 2, Some(dec!(500)))).unwrap_err().code(),
+            "INSUFFICIENT_FUNDS",
+        );
+
+        // The refused withdrawal took nothing out, so there is nothing to refund
+        let dispute = Transaction::new(TransactionType::Dispute, 1, 2, None);
+        assert_eq!(ledger.rule_trace(&dispute).last(), Some(&RuleCheck { rule: "tx_applied", passed: false }));
+        assert_eq!(ledger.process(&dispute), Err(LedgerError::NotApplied {
+            operation: TransactionType::Dispute,
+            transaction: 2,
+            client: 1,
+        }));
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Chargeback, 1, 2, None)).unwrap_err().code(),
+            "INVALID_DISPUTE_STATE",
+        );
+        let account = ledger.get_account(1).unwrap();
+        assert_eq!((account.available, account.held, account.total, account.locked), (dec!(10), dec!(0), dec!(10), false));
+        assert_eq!(ledger.recompute_check(), vec![]);
+    }
+
+    #[test]
+    fn locked_account_test() {
+        let mut ledger = Ledger::default();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(1.5)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 2, Some(dec!(10.0)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 3, Some(dec!(2.0)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Dispute, 1, 1, None)).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Dispute, 1, 3, None)).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Chargeback, 1, 1, None)).unwrap();
+        let locked = ledger.get_account(1).unwrap();
+        assert!(locked.locked);
+
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Deposit, 1, 4, Some(dec!(5.0)))),
+            Err(LedgerError::AccountLocked { operation: TransactionType::Deposit, transaction: 4, client: 1, amount: dec!(5.0) }),
+        );
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Withdrawal, 1, 5, Some(dec!(1.0)))),
+            Err(LedgerError::AccountLocked { operation: TransactionType::Withdrawal, transaction: 5, client: 1, amount: dec!(1.0) }),
+        );
+        assert_eq!(
+            ledger.process(&Transaction::new(TransactionType::Dispute, 1, 2, None)),
+            Err(LedgerError::AccountLocked { operation: TransactionType::Dispute, transaction: 2, client: 1, amount: dec!(10.0) }),
+        );
+        assert_eq!(ledger.get_account(1).unwrap(), locked);
+        assert_eq!(
+            LedgerError::AccountLocked { operation: TransactionType::Deposit, transaction: 4, client: 1, amount: dec!(5.0) }.to_string(),
+            "deposit 4 of 5.0 for client 1 is refused as the account is locked",
+        );
+
+        // The dispute opened before the lock can still be resolved
+        ledger.process(&Transaction::new(TransactionType::Resolve, 1, 3, None)).unwrap();
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(12));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(0));
+        assert_eq!(ledger.recompute_check(), vec![]);
+    }
+
+    #[test]
+    fn retention_min_amount_test() {
+        let mut ledger = Ledger::with_retention(RetentionPolicy {
+            min_amount: Some(dec!(1.0)),
+            clients: None,
+        });
+        let mut transaction_deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(dec!(1.0)),
+        };
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        transaction_deposit.transaction_id = 2;
+        transaction_deposit.amount = Some(dec!(0.9999));
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        assert!(ledger.transactions_by_id.contains_key(&1));
+        assert!(!ledger.transactions_by_id.contains_key(&2));
+        assert!(ledger.unretained_ids.contains(&2));
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.9999));
+
+        let mut dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 2,
+            amount: None,
+        };
+
+        assert_eq!(ledger.dispute(&dispute), Err(LedgerError::NotRetained { operation: TransactionType::Dispute, transaction: 2 }));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(0));
+
+        dispute.transaction_id = 1;
+
+        ledger.dispute(&dispute).unwrap();
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(0.9999));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(1.0));
+    }
+
+    #[test]
+    fn retention_clients_test() {
+        let mut ledger = Ledger::with_retention(RetentionPolicy {
+            min_amount: None,
+            clients: Some(HashSet::from([2])),
+        });
+        let mut transaction_deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(dec!(5.0)),
+        };
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        transaction_deposit.client_id = 2;
+        transaction_deposit.transaction_id = 2;
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        transaction_deposit.transaction_id = 3;
+        transaction_deposit.amount = Some(dec!(1.0));
+
+        ledger.deposit(&transaction_deposit).unwrap();
+
+        assert!(ledger.unretained_ids.contains(&1));
+        assert!(ledger.transactions_by_id.contains_key(&2));
+        assert!(ledger.transactions_by_id.contains_key(&3));
+
+        let unwatched = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+        };
+
+        assert_eq!(ledger.dispute(&unwatched), Err(LedgerError::NotRetained { operation: TransactionType::Dispute, transaction: 1 }));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(0));
+
+        let watched = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 2,
+            transaction_id: 2,
+            amount: None,
+        };
+
+        ledger.dispute(&watched).unwrap();
+        assert_eq!(ledger.get_account(2).unwrap().held, dec!(5.0));
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(5.0));
+    }
+
+    #[test]
+    fn normalize_test() {
+        let mut ledger = Ledger::default();
+        let mut transaction = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 0,
+            amount: None,
+        };
+
+        for i in 0..1000 {
+            transaction.transaction_type = TransactionType::Deposit;
+            transaction.transaction_id = 2 * i;
+            transaction.amount = Some(dec!(2.50000000));
+            ledger.process(&transaction).unwrap();
+
+            transaction.transaction_type = TransactionType::Withdrawal;
+            transaction.transaction_id = 2 * i + 1;
+            transaction.amount = Some(dec!(1.2500000000));
+            ledger.process(&transaction).unwrap();
+        }
+
+        let dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 0,
+            amount: None,
+        };
+        ledger.process(&dispute).unwrap();
+
+        let account = ledger.get_account(1).unwrap();
+        assert_eq!(account.available, dec!(1247.5));
+        assert_eq!(account.held, dec!(2.5));
+        assert_eq!(account.total, dec!(1250));
+        assert!(account.available.scale() <= 4);
+        assert!(account.held.scale() <= 4);
+        assert!(account.total.scale() <= 4);
+    }
+
+    #[test]
+    fn rounding_test() {
+        let mut ledger = Ledger::default();
+        // Ties go to the even digit: 0.00005 rounds down to 0 and is rejected,
+        // reported with the amount as read
+        for transaction_id in 1..=3 {
+            assert_eq!(
+                ledger.process(&Transaction::new(TransactionType::Deposit, 1, transaction_id, Some(dec!(0.00005)))),
+                Err(LedgerError::InvalidAmount { transaction: transaction_id, client: 1, amount: dec!(0.00005) }),
+            );
+        }
+        let err = ledger.process(&Transaction::new(TransactionType::Withdrawal, 1, 8, Some(dec!(0.00005)))).unwrap_err();
+        assert!(err.to_string().contains("invalid amount 0.00005,"), "{}", err);
+        assert!(ledger.get_account(1).is_none());
+
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 4, Some(dec!(0.00015)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 5, Some(dec!(0.00025)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 6, Some(dec!(0.000051)))).unwrap();
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(0.0005));
+
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 7, Some(dec!(1.123456789)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Dispute, 1, 7, None)).unwrap();
+        let account = ledger.get_account(1).unwrap();
+        assert_eq!(account.available, dec!(0.0005));
+        assert_eq!(account.held, dec!(1.1235));
+        assert_eq!(account.total, dec!(1.124));
+
+        let mut output = Vec::new();
+        write_accounts(&ledger, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n1,0.0005,1.1235,1.124,false\n");
+    }
+
+    #[test]
+    fn clients_seen_test() {
+        let mut ledger = Ledger::default();
+        let rows = [
+            (TransactionType::Deposit, 9, 1, Some(dec!(1.0))),
+            (TransactionType::Withdrawal, 7, 2, Some(dec!(1.0))),
+            (TransactionType::Dispute, 65535, 42, None),
+            (TransactionType::Deposit, 9, 3, Some(dec!(2.0))),
+            (TransactionType::Deposit, 0, 4, Some(dec!(2.0))),
+            (TransactionType::Withdrawal, 7, 5, Some(dec!(1.0))),
+        ];
+
+        for (transaction_type, client_id, transaction_id, amount) in rows {
+            let _ = ledger.process(&Transaction {
+                transaction_type,
+                client_id,
+                transaction_id,
+                amount,
+            });
+        }
+
+        assert_eq!(ledger.clients_seen.iter().collect::<Vec<u16>>(), vec![0, 7, 9, 65535]);
+        assert!(ledger.get_account(7).is_none());
+        assert!(ledger.get_account(65535).is_none());
+    }
+
+    #[test]
+    fn recompute_check_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        for r in reader.deserialize::<Transaction>() {
+            let _ = ledger.process(&r.unwrap());
+        }
+
+        assert_eq!(ledger.recompute_check(), vec![]);
+
+        ledger.account_by_id.get_mut(&3).unwrap().available += dec!(0.5);
+        ledger.account_by_id.get_mut(&3).unwrap().total += dec!(0.5);
+
+        assert_eq!(ledger.recompute_check(), vec![BalanceMismatch {
+            client_id: 3,
+            available_delta: dec!(0.5),
+            held_delta: dec!(0),
+            total_delta: dec!(0.5),
+        }]);
+    }
+
+    #[test]
+    fn budget_rows_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+
+        let progress = process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget {
+            max_rows: Some(8),
+            max_duration: None,
+            max_memory: None,
+            cancel: None,
+            max_field_bytes: None,
+            max_record_bytes: None,
+            strict: false,
+        }).unwrap();
+
+        assert_eq!(progress, RunProgress { rows: 8, last_line: 9, partial: true, memory_exceeded: false, cancelled: false, malformed: None });
+        assert_eq!(ledger.get_account(3).unwrap().held, dec!(3.5));
+        assert!(ledger.get_account(4).is_none());
+
+        let progress = process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        assert_eq!(progress, RunProgress { rows: 10, last_line: 19, partial: false, memory_exceeded: false, cancelled: false, malformed: None });
+
+        let mut full_ledger = Ledger::default();
+        let mut full_reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut full_ledger, &mut full_reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        for client_id in 1..=5 {
+            let account = ledger.get_account(client_id).unwrap();
+            let full_account = full_ledger.get_account(client_id).unwrap();
+            assert_eq!(account.available, full_account.available);
+            assert_eq!(account.held, full_account.held);
+            assert_eq!(account.total, full_account.total);
+            assert_eq!(account.locked, full_account.locked);
+        }
+    }
+
+    // Fails every read, as a disk or network input gone mid-run
+    struct BrokenInput;
+
+    impl Read for BrokenInput {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("input gone"))
+        }
+    }
+
+    #[test]
+    fn read_error_test() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.5\n".as_bytes().chain(BrokenInput);
+        let mut ledger = Ledger::default();
+        let err = process_records(&mut ledger, &mut Reader::from_reader(input), &InputMapping::default(), &Budget::default()).unwrap_err();
+        assert_eq!(err.to_string(), "input gone");
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.5));
+        // Before the header is read too
+        let mut reader = Reader::from_reader(BrokenInput);
+        let err = process_records(&mut Ledger::default(), &mut reader, &InputMapping::default(), &Budget::default()).unwrap_err();
+        assert_eq!(err.to_string(), "input gone");
+
+        let input = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.5}\n".as_bytes().chain(BrokenInput);
+        let mut ledger = Ledger::default();
+        let err = process_json_lines(&mut ledger, &mut BufReader::new(input), &InputMapping::default(), &Budget::default()).unwrap_err();
+        assert_eq!(err.to_string(), "input gone");
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.5));
+    }
+
+    // Hands out one chunk per read, asking for it first so the test knows
+    // the rows of the previous chunk have all been processed
+    struct ChunkReader {
+        requests: std::sync::mpsc::Sender<()>,
+        chunks: std::sync::mpsc::Receiver<Vec<u8>>,
+        pending: VecDeque<u8>,
+    }
+
+    impl Read for ChunkReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pending.is_empty() {
+                let _ = self.requests.send(());
+                match self.chunks.recv() {
+                    Ok(chunk) => self.pending.extend(chunk),
+                    Err(_) => return Ok(0),
+                }
+            }
+            self.pending.read(buf)
+        }
+    }
+
+    #[test]
+    fn budget_cancel_test() {
+        let input = std::fs::read_to_string("data/transactions_complete.csv").unwrap();
+        let lines: Vec<&str> = input.lines().collect();
+        let (request_sender, requests) = std::sync::mpsc::channel();
+        let (chunk_sender, chunks) = std::sync::mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let budget = Budget { cancel: Some(cancel.clone()), ..Budget::default() };
+
+        let worker = std::thread::spawn(move || {
+            let mut ledger = Ledger::default();
+            let mut reader = Reader::from_reader(ChunkReader { requests: request_sender, chunks, pending: VecDeque::new() });
+            let progress = process_records(&mut ledger, &mut reader, &InputMapping::default(), &budget).unwrap();
+            (ledger, reader, progress)
+        });
+
+        // Header and first 8 rows, then cancel while the worker waits for more
+        requests.recv().unwrap();
+        chunk_sender.send(format!("{}\n", lines[..9].join("\n")).into_bytes()).unwrap();
+        requests.recv().unwrap();
+        cancel.store(true, Ordering::Relaxed);
+        chunk_sender.send(format!("{}\n", lines[9..].join("\n")).into_bytes()).unwrap();
+        drop(chunk_sender);
+
+        let (mut ledger, mut reader, progress) = worker.join().unwrap();
+        // The row already read when the flag was seen is applied in full
+        assert_eq!(progress, RunProgress { rows: 9, last_line: 10, partial: true, memory_exceeded: false, cancelled: true, malformed: None });
+        assert_eq!(ledger.get_account(4).unwrap().total, dec!(13.5));
+        assert!(ledger.recompute_check().is_empty());
+
+        let progress = process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        assert_eq!(progress, RunProgress { rows: 9, last_line: 19, partial: false, memory_exceeded: false, cancelled: false, malformed: None });
+
+        let mut full_ledger = Ledger::default();
+        let mut full_reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut full_ledger, &mut full_reader, &InputMapping::default(), &Budget::default()).unwrap();
+        for client_id in 1..=5 {
+            let account = ledger.get_account(client_id).unwrap();
+            let full_account = full_ledger.get_account(client_id).unwrap();
+            assert_eq!(account.available, full_account.available);
+            assert_eq!(account.held, full_account.held);
+            assert_eq!(account.total, full_account.total);
+            assert_eq!(account.locked, full_account.locked);
+        }
+    }
+
+    #[test]
+    fn row_limits_test() {
+        let mut input = b"type,client,tx,amount\ndeposit,1,1,10.0\n".to_vec();
+        input.extend(format!("deposit,1,2,1.0{}\n", " ".repeat(2000)).as_bytes());
+        input.extend(format!("deposit,1,3,1.0{}\n", ",x".repeat(10_000)).as_bytes());
+        input.extend(b"dep\xffosit,1,4,1.0\ndeposit,1,5,2.0\n");
+        input.extend(format!("deposit,1,6,{}", "1".repeat(100_000)).as_bytes());
+        let budget = Budget {
+            max_field_bytes: Some(1024),
+            max_record_bytes: Some(8192),
+            ..Default::default()
+        };
+
+        let mut limited = Vec::new();
+        RecordLimit::new(&input[..], 8192).read_to_end(&mut limited).unwrap();
+        assert_eq!(limited.len(), input.len() - (20_000 + 15 - 8193) - (100_012 - 8193));
+
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_reader(RecordLimit::new(&input[..], 8192));
+        let progress = process_records(&mut ledger, &mut reader, &InputMapping::default(), &budget).unwrap();
+        assert_eq!((progress.rows, progress.last_line), (6, 7));
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(12));
+
+        let mut ledger = Ledger::default();
+        let mapping = InputMapping { lossy_utf8: true, ..Default::default() };
+        let mut reader = Reader::from_reader(RecordLimit::new(&input[..], 8192));
+        process_records(&mut ledger, &mut reader, &mapping, &budget).unwrap();
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(12));
+        assert_eq!(ledger.activity_report()[0].tx_count, 3);
+
+        let line = |position: u64| {
+            let mut record = ByteRecord::from(vec!["deposit", "1", "7", "1.0"]);
+            record.set_position(Some(csv::Position::new().set_line(position).clone()));
+            record
+        };
+        assert_eq!(decode_row(&line(2), 9000, &InputMapping::default(), &budget), Err(RowError::OverlongRecord { line: 2, max_bytes: 8192 }));
+        assert_eq!(decode_row(&line(2), 8193, &InputMapping::default(), &budget).unwrap().get(0), Some("deposit"));
+        let mut invalid = ByteRecord::from(vec![&b"dep\xffosit"[..], b"1", b"7", b"1.0"]);
+        invalid.set_position(Some(csv::Position::new().set_line(3).clone()));
+        assert_eq!(
+            decode_row(&invalid, 17, &InputMapping::default(), &budget).unwrap_err().to_string(),
+            "[INVALID_UTF8] field 1 of line 3 is not valid UTF-8",
+        );
+    }
+
+    #[test]
+    fn budget_exact_rows_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+
+        let progress = process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget {
+            max_rows: Some(18),
+            max_duration: None,
+            max_memory: None,
+            cancel: None,
+            max_field_bytes: None,
+            max_record_bytes: None,
+            strict: false,
+        }).unwrap();
+        assert_eq!(progress, RunProgress { rows: 18, last_line: 19, partial: true, memory_exceeded: false, cancelled: false, malformed: None });
+
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+
+        let progress = process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget {
+            max_rows: Some(19),
+            max_duration: None,
+            max_memory: None,
+            cancel: None,
+            max_field_bytes: None,
+            max_record_bytes: None,
+            strict: false,
+        }).unwrap();
+        assert_eq!(progress, RunProgress { rows: 18, last_line: 19, partial: false, memory_exceeded: false, cancelled: false, malformed: None });
+    }
+
+    #[test]
+    fn dispute_graph_test() {
+        // Transitions the fixture goes through, observed on the retained transactions
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        let mut exercised = Vec::new();
+        for transaction in reader.deserialize::<Transaction>() {
+            let transaction = transaction.unwrap();
+            let state = |ledger: &Ledger| ledger.transactions_by_id.get(&transaction.transaction_id).map(|stored| stored.state);
+            let before = state(&ledger);
+            let _ = ledger.process(&transaction);
+            if let (Some(from), Some(to)) = (before, state(&ledger)) {
+                if from != to && !exercised.contains(&(from, transaction.transaction_type.clone(), to)) {
+                    exercised.push((from, transaction.transaction_type.clone(), to));
+                }
+            }
+        }
+        assert_eq!(exercised, DISPUTE_TRANSITIONS.to_vec());
+
+        let dot = dispute_graph(GraphFormat::Dot);
+        assert_eq!(dot, "digraph dispute {
+    normal -> disputed [label=\"dispute\"];
+    disputed -> normal [label=\"resolve\"];
+    disputed -> charged_back [label=\"chargeback\"];
+}
+");
+        let mermaid = dispute_graph(GraphFormat::Mermaid);
+        assert_eq!(mermaid.lines().filter(|line| line.contains("-->")).count(), DISPUTE_TRANSITIONS.len());
+        assert!(mermaid.contains("    disputed --> charged_back: chargeback\n"));
+
+        // No transition leaves charged_back
+        assert_eq!(DisputeState::ChargedBack.after(&TransactionType::Dispute), None);
+    }
+
+    #[test]
+    fn memory_report_test() {
+        let allocated_before = ALLOCATED.with(Cell::get);
+        let mut ledger = Box::new(Ledger::default());
+        for transaction_id in 1..=20_000 {
+            let client_id = (transaction_id % 500) as u16;
+            ledger.process(&Transaction {
+                transaction_type: TransactionType::Deposit,
+                client_id,
+                transaction_id,
+                amount: Some(dec!(1.5)),
+            }).unwrap();
+        }
+        for transaction_id in 1..=100 {
+            ledger.process(&Transaction {
+                transaction_type: TransactionType::Dispute,
+                client_id: (transaction_id % 500) as u16,
+                transaction_id,
+                amount: None,
+            }).unwrap();
+        }
+        let measured = (ALLOCATED.with(Cell::get) - allocated_before) as usize;
+
+        let report = ledger.memory_report();
+        assert_eq!((report.accounts, report.retained_transactions, report.open_disputes), (500, 20_000, 100));
+        assert_eq!(report.total_bytes(), ledger.approximate_memory());
+        // Spare map capacity is the difference
+        assert!(report.total_bytes() <= measured && measured <= 2 * report.total_bytes());
+    }
+
+    #[test]
+    fn stats_test() {
+        let input = std::fs::read_to_string("data/transactions_complete.csv").unwrap() + "bonus,1,19,1\ndeposit,1,20,1.00000000000000000000001\n";
+        let mut ledger = Ledger::new();
+        let budget = Budget {
+            max_field_bytes: Some(16),
+            ..Budget::default()
+        };
+        process_records(&mut ledger, &mut Reader::from_reader(input.as_bytes()), &InputMapping::default(), &budget).unwrap();
+
+        let stats = ledger.stats();
+        let read: Vec<(&str, u64)> = stats.read_by_type.iter().map(|(name, count)| (name.as_str(), *count)).collect();
+        assert_eq!(read, [("bonus", 1), ("chargeback", 2), ("deposit", 9), ("dispute", 3), ("resolve", 2), ("withdrawal", 2)]);
+        assert_eq!(stats.applied, 15);
+        let rejected: Vec<(&str, u64)> = stats.rejected_by_reason.iter().map(|(reason, count)| (reason.as_str(), *count)).collect();
+        assert_eq!(rejected, [("INSUFFICIENT_FUNDS", 1), ("INVALID_DISPUTE_STATE", 2), ("OVERLONG_FIELD", 1), ("UNKNOWN_TYPE", 1)]);
+        assert_eq!(stats.rejected(), 5);
+        assert_eq!((stats.accounts, stats.locked_accounts, stats.total_held), (5, 1, dec!(3.5)));
+        assert_eq!(
+            stats.to_string(),
+            "Read: 1 bonus, 2 chargeback, 9 deposit, 3 dispute, 2 resolve, 2 withdrawal\n\
+             Applied: 15, rejected: 5 (INSUFFICIENT_FUNDS 1, INVALID_DISPUTE_STATE 2, OVERLONG_FIELD 1, UNKNOWN_TYPE 1)\n\
+             Accounts: 5, locked: 1, total held: 3.5",
+        );
+    }
+
+    #[test]
+    fn progress_test() {
+        let input = std::fs::read("data/transactions_complete.csv").unwrap();
+        let mut ledger = Ledger {
+            progress: Some(Progress::new(Some(2 * input.len() as u64), false)),
+            ..Ledger::default()
+        };
+        let counted = ledger.progress.as_ref().unwrap().counting(input.as_slice());
+        process_records(&mut ledger, &mut Reader::from_reader(counted), &InputMapping::default(), &Budget::default()).unwrap();
+
+        let progress = ledger.progress.as_ref().unwrap();
+        assert_eq!(progress.rows, 18);
+        assert_eq!(progress.bytes_read.load(Ordering::Relaxed), input.len() as u64);
+        // Half the input in 3s, the other half takes as long
+        assert_eq!(
+            progress.status(Duration::from_secs(3)),
+            "Progress: 18 rows, 0.0 MB of 0.0 MB (50.0%), 6 rows/s, ETA 0:00:03",
+        );
+
+        let unknown_size = Progress::new(None, false);
+        assert_eq!(unknown_size.status(Duration::from_secs(1)), "Progress: 0 rows, 0.0 MB, 0 rows/s");
+    }
+
+    #[test]
+    fn stored_transaction_test() {
+        assert!(size_of::<StoredTx>() * 2 <= size_of::<Transaction>());
+
+        let mut ledger = Ledger::default();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(10.0)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Withdrawal, 1, 2, Some(dec!(4.0)))).unwrap();
+        let _ = ledger.process(&Transaction::new(TransactionType::Withdrawal, 1, 3, Some(dec!(40.0))));
+        ledger.process(&Transaction::new(TransactionType::Dispute, 1, 2, None)).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Chargeback, 1, 2, None)).unwrap();
+        assert_eq!(ledger.transactions_by_id[&1], StoredTx {
+            client_id: 1,
+            amount: dec!(10.0),
+            kind: StoredKind::Deposit,
+            state: DisputeState::Normal,
+            applied: true,
+        });
+        assert_eq!(ledger.transactions_by_id[&2], StoredTx {
+            client_id: 1,
+            amount: dec!(4.0),
+            kind: StoredKind::Withdrawal,
+            state: DisputeState::ChargedBack,
+            applied: true,
+        });
+        assert!(!ledger.transactions_by_id[&3].applied);
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10));
+        assert_eq!(ledger.recompute_check(), vec![]);
+    }
+
+    #[test]
+    fn qa_sample_test() {
+        let mut ledger = Ledger::default();
+        let mut transaction_id = 0;
+
+        // Client n sends n * n deposits, client 10 only an overdrawn withdrawal
+        for client_id in 1..=9u16 {
+            for _ in 0..client_id * client_id {
+                transaction_id += 1;
+                ledger.process(&Transaction {
+                    transaction_type: TransactionType::Deposit,
+                    client_id,
+                    transaction_id,
+                    amount: Some(dec!(1.0)),
+                }).unwrap();
+            }
+        }
+        assert_eq!(ledger.process(&Transaction {
+            transaction_type: TransactionType::Withdrawal,
+            client_id: 10,
+            transaction_id: transaction_id + 1,
+            amount: Some(dec!(1.0)),
+        }), Err(LedgerError::UnknownAccount(10)));
+
+        let sample = ledger.qa_sample(4, 42);
+        assert_eq!(sample, ledger.qa_sample(4, 42));
+        assert_eq!(
+            sample.iter().map(|(stratum, _)| *stratum).collect::<Vec<Stratum>>(),
+            vec![Stratum::Heavy, Stratum::Medium, Stratum::Light, Stratum::RejectedOnly],
+        );
+        assert!((7..=9).contains(&sample[0].1));
+        assert!((4..=6).contains(&sample[1].1));
+        assert!((1..=3).contains(&sample[2].1));
+        assert_eq!(sample[3].1, 10);
+
+        let everyone = ledger.qa_sample(100, 7);
+        assert_eq!(everyone.len(), 10);
+        assert_eq!(everyone.iter().filter(|(stratum, _)| *stratum == Stratum::Heavy).count(), 3);
+    }
+
+    #[test]
+    fn read_histories_test() {
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        let histories = read_histories(&mut reader, &InputMapping::default(), &Budget::default(), 8, &HashSet::from([3, 4])).unwrap();
+
+        let history: Vec<u32> = histories[&3].iter().map(|transaction| transaction.transaction_id).collect();
+        assert_eq!(history, vec![6, 7, 6]);
+        assert_eq!(histories[&3][2].transaction_type, TransactionType::Dispute);
+        assert!(!histories.contains_key(&4));
+
+        // The rows a run skipped are skipped again, and counted the same
+        let mut input = b"type,client,tx,amount\ndeposit,3,1,1.0\ndeposit,3,2\n".to_vec();
+        input.extend_from_slice(b"deposit,3,3,1.0,extra\ndeposit,3,4,\xff\ndeposit,three,5,1.0\n");
+        input.extend_from_slice(format!("deposit,3,6,{}\ndeposit,3,7,2.0\ndeposit,3,8,3.0\n", "1".repeat(64)).as_bytes());
+        let budget = Budget { max_record_bytes: Some(32), ..Budget::default() };
+        let mut ledger = Ledger::default();
+        let progress = process_records(&mut ledger, &mut Reader::from_reader(&input[..]), &InputMapping::default(), &budget).unwrap();
+        assert_eq!(progress.rows, 8);
+        let mut reader = Reader::from_reader(&input[..]);
+        let histories = read_histories(&mut reader, &InputMapping::default(), &budget, progress.rows - 1, &HashSet::from([3])).unwrap();
+        let history: Vec<u32> = histories[&3].iter().map(|transaction| transaction.transaction_id).collect();
+        assert_eq!(history, vec![1, 7]);
+    }
+
+    #[test]
+    fn activity_report_test() {
+        let mut ledger = Ledger::default();
+        let mut transaction_id = 0;
+
+        // Client n sends n deposits, client 100 also sends a withdrawal and a dispute
+        for client_id in 1..=100 {
+            for _ in 0..client_id {
+                transaction_id += 1;
+                ledger.process(&Transaction {
+                    transaction_type: TransactionType::Deposit,
+                    client_id,
+                    transaction_id,
+                    amount: Some(dec!(2.5)),
+                }).unwrap();
+            }
+        }
+        ledger.process(&Transaction {
+            transaction_type: TransactionType::Withdrawal,
+            client_id: 100,
+            transaction_id: transaction_id + 1,
+            amount: Some(dec!(1.0)),
+        }).unwrap();
+        assert_eq!(ledger.process(&Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 100,
+            transaction_id: 1,
+            amount: None,
+        }), Err(LedgerError::ClientMismatch { operation: TransactionType::Dispute, transaction: 1, client: 100, owner: 1 }));
+
+        let activities = ledger.activity_report();
+        assert_eq!(activities.len(), 100);
+        assert_eq!(*activities[0], Activity {
+            client_id: 100,
+            tx_count: 102,
+            deposit_sum: dec!(250.0),
+            withdrawal_sum: dec!(1.0),
+            dispute_count: 1,
+        });
+        assert_eq!(activities[1].client_id, 99);
+        assert_eq!(activities[99].client_id, 1);
+        assert!(activities.windows(2).all(|pair| pair[0].tx_count >= pair[1].tx_count));
+
+        let mut counts: Vec<u64> = activities.iter().map(|activity| activity.tx_count).collect();
+        counts.sort_unstable();
+        assert_eq!(percentile(&counts, 50), 50);
+        assert_eq!(percentile(&counts, 99), 99);
+        assert_eq!(percentile(&counts, 100), 102);
+        assert_eq!(percentile(&[], 50), 0);
+        assert_eq!(percentile(&[7], 1), 7);
+    }
+
+    #[test]
+    fn memory_budget_test() {
+        let empty_memory = Ledger::default().approximate_memory();
+        let budget = Budget {
+            max_memory: Some(empty_memory + 3 * Ledger::MAX_ROW_MEMORY),
+            ..Default::default()
+        };
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+
+        // The first two deposits each open an account, the third one only
+        // retains a transaction, leaving less than a full row of headroom
+        let progress = process_records(&mut ledger, &mut reader, &InputMapping::default(), &budget).unwrap();
+        assert_eq!(progress, RunProgress { rows: 3, last_line: 4, partial: false, memory_exceeded: true, cancelled: false, malformed: None });
+        assert!(ledger.approximate_memory() <= budget.max_memory.unwrap());
+        assert_eq!(ledger.transactions_by_id.len(), 3);
+
+        let progress = process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        assert!(!progress.memory_exceeded);
+        assert_eq!(ledger.get_account(5).unwrap().available, dec!(1110.0));
+        assert!(ledger.get_account(5).unwrap().locked);
+    }
+
+    #[test]
+    fn account_order_test() {
+        let mut ledger = Ledger::new();
+        for (transaction_id, client_id) in [907, 3, 65535, 12, 1, 400, 77, 2, 31000, 5, 1024, 90].into_iter().enumerate() {
+            ledger.process(&Transaction::new(TransactionType::Deposit, client_id, transaction_id as u32 + 1, Some(dec!(1)))).unwrap();
+        }
+        let mut output = Vec::new();
+        write_accounts(&ledger, &mut output).unwrap();
+
+        let clients: Vec<u16> = String::from_utf8(output).unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(clients.len(), 12);
+        assert!(clients.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", clients);
+    }
+
+    #[test]
+    fn checksum_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        let mut again_ledger = Ledger::default();
+        let mut again_reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut again_ledger, &mut again_reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        let checksum = accounts_checksum(&ledger);
+        assert_eq!(checksum.len(), 64);
+        assert_eq!(checksum, accounts_checksum(&again_ledger));
+
+        // Same accounts reached through a different insertion order
+        let mut reordered_ledger = Ledger::default();
+        for account in ledger.sorted_accounts().into_iter().rev() {
+            reordered_ledger.account_by_id.insert(account.client_id, account.clone());
+        }
+        assert_eq!(checksum, accounts_checksum(&reordered_ledger));
+
+        reordered_ledger.account_by_id.get_mut(&1).unwrap().locked = true;
+        assert_ne!(checksum, accounts_checksum(&reordered_ledger));
+    }
+
+    #[derive(Debug)]
+    struct BonusHandler;
+
+    impl TransactionHandler for BonusHandler {
+        fn validate(&self, ledger: &Ledger, transaction: &Transaction) -> Result<(), String> {
+            match ledger.account_by_id.get(&transaction.client_id) {
+                Some(account) if !account.locked => Ok(()),
+                Some(_) => Err("account is locked".to_string()),
+                None => Err("bonuses only go to existing accounts".to_string()),
+            }
+        }
+
+        fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+            let account = ledger.account_mut(transaction.client_id);
+            account.available += transaction.amount.unwrap();
+            account.total += transaction.amount.unwrap();
+            account.round();
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct PoisonHandler;
+
+    impl TransactionHandler for PoisonHandler {
+        fn apply(&self, _ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+            panic!("poison transaction {}", transaction.transaction_id);
+        }
+    }
+
+    #[test]
+    fn crash_artifacts_test() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for transaction_id in 1..=10 {
+            input.push_str(&format!("deposit,{},{},1.5\n", transaction_id % 3, transaction_id));
+        }
+        input.push_str("poison,1,11,\ndeposit,1,12,1.0\n");
+
+        let mut ledger = Ledger::default();
+        ledger.register_handler(TransactionType::Custom("poison".to_string()), PoisonHandler);
+        let mut reader = Reader::from_reader(input.as_bytes());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap()
+        }));
+        assert!(result.is_err());
+        assert_eq!(ledger.rows_processed, 11);
+        assert_eq!(ledger.recent_rows.len(), RECENT_ROWS);
+
+        let directory = std::env::temp_dir().join(format!("pieuvre-crash-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        write_crash_artifacts(&ledger, directory.to_str().unwrap(), "poison transaction 11").unwrap();
+
+        let report = std::fs::read_to_string(directory.join("crash-report.json")).unwrap();
+        assert!(report.contains("\"panic\": \"poison transaction 11\""));
+        assert!(report.contains("\"rows_processed\": 11"));
+        assert!(report.contains("{\"line\": 5, \"fields\": [\"deposit\", \"1\", \"4\", \"1.5\"]}"));
+        assert!(!report.contains("\"line\": 4,"));
+        assert!(report.contains("{\"line\": 12, \"fields\": [\"poison\", \"1\", \"11\", \"\"]}\n  ]"));
+        assert_eq!(json_string("a \"b\"\\\n\t"), "\"a \\\"b\\\"\\\\\\n\\u0009\"");
+
+        let mut snapshot = Reader::from_path(directory.join("crash-accounts.csv")).unwrap();
+        let accounts: Vec<Account> = snapshot.deserialize().collect::<Result<_, _>>().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(accounts, ledger.sorted_accounts().into_iter().cloned().collect::<Vec<Account>>());
+        assert_eq!(accounts[1].total, dec!(6.0));
+    }
+
+    #[test]
+    fn custom_handler_test() {
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+bonus,1,2,2.5
+bonus,2,3,2.5
+deposit,3,4,1.0
+deposit,3,8,2.0
+dispute,3,4,
+chargeback,3,4,
+bonus,3,5,2.5
+refund,1,6,1.0
+withdrawal,1,7,12.5
+";
+        let mut ledger = Ledger::default();
+        ledger.register_handler(TransactionType::from_name("bonus"), BonusHandler);
+        let mut reader = Reader::from_reader(input.as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(0));
+        assert_eq!(ledger.get_account(1).unwrap().total, dec!(0));
+        assert!(ledger.get_account(2).is_none());
+        assert_eq!(ledger.get_account(3).unwrap().total, dec!(2.0));
+        assert!(ledger.get_account(3).unwrap().locked);
+
+        let mut builtin_ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut builtin_ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        assert_eq!(builtin_ledger.get_account(3).unwrap().held, dec!(3.5));
+        assert_eq!(builtin_ledger.get_account(4).unwrap().available, dec!(123.5));
+        assert!(builtin_ledger.get_account(5).unwrap().locked);
+    }
+
+    #[test]
+    fn transaction_type_name_test() {
+        for name in ["deposit", "withdrawal", "dispute", "resolve", "chargeback", "bonus"] {
+            assert_eq!(TransactionType::from_name(name).name(), name);
+        }
+        assert_eq!(TransactionType::from_name("bonus"), TransactionType::Custom("bonus".to_string()));
+    }
+
+    #[test]
+    fn validate_headers_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        assert_eq!(validate_headers(reader.headers().unwrap()), Ok(()));
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        let mut output = Vec::new();
+        write_accounts(&ledger, &mut output).unwrap();
+        let mut output_reader = Reader::from_reader(output.as_slice());
+        let err = validate_headers(output_reader.headers().unwrap()).unwrap_err();
+        assert_eq!(err, HeaderError::AccountsOutput);
+        assert!(err.to_string().contains("looks like accounts output of pieuvre"));
+        assert!(err.to_string().contains("type,client,tx,amount"));
+
+        let headers = StringRecord::from(vec!["type", " client", "amount"]);
+        assert_eq!(validate_headers(&headers), Err(HeaderError::MissingColumns(vec!["tx".to_string()])));
+    }
+
+    #[test]
+    fn sniff_input_test() {
+        let mut input = std::fs::read("data/transactions_complete.csv").unwrap();
+        assert_eq!(sniff_input(&input), Ok(()));
+        assert_eq!(sniff_input(b""), Ok(()));
+        input.extend(b"deposit,1,12,1.0\0\n");
+        assert_eq!(sniff_input(&input), Ok(()));
+
+        assert_eq!(sniff_input(&[0x1f, 0x8b, 0x08, 0x00]), Err(InputFormatError::Compressed("gzip", "gunzip")));
+        assert_eq!(sniff_input(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]), Err(InputFormatError::Compressed("zstd", "unzstd")));
+        assert_eq!(sniff_input(b"PAR1\x15\x04"), Err(InputFormatError::Columnar("Parquet")));
+        assert_eq!(sniff_input(b"Obj\x01\x04\x14avro"), Err(InputFormatError::Columnar("Avro")));
+        assert_eq!(sniff_input(b"\xff\xfet\0y\0p\0e\0"), Err(InputFormatError::Utf16));
+        assert_eq!(sniff_input(b"\xfe\xff\0t\0y\0p\0e"), Err(InputFormatError::Utf16));
+        assert_eq!(sniff_input(b"\x7fELF\x02\x01\x01\0\0\0\0\0"), Err(InputFormatError::Binary));
+
+        let err = InputFormatError::Compressed("gzip", "gunzip");
+        assert!(err.to_string().starts_with("[COMPRESSED_INPUT]"));
+        assert!(err.to_string().contains("gunzip"));
+        assert!(InputFormatError::Binary.to_string().contains("not a text CSV"));
+    }
+
+    #[test]
+    fn no_headers_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        let mut headerless_ledger = Ledger::default();
+        let mut headerless_reader = csv::ReaderBuilder::new().has_headers(false).from_path("data/transactions_headerless.csv").unwrap();
+        let progress = process_records(&mut headerless_ledger, &mut headerless_reader, &InputMapping::default(), &Budget::default()).unwrap();
+        assert_eq!(progress.rows, 18);
+        assert_eq!(progress.last_line, 18);
+        // The first row is a deposit, not a header
+        assert_eq!(headerless_ledger.get_account(1).unwrap().available, dec!(1.5));
+        // The dispute row has an empty amount
+        assert_eq!(headerless_ledger.get_account(3).unwrap().held, dec!(3.5));
+        assert_eq!(accounts_checksum(&headerless_ledger), accounts_checksum(&ledger));
+    }
+
+    #[test]
+    fn serve_connection_test() {
+        let ledger = std::sync::Mutex::new(Ledger::default());
+        let mut replies = Vec::new();
+        let input = "deposit,1,1,2.5\r\n\ndeposit, 1, 2, 1\ndispute,1,1\nresolve,1,1,\ndispute,1,9\nrefund\nACCOUNTS\n";
+        serve_connection(&ledger, input.as_bytes(), &mut replies).unwrap();
+        assert_eq!(String::from_utf8(replies).unwrap(), concat!(
+            "OK\n",
+            "OK\n",
+            "OK\n",
+            "OK\n",
+            "ERROR [UNKNOWN_TRANSACTION] Can't find transaction id 9 to dispute\n",
+            "ERROR [UNPARSABLE_ROW] CSV deserialize error: record 0 (line: 1, byte: 0): expected field, but got end of row\n",
+            "client,available,held,total,locked\n1,3.5,0,3.5,false\n\n",
+        ));
+        assert_eq!(ledger.lock().unwrap().get_account(1).unwrap().available, dec!(3.5));
+    }
+
+    #[test]
+    fn http_api_test() {
+        let ledger = std::sync::Mutex::new(Ledger::default());
+        let post = |body: &str| route_http(&ledger, "POST", "/transactions", body);
+        assert_eq!(post(r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#), (200, r#"{"outcome": "applied"}"#.to_string()));
+        assert_eq!(post(r#"{"type":"deposit","client":1,"tx":2,"amount":2.5}"#).0, 200);
+        let (status, body) = post(r#"{"type":"withdrawal","client":1,"tx":3,"amount":"100"}"#);
+        assert_eq!(status, 422);
+        assert!(body.starts_with(r#"{"error": "INSUFFICIENT_FUNDS", "message": "#), "{}", body);
+        assert_eq!(post(r#"{"type":"dispute","client":1,"tx":9}"#).0, 422);
+        assert_eq!(post(r#"{"type":"deposit","client":"one"}"#).0, 400);
+        assert_eq!(post(r#"{"type":"dispute","client":1,"tx":2}"#).0, 200);
+        assert_eq!(post(r#"{"type":"chargeback","client":1,"tx":2,"amount":null}"#).0, 200);
+        let (status, body) = post(r#"{"type":"deposit","client":1,"tx":4,"amount":"1"}"#);
+        assert_eq!(status, 422);
+        assert!(body.starts_with(r#"{"error": "ACCOUNT_LOCKED""#), "{}", body);
+
+        assert_eq!(
+            route_http(&ledger, "GET", "/accounts/1", ""),
+            (200, r#"{"client": 1, "available": "10", "held": "0", "total": "10", "locked": true}"#.to_string()),
+        );
+        assert_eq!(route_http(&ledger, "GET", "/accounts/2", "").0, 404);
+        assert_eq!(route_http(&ledger, "GET", "/accounts/two", "").0, 404);
+        assert_eq!(route_http(&ledger, "DELETE", "/accounts/1", "").0, 405);
+        assert_eq!(route_http(&ledger, "GET", "/transactions/1", "").0, 404);
+
+        let request = concat!(
+            "GET /accounts HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "POST /transactions HTTP/1.1\r\nContent-Length: 47\r\nConnection: close\r\n\r\n",
+            r#"{"type":"deposit","client":2,"tx":5,"amount":1}"#,
+        );
+        let mut response = Vec::new();
+        serve_http_connection(&ledger, request.as_bytes(), &mut response).unwrap();
+        let accounts = "[\n  {\"client\": 1, \"available\": \"10\", \"held\": \"0\", \"total\": \"10\", \"locked\": true}\n]\n";
+        assert_eq!(String::from_utf8(response).unwrap(), format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}{}",
+            accounts.len(),
+            accounts,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 22\r\n\r\n{\"outcome\": \"applied\"}",
+        ));
+        assert_eq!(ledger.lock().unwrap().get_account(2).unwrap().available, dec!(1));
+
+        // Oversized requests are refused before their body or the rest of
+        // their line is buffered, and the connection closed
+        let mut response = Vec::new();
+        let request = "POST /transactions HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\nGET /accounts HTTP/1.1\r\n\r\n";
+        serve_http_connection(&ledger, request.as_bytes(), &mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+        assert_eq!(response.matches("HTTP/1.1").count(), 1);
+        let mut response = Vec::new();
+        let request = format!("GET /accounts HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(200_000));
+        serve_http_connection(&ledger, request.as_bytes(), &mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{}", response);
+
+        // A panic while the ledger is locked doesn't take the server down
+        std::thread::scope(|scope| {
+            let panicked = scope.spawn(|| {
+                let _ledger = ledger.lock().unwrap();
+                panic!("poisoning the ledger");
+            });
+            assert!(panicked.join().is_err());
+        });
+        assert!(ledger.is_poisoned());
+        assert_eq!(route_http(&ledger, "GET", "/accounts/2", "").0, 200);
+        assert_eq!(route_http(&ledger, "POST", "/transactions", r#"{"type":"deposit","client":2,"tx":6,"amount":1}"#).0, 200);
+    }
+
+    #[test]
+    fn state_test() {
+        let complete = std::fs::read_to_string("data/transactions_complete.csv").unwrap();
+        let lines: Vec<&str> = complete.lines().collect();
+        // The dispute of the second day references a deposit of the first
+        let day1 = format!("{}\n{}\n", lines[0], lines[1..8].join("\n"));
+        let day2 = format!("{}\n{}\n", lines[0], lines[8..].join("\n"));
+        assert!(lines[8].starts_with("dispute,3,6"));
+
+        let mut ledger = Ledger::default();
+        process_records(&mut ledger, &mut Reader::from_reader(complete.as_bytes()), &InputMapping::default(), &Budget::default()).unwrap();
+
+        let mut first_day = Ledger::default();
+        process_records(&mut first_day, &mut Reader::from_reader(day1.as_bytes()), &InputMapping::default(), &Budget::default()).unwrap();
+        let mut state = Vec::new();
+        first_day.save_state(&mut state).unwrap();
+        let mut second_day = Ledger::default();
+        second_day.load_state(&state[..]).unwrap();
+        let mut saved_again = Vec::new();
+        second_day.save_state(&mut saved_again).unwrap();
+        assert_eq!(saved_again, state);
+        process_records(&mut second_day, &mut Reader::from_reader(day2.as_bytes()), &InputMapping::default(), &Budget::default()).unwrap();
+
+        assert_eq!(accounts_checksum(&second_day), accounts_checksum(&ledger));
+        assert_eq!(second_day.get_account(3).unwrap().held, dec!(3.5));
+        let (mut expected, mut restored) = (Vec::new(), Vec::new());
+        ledger.save_state(&mut expected).unwrap();
+        second_day.save_state(&mut restored).unwrap();
+        assert_eq!(String::from_utf8(restored).unwrap(), String::from_utf8(expected).unwrap());
+
+        let mut ledger = Ledger::default();
+        assert_eq!(ledger.load_state(&b"client,available,held,total,locked\n"[..]), Err("not a ledger state file".to_string()));
+        assert_eq!(
+            ledger.load_state(&b"pieuvre-state,2\naccount,1,1,0,1,false,false\n"[..]),
+            Err("state version 2 is not supported, expected 1".to_string()),
+        );
+        assert_eq!(
+            ledger.load_state(&b"pieuvre-state,1\naccount,1,1,0,1,false\n"[..]),
+            Err("line 2: missing field 7".to_string()),
+        );
+        assert_eq!(ledger.get_account(1), None);
+    }
+
+    // Runs the transactions through both the engine and the reference, which
+    // must agree on every row's outcome and on the final accounts. The rule
+    // trace of a row must pass entirely exactly when the row is applied.
+    fn assert_matches_reference(name: &str, transactions: &[Transaction]) {
+        let mut ledger = Ledger::new();
+        let mut reference = reference::ReferenceLedger::default();
+        for (row, transaction) in transactions.iter().enumerate() {
+            let traced = ledger.rule_trace(transaction).iter().all(|check| check.passed);
+            let outcome = ledger.process(transaction).map_err(|err| err.code());
+            assert_eq!(traced, outcome.is_ok(), "{} row {} trace: {:?}", name, row + 1, transaction);
+            assert_eq!(outcome, reference.process(transaction), "{} row {}: {:?}", name, row + 1, transaction);
+        }
+        let accounts: Vec<Account> = ledger.sorted_accounts().into_iter().cloned().collect();
+        assert_eq!(accounts, reference.accounts(), "{}", name);
+    }
+
+    #[test]
+    fn reference_test() {
+        for fixture in ["data/transactions.csv", "data/transactions_complete.csv", "data/transactions_mixed.csv"] {
+            let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(fixture).unwrap();
+            // Rows that don't parse never reach either
+            let transactions: Vec<Transaction> = reader.deserialize().filter_map(Result::ok).collect();
+            assert_matches_reference(fixture, &transactions);
+        }
+
+        // Random runs over few clients, ids and amounts, so that duplicates,
+        // mismatched clients and every dispute state come up often
+        let types = ["deposit", "deposit", "withdrawal", "withdrawal", "dispute", "resolve", "chargeback", "bonus"];
+        let amounts = [None, Some(dec!(0)), Some(dec!(-1)), Some(dec!(0.5)), Some(dec!(1)), Some(dec!(2.25)), Some(dec!(3.00005))];
+        let mut rng = SampleRng(0);
+        for run in 0..500 {
+            let transactions: Vec<Transaction> = (0..40)
+                .map(|_| Transaction::new(
+                    TransactionType::from_name(types[rng.next() as usize % types.len()]),
+                    1 + (rng.next() % 3) as u16,
+                    1 + (rng.next() % 12) as u32,
+                    amounts[rng.next() as usize % amounts.len()],
+                ))
+                .collect();
+            assert_matches_reference(&format!("run {}", run), &transactions);
+        }
+    }
+
+    #[test]
+    fn audit_test() {
+        let path = std::env::temp_dir().join(format!("pieuvre-audit-{}.csv", std::process::id()));
+        // The mixed rows but the one that doesn't parse, and a row too long
+        let mixed = std::fs::read_to_string("data/transactions_mixed.csv").unwrap()
+            .replace("deposit,x,7,1.0\n", "deposit,1,7,1.00000000000000000000001\n");
+        let mut ledger = Ledger {
+            sentinel_ids: SentinelIds::Ignore,
+            audit: Some(AuditLog::new(Box::new(File::create(&path).unwrap()))),
+            ..Ledger::default()
+        };
+        let input = format!("{}deposit,0,11,1\n", mixed);
+        let budget = Budget {
+            max_field_bytes: Some(16),
+            ..Budget::default()
+        };
+        let progress = process_records(&mut ledger, &mut Reader::from_reader(input.as_bytes()), &InputMapping::default(), &budget).unwrap();
+        ledger.audit.as_mut().unwrap().finish().unwrap();
+        drop(ledger);
+        let audit = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = audit.lines().collect();
+        assert_eq!(lines.len() as u64, progress.rows + 1);
+        assert_eq!(lines.len(), input.lines().count());
+        assert_eq!(lines, [
+            "row,line,type,client,tx,amount,outcome,reason,available,held",
+            "1,2,deposit,1,1,1.5,applied,,1.5,0",
+            "2,3,deposit,2,2,2,applied,,2,0",
+            "3,4,deposit,2,3,1,applied,,3,0",
+            "4,5,withdrawal,1,4,5,rejected,INSUFFICIENT_FUNDS,1.5,0",
+            "5,6,withdrawal,1,5,0.5,applied,,1,0",
+            "6,7,dispute,2,2,,applied,,1,2",
+            "7,8,dispute,2,2,,rejected,INVALID_DISPUTE_STATE,1,2",
+            "8,9,resolve,1,2,,ignored,CLIENT_MISMATCH,1,0",
+            "9,10,withdrawal,2,6,,rejected,MISSING_AMOUNT,1,2",
+            "10,11,,,,,rejected,OVERLONG_FIELD,,",
+            "11,12,dispute,2,99,,ignored,UNKNOWN_TRANSACTION,1,2",
+            "12,13,chargeback,2,2,,applied,,1,0",
+            "13,14, Deposit,1,8,3,rejected,UNKNOWN_TYPE,1,0",
+            "14,15,bonus,1,9,1,rejected,UNKNOWN_TYPE,1,0",
+            "15,16,deposit,1,10,3,applied,,4,0",
+            "16,17,deposit,0,11,1,ignored,SENTINEL_ID,,",
+        ]);
+    }
+
+    #[test]
+    fn client_exports_test() {
+        let directory = std::env::temp_dir().join(format!("pieuvre-exports-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let audit_path = directory.join(".audit.csv");
+        let mut ledger = Ledger {
+            audit: Some(AuditLog::new(Box::new(File::create(&audit_path).unwrap()))),
+            ..Ledger::default()
+        };
+        // Client 6 only has a dispute of another client's transaction, ignored
+        let input = std::fs::read_to_string("data/transactions_complete.csv").unwrap() + "dispute,6,1,\n";
+        process_records(&mut ledger, &mut Reader::from_reader(input.as_bytes()), &InputMapping::default(), &Budget::default()).unwrap();
+        ledger.audit.as_mut().unwrap().finish().unwrap();
+
+        let exported = write_client_exports(&ledger, File::open(&audit_path).unwrap(), &directory.to_string_lossy()).unwrap();
+        std::fs::remove_file(&audit_path).unwrap();
+        let mut files: Vec<String> = std::fs::read_dir(&directory).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        let client_2 = std::fs::read_to_string(directory.join("2.json")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(exported, 5);
+        assert_eq!(files, ["1.json", "2.json", "3.json", "4.json", "5.json"]);
+        assert_eq!(client_2, "\
+{
+  \"client\": 2,
+  \"transactions\": [
+    {\"type\": \"deposit\", \"tx\": 2, \"amount\": \"2\", \"outcome\": \"applied\"},
+    {\"type\": \"withdrawal\", \"tx\": 5, \"amount\": \"3\", \"outcome\": \"rejected\", \"reason\": \"INSUFFICIENT_FUNDS\"}
+  ],
+  \"account\": {\"client\": 2, \"available\": \"2\", \"held\": \"0\", \"total\": \"2\", \"locked\": false}
+}
+");
+    }
+
+    #[test]
+    fn explain_test() {
+        let rules = |explanation: &Explanation| -> Vec<(&str, bool)> {
+            explanation.rules.iter().map(|check| (check.rule, check.passed)).collect()
+        };
+        let explain_line = |target| {
+            let mut ledger = Ledger::new();
+            let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+            explain(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default(), target).unwrap().unwrap()
+        };
+
+        let applied = explain_line(ExplainTarget::Transaction(4));
+        assert_eq!((applied.line, applied.outcome, applied.reason), (5, "applied", ""));
+        assert_eq!(applied.account_before.as_ref().unwrap().available, dec!(3));
+        assert_eq!(rules(&applied), [
+            ("sentinel_id", true), ("not_merged", true), ("known_type", true), ("handler_validate", true),
+            ("amount_present", true), ("amount_in_range", true), ("unique_tx", true), ("account_exists", true),
+            ("account_not_locked", true), ("not_under_review", true), ("sufficient_available", true),
+        ]);
+
+        let rejected = explain_line(ExplainTarget::Line(12));
+        assert_eq!((rejected.outcome, rejected.reason), ("rejected", "INVALID_DISPUTE_STATE"));
+        assert_eq!(rejected.fields[0], ("type".to_string(), "resolve".to_string()));
+        assert_eq!(rules(&rejected), [
+            ("sentinel_id", true), ("not_merged", true), ("known_type", true), ("handler_validate", true),
+            ("tx_known", true), ("tx_retained", true), ("same_client", true), ("account_exists", true),
+            ("dispute_state_allows", false), ("sufficient_held", false),
+        ]);
+        assert!(rejected.to_string().contains("  FAIL dispute_state_allows\n"));
+        assert!(rejected.to_json().contains("{\"rule\": \"dispute_state_allows\", \"passed\": false}"));
+
+        let missing = {
+            let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+            explain(&mut Ledger::new(), &mut reader, &InputMapping::default(), &Budget::default(), ExplainTarget::Line(100)).unwrap()
+        };
+        assert!(missing.is_none());
+    }
+
+    #[test]
+    fn simulate_fees_test() {
+        let mut reader = Reader::from_path("data/fee_schedule.csv").unwrap();
+        let schedule = read_fee_schedule(&mut reader).unwrap();
+        // Below the first deposit tier
+        assert_eq!(schedule.fee(&TransactionType::Deposit, dec!(999.9999)), None);
+        assert_eq!(schedule.fee(&TransactionType::Deposit, dec!(1000)), Some(dec!(2.5)));
+        assert_eq!(schedule.fee(&TransactionType::Withdrawal, dec!(99.99)), Some(dec!(0.5)));
+        assert_eq!(schedule.fee(&TransactionType::Withdrawal, dec!(100)), Some(dec!(1.25)));
+        assert_eq!(schedule.fee(&TransactionType::Withdrawal, dec!(0.0001)), Some(dec!(0)));
+        assert_eq!(schedule.fee(&TransactionType::Dispute, dec!(100)), None);
+
+        let tier = |transaction_type, from| FeeTier { transaction_type, from, percent: dec!(1), fixed: dec!(0) };
+        assert!(FeeSchedule::new(vec![tier(TransactionType::Dispute, dec!(0))]).is_err());
+        assert!(FeeSchedule::new(vec![tier(TransactionType::Deposit, dec!(-1))]).is_err());
+        assert!(FeeSchedule::new(vec![tier(TransactionType::Deposit, dec!(5)), tier(TransactionType::Deposit, dec!(5))]).is_err());
+
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        let mut simulated_ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        let fees = simulate_fees(&mut simulated_ledger, &mut reader, &InputMapping::default(), &Budget::default(), &schedule).unwrap();
+        assert_eq!(accounts_checksum(&simulated_ledger), accounts_checksum(&ledger));
+        // The withdrawal of client 2 was refused, so not charged
+        assert_eq!(fees, vec![
+            ClientFees { client: 1, transactions: 1, fees: dec!(0.0075) },
+            ClientFees { client: 5, transactions: 1, fees: dec!(2.5) },
+        ]);
+
+        let path = std::env::temp_dir().join(format!("pieuvre-fees-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_fee_report(path, &fees).unwrap();
+        let report = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(report, "client,transactions,fees\n1,1,0.0075\n5,1,2.5\ntotal,2,2.5075\n");
+    }
+
+    #[test]
+    fn input_mapping_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        let mapping = InputMapping {
+            columns: pairs(&[("type", "txn_type"), ("client", "customer"), ("tx", "reference"), ("amount", "value")]),
+            types: pairs(&[("CR", "deposit"), ("DR", "withdrawal"), ("DSP", "dispute"), ("RES", "resolve"), ("CHB", "chargeback")]),
+            lossy_utf8: false,
+            delimiter: b',',
+            no_headers: false,
+        };
+        let mut partner_ledger = Ledger::default();
+        let mut partner_reader = Reader::from_path("data/transactions_partner.csv").unwrap();
+        mapping.map_headers(&mut partner_reader).unwrap();
+        assert_eq!(mapping.validate_headers(partner_reader.headers().unwrap()), Ok(()));
+        let progress = process_records(&mut partner_ledger, &mut partner_reader, &mapping, &Budget::default()).unwrap();
+        assert_eq!(progress.last_line, 19);
+
+        let mut output = Vec::new();
+        write_accounts(&ledger, &mut output).unwrap();
+        let mut partner_output = Vec::new();
+        write_accounts(&partner_ledger, &mut partner_output).unwrap();
+        assert_eq!(partner_output, output);
+
+        let mut reader = Reader::from_reader("customer,txn_type,value\n".as_bytes());
+        mapping.map_headers(&mut reader).unwrap();
+        let err = mapping.validate_headers(reader.headers().unwrap()).unwrap_err();
+        assert_eq!(err, HeaderError::MissingColumns(vec!["tx (mapped to reference)".to_string()]));
+    }
+
+    #[test]
+    fn repair_output_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        let mut clean = Vec::new();
+        write_accounts(&ledger, &mut clean).unwrap();
+
+        let repair = |partial: &[u8]| {
+            let (output, report) = repair_output(&ledger, partial);
+            assert_eq!(output, clean);
+            (report.salvaged_rows, report.regenerated_rows)
+        };
+        assert_eq!(repair(&clean), (5, 0));
+        assert_eq!(repair(b""), (0, 5));
+        assert_eq!(repair(b"client,avail"), (0, 5));
+
+        let lines: Vec<&[u8]> = clean.split_inclusive(|byte| *byte == b'\n').collect();
+        // Mid-file, cut after the third row
+        assert_eq!(repair(&lines[..4].concat()), (3, 2));
+        // Mid-row, the fourth row lost its locked column
+        let cut = [lines[..4].concat(), lines[4][..lines[4].len() - 6].to_vec()].concat();
+        assert_eq!(repair(&cut), (3, 2));
+        // Only the last newline is missing, the row could still be cut
+        assert_eq!(repair(&clean[..clean.len() - 1]), (4, 1));
+        // A corrupted trailing row
+        let corrupted = [lines[..5].concat(), b"5,1110\0\0\0\n".to_vec()].concat();
+        assert_eq!(repair(&corrupted), (4, 1));
+    }
+
+    #[test]
+    fn annotate_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_mixed.csv").unwrap();
+        let mut output = Vec::new();
+        annotate(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), std::fs::read_to_string("data/transactions_mixed_annotated.csv").unwrap());
+        assert!(ledger.get_account(2).unwrap().locked);
+
+        // Rows the run would skip before parsing them are annotated with why,
+        // strict or not
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_malformed.csv").unwrap();
+        let budget = Budget { max_field_bytes: Some(16), max_record_bytes: Some(40), strict: true, ..Budget::default() };
+        let mut output = Vec::new();
+        annotate(&mut ledger, &mut reader, &InputMapping::default(), &budget, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), std::fs::read_to_string("data/transactions_malformed_annotated.csv").unwrap());
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(8));
+    }
+
+    #[test]
+    fn enrichment_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        let enrichment_file = "segment,client,country
+retail,1,FR
+corporate,3,DE
+retail,8,ES
+";
+        let columns = vec!["country".to_string(), "segment".to_string()];
+        let mut reader = Reader::from_reader(enrichment_file.as_bytes());
+        let enrichment = read_enrichment(&mut reader, &columns, false).unwrap();
+        assert_eq!(unseen_enriched_clients(&enrichment, &ledger.clients_seen), vec![8]);
+
+        let mut output = Vec::new();
+        write_extended_accounts(&ledger, false, Some(&enrichment), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked,country,segment
+1,1.5,0,1.5,false,FR,retail
+2,2,0,2,false,,
+3,10,3.5,13.5,false,DE,corporate
+4,123.5,0,123.5,false,,
+5,1110,0,1110,true,,
+");
+
+        let duplicated = format!("{}wholesale,3,NL\n", enrichment_file);
+        let mut reader = Reader::from_reader(duplicated.as_bytes());
+        assert_eq!(read_enrichment(&mut reader, &columns, false), Err("duplicate client 3 on line 5".to_string()));
+        let mut reader = Reader::from_reader(duplicated.as_bytes());
+        let enrichment = read_enrichment(&mut reader, &columns, true).unwrap();
+        assert_eq!(enrichment.values_by_id[&3], vec!["NL".to_string(), "wholesale".to_string()]);
+
+        let mut reader = Reader::from_reader(enrichment_file.as_bytes());
+        assert_eq!(read_enrichment(&mut reader, &["region".to_string()], false), Err("missing column region".to_string()));
+    }
+
+    #[test]
+    fn reconcile_test() {
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_path("data/transactions_complete.csv").unwrap();
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        let matching = "client,available,held,total,locked
+1,1.5,0,1.5,false
+2,2,0,2,false
+3,10,3.5,13.5,false
+4,123.5,0,123.5,false
+5,1110,0,1110,true
+";
+        let mut external = Reader::from_reader(matching.as_bytes());
+        assert_eq!(reconcile(&ledger, &mut external, &HashMap::new(), dec!(0)), Ok(vec![]));
+
+        let drifting = "cust_id,balance
+1,1.50005
+2,2
+3,13.49995
+4,123.5
+5,1110
+";
+        let column_map = pairs(&[("client", "cust_id"), ("total", "balance")]);
+        let mut external = Reader::from_reader(drifting.as_bytes());
+        assert_eq!(reconcile(&ledger, &mut external, &column_map, dec!(0.0001)), Ok(vec![]));
+
+        let mismatching = "cust_id,balance
+1,1.5
+3,13.0
+5,1110
+6,7
+";
+        let mut external = Reader::from_reader(mismatching.as_bytes());
+        assert_eq!(reconcile(&ledger, &mut external, &column_map, dec!(0.0001)), Ok(vec![
+            ReconcileDelta { client_id: 2, field: "total", computed: dec!(2), external: dec!(0), delta: dec!(2) },
+            ReconcileDelta { client_id: 3, field: "total", computed: dec!(13.5), external: dec!(13.0), delta: dec!(0.5) },
+            ReconcileDelta { client_id: 4, field: "total", computed: dec!(123.5), external: dec!(0), delta: dec!(123.5) },
+            ReconcileDelta { client_id: 6, field: "total", computed: dec!(0), external: dec!(7), delta: dec!(-7) },
+        ]));
+
+        let mut external = Reader::from_reader("id,balance\n1,1.5\n".as_bytes());
+        assert!(reconcile(&ledger, &mut external, &column_map, dec!(0)).is_err());
+    }
+
+    #[test]
+    fn consolidate_test() {
+        let read = |output: &str| read_accounts(&mut Reader::from_reader(output.as_bytes())).unwrap();
+        let units = vec![
+            ("bu1".to_string(), read("client,available,held,total,locked
+1,10,0,10,false
+2,5.5,1,6.5,true
+3,1,0,1,false
+")),
+            ("bu2".to_string(), read("client,available,held,total,locked
+2,4.5,0,4.5,false
+4,100,20,120,false
+")),
+            ("bu3".to_string(), read("client,available,held,total,locked
+1,0,0,0,true
+2,0.25,0,0.25,false
+")),
+            ("bu4".to_string(), read("client,available,held,total,locked
+7,3,0,3,false
+")),
+        ];
+        let (subtotals, cross_units) = consolidate(&units);
+
+        let subtotal = |unit: &str, accounts, locked, available, held, total| UnitSubtotal {
+            unit: unit.to_string(),
+            accounts,
+            locked,
+            available,
+            held,
+            total,
+        };
+        assert_eq!(subtotals, vec![
+            subtotal("bu1", 3, 1, dec!(16.5), dec!(1), dec!(17.5)),
+            subtotal("bu2", 2, 0, dec!(104.5), dec!(20), dec!(124.5)),
+            subtotal("bu3", 2, 1, dec!(0.25), dec!(0), dec!(0.25)),
+            subtotal("bu4", 1, 0, dec!(3), dec!(0), dec!(3)),
+            subtotal("total", 8, 2, dec!(124.25), dec!(21), dec!(145.25)),
+        ]);
+        assert_eq!(cross_units, vec![
+            CrossUnitRow { client: 1, units: "bu1;bu3".to_string(), available: dec!(10), held: dec!(0), total: dec!(10), locked_units: 1 },
+            CrossUnitRow { client: 2, units: "bu1;bu2;bu3".to_string(), available: dec!(10.25), held: dec!(1), total: dec!(11.25), locked_units: 1 },
+        ]);
+
+        // Disjoint units have no client in common
+        let (subtotals, cross_units) = consolidate(&[units[1].clone(), units[3].clone()]);
+        assert_eq!(subtotals[2], subtotal("total", 3, 0, dec!(107.5), dec!(20), dec!(127.5)));
+        assert!(cross_units.is_empty());
+    }
+
+    #[test]
+    fn write_atomically_test() {
+        let path = std::env::temp_dir().join(format!("pieuvre-atomic-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let temporary = format!("{}/.pieuvre-atomic-{}.csv.tmp-{}", std::env::temp_dir().display(), std::process::id(), std::process::id());
+        let mut ledger = Ledger::default();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(1.5)))).unwrap();
+
+        // Absent when the first write fails partway
+        let failed: Result<(), csv::Error> = write_atomically(path, |file| {
+            file.write_all(b"client,available")?;
+            Err(std::io::Error::other("disk full").into())
+        });
+        assert!(failed.is_err());
+        assert!(!std::path::Path::new(path).exists());
+        assert!(!std::path::Path::new(&temporary).exists());
+
+        write_atomically(path, |file| write_accounts(&ledger, file)).unwrap();
+        let previous = std::fs::read_to_string(path).unwrap();
+        assert_eq!(previous, "client,available,held,total,locked\n1,1.5,0,1.5,false\n");
+
+        // Untouched when a later write fails partway
+        ledger.process(&Transaction::new(TransactionType::Deposit, 2, 2, Some(dec!(2.0)))).unwrap();
+        let failed: Result<(), csv::Error> = write_atomically(path, |file| {
+            write_accounts(&ledger, &mut *file)?;
+            Err(std::io::Error::other("disk full").into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), previous);
+        assert!(!std::path::Path::new(&temporary).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn staged_outputs_test() {
+        let target = std::env::temp_dir().join(format!("pieuvre-run-{}", std::process::id()));
+        let target_name = target.to_string_lossy().into_owned();
+        let mut ledger = Ledger::new();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(1.5)))).unwrap();
+
+        // A run failing after its first artifact publishes nothing
+        let staged = StagedOutputs::new(&target_name).unwrap();
+        let staging = staged.staging().to_path_buf();
+        write_accounts(&ledger, File::create(staged.path("accounts.csv")).unwrap()).unwrap();
+        drop(staged);
+        assert!(!target.exists());
+        assert!(staging.join("accounts.csv").exists());
+        assert!(!staging.join(SUCCESS_MARKER).exists());
+        std::fs::remove_dir_all(&staging).unwrap();
+
+        let staged = StagedOutputs::new(&target_name).unwrap();
+        write_accounts(&ledger, File::create(staged.path("accounts.csv")).unwrap()).unwrap();
+        std::fs::write(staged.path("stats.txt"), ledger.stats().to_string()).unwrap();
+        staged.publish().unwrap();
+        let mut published: Vec<String> = std::fs::read_dir(&target).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        published.sort();
+        assert_eq!(published, [SUCCESS_MARKER, "accounts.csv", "stats.txt"]);
+        assert_eq!(std::fs::read_to_string(target.join("accounts.csv")).unwrap(), "client,available,held,total,locked\n1,1.5,0,1.5,false\n");
+
+        // Published outputs are never replaced
+        assert_eq!(StagedOutputs::new(&target_name).unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn accounts_json_test() {
+        let mut ledger = Ledger::default();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(1.5)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 2, 2, Some(dec!(10.1234)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 2, 3, Some(dec!(3)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Dispute, 2, 3, None)).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Chargeback, 2, 3, None)).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 3, 4, Some(dec!(7)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Deposit, 3, 5, Some(dec!(0.5)))).unwrap();
+        ledger.process(&Transaction::new(TransactionType::Dispute, 3, 4, None)).unwrap();
+
+        let mut output = Vec::new();
+        write_accounts_json(&ledger, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let objects: Vec<&str> = output.trim().trim_start_matches('[').trim_end_matches(']')
+            .split('}')
+            .map(|object| object.trim().trim_start_matches(',').trim().trim_start_matches('{'))
+            .filter(|object| !object.is_empty())
+            .collect();
+        assert_eq!(objects.len(), 3);
+
+        for object in objects {
+            let fields: HashMap<&str, &str> = object.split(", ")
+                .map(|field| {
+                    let (key, value) = field.split_once(": ").unwrap();
+                    (key.trim_matches('"'), value)
+                })
+                .collect();
+            let decimal = |key: &str| {
+                let value = fields[key];
+                assert!(value.starts_with('"') && value.ends_with('"'));
+                value.trim_matches('"').parse::<Decimal>().unwrap()
+            };
+            let account = ledger.get_account(fields["client"].parse().unwrap()).unwrap();
+            assert_eq!(decimal("available"), account.available);
+            assert_eq!(decimal("held"), account.held);
+            assert_eq!(decimal("total"), account.total);
+            assert_eq!(fields["locked"].parse::<bool>().unwrap(), account.locked);
+        }
+
+        let mut output = Vec::new();
+        write_accounts_json(&Ledger::default(), &mut output).unwrap();
+        assert_eq!(output, b"[]\n");
+    }
+
+    #[test]
+    fn json_lines_test() {
+        let record = json_record(r#"{ "amount": "1.5", "tx": 3, "memo": "a \"b\" \u00e9", "client": 2, "type": "deposit" }"#).unwrap();
+        assert_eq!(record, StringRecord::from(vec!["deposit", "2", "3", "1.5"]));
+        let record = json_record(r#"{"type":"dispute","client":2,"tx":3,"amount":null}"#).unwrap();
+        assert_eq!(record, StringRecord::from(vec!["dispute", "2", "3", ""]));
+        assert!(json_record(r#"{"type":"deposit","client":2,"tx":3,"amount":1.5"#).is_err());
+        assert!(json_record(r#"{"type":"deposit","client":two}"#).is_err());
+        assert!(json_record(r#"["deposit",2,3,1.5]"#).is_err());
+        assert!(json_record(r#"{"type":"deposit"} {}"#).is_err());
+
+        // Numbers
+        assert_eq!(json_record(r#"{"amount":-0.5,"tx":10}"#).unwrap(), StringRecord::from(vec!["", "", "10", "-0.5"]));
+        assert_eq!(
+            json_record(r#"{"type":"deposit","client":1,"tx":1,"amount":1.5e2}"#),
+            Err("number 1.5e2 for key amount is not a plain decimal".to_string()),
+        );
+        for number in ["1E2", "01", "1.", ".5", "+1", "-", "0x10"] {
+            assert!(json_record(&format!(r#"{{"amount":{}}}"#, number)).is_err(), "{}", number);
+        }
+
+        // Escapes
+        let record = json_record(r#"{"type":"deposit 😀 \/ \\ \"\t"}"#).unwrap();
+        assert_eq!(&record[0], "deposit 😀 / \\ \"\t");
+        assert_eq!(&json_record(r#"{"type":"\ud83d\ude00 \u00e9"}"#).unwrap()[0], "😀 é");
+        assert!(json_record(r#"{"type":"\x"}"#).is_err());
+        assert!(json_record(r#"{"type":"\u00e"}"#).is_err());
+        assert!(json_record(r#"{"type":"\ud83d"}"#).is_err());
+        assert!(json_record(r#"{"type":"\ude00"}"#).is_err());
+        // What json_string writes reads back as it was
+        for value in ["a \"b\" \\ /", "\0\r\n\t\u{7f}\u{85}", "é 😀 \u{2028}"] {
+            assert_eq!(&json_record(&format!(r#"{{"type":{}}}"#, json_string(value))).unwrap()[0], value);
+        }
+
+        // Nesting, only under ignored keys
+        let record = json_record(r#"{"meta":{"tags":["a","}"],"n":{"m":[]}},"type":"deposit","client":2,"tx":3,"amount":"1"}"#).unwrap();
+        assert_eq!(record, StringRecord::from(vec!["deposit", "2", "3", "1"]));
+        assert!(json_record(r#"{"type":"deposit","amount":{"value":"1"}}"#).is_err());
+        assert!(json_record(r#"{"type":"deposit","amount":["1"]}"#).is_err());
+        assert!(json_record(r#"{"meta":{"tags":["a"}],"type":"deposit"}"#).is_err());
+        assert!(json_record(r#"{"meta":{"tags":["a"]"#).is_err());
+
+        // Keys and values
+        assert_eq!(json_record(r#"{"tx":1,"amount":"1","tx":2}"#), Err("duplicate key tx".to_string()));
+        assert_eq!(json_record(r#"{"memo":1,"memo":"2","tx":3}"#).unwrap(), StringRecord::from(vec!["", "", "3", ""]));
+        let headers = StringRecord::from(INPUT_COLUMNS.to_vec());
+        let record = json_record(r#"{"type":"deposit","client":"2","tx":"3","amount":"1.5"}"#).unwrap();
+        let transaction: Transaction = record.deserialize(Some(&headers)).unwrap();
+        assert_eq!((transaction.client_id, transaction.transaction_id, transaction.amount), (2, 3, Some(dec!(1.5))));
+        let record = json_record(r#"{"type":"deposit","client":null,"tx":3,"amount":null}"#).unwrap();
+        assert_eq!(record, StringRecord::from(vec!["deposit", "", "3", ""]));
+        assert!(record.deserialize::<Transaction>(Some(&headers)).is_err());
+        assert_eq!(&json_record(r#"{"type":"a\u0000b"}"#).unwrap()[0], "a\0b");
+        assert!(json_record("{\"type\":\"a\tb\"}").is_err());
+        assert!(json_record("\u{feff}{}").is_err());
+
+        let input = concat!(
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"2.5\"}\n",
+            "\n",
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":}\n",
+            "{\"type\":\"refund\",\"client\":1,\"tx\":3,\"amount\":\"1\"}\n",
+            "{\"type\":\"withdrawal\",\"client\":1,\"tx\":4,\"amount\":1}\n",
+        );
+        let mut ledger = Ledger { notices: Some(Vec::new()), ..Ledger::default() };
+        let progress = process_json_lines(&mut ledger, &mut input.as_bytes(), &InputMapping::default(), &Budget::default()).unwrap();
+        assert_eq!(progress.rows, 4);
+        assert_eq!(progress.last_line, 5);
+        assert!(!progress.partial);
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.5));
+        // Skipped rows are left to the caller to report
+        let notices: Vec<String> = ledger.notices.as_ref().unwrap().iter().map(Notice::to_string).collect();
+        assert_eq!(notices.len(), 2, "{:?}", notices);
+        assert!(notices[0].starts_with("[UNPARSABLE_JSON] line 3 ") && notices[0].ends_with(", skipped"), "{}", notices[0]);
+        assert!(notices[1].starts_with("[UNKNOWN_TYPE] line 4 ") && notices[1].ends_with(", skipped"), "{}", notices[1]);
+        let mut ledger = Ledger { notices: Some(Vec::new()), ..Ledger::default() };
+        let mut reader = Reader::from_reader("type,client,tx,amount\nwithdrawal,1,1,1.0\n".as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        assert_eq!(ledger.notices, Some(vec![Notice::Rejected(LedgerError::UnknownAccount(1))]));
+
+        // Only the input may start with a byte order mark
+        let input_with_bom = "\u{feff}{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1}\n\u{feff}{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":1}\n";
+        let mut ledger = Ledger::default();
+        let progress = process_json_lines(&mut ledger, &mut input_with_bom.as_bytes(), &InputMapping::default(), &Budget::default()).unwrap();
+        assert_eq!(progress.rows, 2);
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1));
+
+        let budget = Budget {
+            max_rows: Some(1),
+            ..Budget::default()
+        };
+        let mut ledger = Ledger::default();
+        let progress = process_json_lines(&mut ledger, &mut input.as_bytes(), &InputMapping::default(), &budget).unwrap();
+        assert_eq!(progress.rows, 1);
+        assert!(progress.partial);
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(2.5));
+
+        let input = b"{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"2\xff\"}\n{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":1}\n";
+        let mut ledger = Ledger::default();
+        let progress = process_json_lines(&mut ledger, &mut &input[..], &InputMapping::default(), &Budget::default()).unwrap();
+        assert_eq!((progress.rows, progress.malformed), (2, None));
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1));
+        let strict = Budget { strict: true, ..Budget::default() };
+        let mut ledger = Ledger::default();
+        let progress = process_json_lines(&mut ledger, &mut &input[..], &InputMapping::default(), &strict).unwrap();
+        assert_eq!(progress.malformed.as_deref(), Some("[INVALID_UTF8] line 1 is not valid UTF-8"));
+        assert!(ledger.get_account(1).is_none());
+
+        // Only the first bytes of the overlong line are read
+        let memo = "x".repeat(10_000);
+        let input = format!("{{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":2,\"memo\":\"{}\"}}\n{{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":1}}\n", memo);
+        let budget = Budget { max_record_bytes: Some(100), ..Budget::default() };
+        let mut ledger = Ledger::default();
+        let mut limited = BufReader::new(RecordLimit::new(input.as_bytes(), 100));
+        let progress = process_json_lines(&mut ledger, &mut limited, &InputMapping::default(), &budget).unwrap();
+        assert_eq!(progress.rows, 2);
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1));
+        assert_eq!(ledger.stats().rejected_by_reason.get("OVERLONG_RECORD"), Some(&1));
+    }
+
+    #[test]
+    fn append_accounts_test() {
+        let path = std::env::temp_dir().join(format!("pieuvre-append-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_reader("type,client,tx,amount\ndeposit,2,1,1.5\n".as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        append_accounts(&ledger, path, "2024-06-01").unwrap();
+
+        let mut reader = Reader::from_reader("type,client,tx,amount\ndeposit,1,2,2.0\n".as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        append_accounts(&ledger, path, "2024-06-02").unwrap();
+
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "client,available,held,total,locked,run_label
+2,1.5,0,1.5,false,2024-06-01
+1,2,0,2,false,2024-06-02
+2,1.5,0,1.5,false,2024-06-02
+");
+
+        std::fs::write(path, "client,available,held,total,locked\n").unwrap();
+        let err = append_accounts(&ledger, path, "2024-06-03").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "client,available,held,total,locked\n");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn near_duplicate_window_test() {
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,10.0
+deposit,1,3,10.0
+withdrawal,1,4,1.0
+deposit,1,5,4.0
+dispute,1,5,
+withdrawal,1,6,1.0
+deposit,1,7,3.0
+deposit,1,7,3.0
+deposit,1,8,10.0
+";
+        let mut ledger = Ledger {
+            near_duplicates: Some(NearDuplicateWindow::new(3, vec![NearDuplicateField::Client, NearDuplicateField::Type, NearDuplicateField::Amount])),
+            ..Default::default()
+        };
+        let mut reader = Reader::from_reader(input.as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        // tx 3 is two rows after tx 1 and tx 6 three rows after tx 4, but tx 8
+        // is nine rows after tx 1. Repeating tx 7 under the same id is not a
+        // near-duplicate but a duplicate id.
+        assert!(!ledger.transactions_by_id.contains_key(&3));
+        assert!(!ledger.transactions_by_id.contains_key(&6));
+        assert!(ledger.transactions_by_id.contains_key(&7));
+        assert!(ledger.transactions_by_id.contains_key(&8));
+        assert_eq!(ledger.get_account(1).unwrap().available, dec!(22.0));
+        assert_eq!(ledger.get_account(1).unwrap().held, dec!(4.0));
+        assert_eq!(ledger.get_account(2).unwrap().available, dec!(10.0));
+
+        let mut window = NearDuplicateWindow::new(100, vec![NearDuplicateField::Type, NearDuplicateField::Amount]);
+        let mut deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(dec!(10.0)),
+        };
+        assert_eq!(window.check(&deposit), None);
+        deposit.client_id = 2;
+        deposit.transaction_id = 2;
+        assert_eq!(window.check(&deposit), Some(1));
+    }
+
+    #[test]
+    fn holds_report_test() {
+        let input = "type,client,tx,amount
+deposit,9,1,100.0
+deposit,9,2,20.25
+deposit,9,3,0.0001
+deposit,7,4,20.0
+deposit,7,5,50.0
+deposit,7,7,1.0
+withdrawal,9,6,10.0
+dispute,9,2,
+dispute,9,6,
+dispute,9,2,
+dispute,7,5,
+dispute,9,3,
+dispute,7,4,
+resolve,7,5,
+chargeback,9,3,
+";
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_reader(input.as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        assert_eq!(ledger.holds_report(), vec![
+            HoldRow { client: 7, tx: 4, held: dec!(20.0), opened_row: 13, origin: TransactionType::Deposit },
+            HoldRow { client: 9, tx: 2, held: dec!(20.25), opened_row: 8, origin: TransactionType::Deposit },
+            HoldRow { client: 9, tx: 6, held: dec!(10.0), opened_row: 9, origin: TransactionType::Withdrawal },
+        ]);
+
+        for client_id in [7, 9] {
+            let held: Decimal = ledger.holds_report().iter()
+                .filter(|hold| hold.client == client_id)
+                .map(|hold| hold.held)
+                .sum();
+            assert_eq!(held, ledger.get_account(client_id).unwrap().held);
+        }
+        assert_eq!(ledger.recompute_check(), vec![]);
+    }
+
+    #[test]
+    fn projection_report_test() {
+        let input = "type,client,tx,amount
+deposit,9,1,100.0
+deposit,9,2,20.25
+deposit,9,3,5.0
+withdrawal,9,4,10.0
+deposit,7,5,50.0
+deposit,7,6,1.0
+deposit,7,9,2.0
+deposit,3,7,4.0
+dispute,9,2,
+dispute,9,4,
+dispute,9,3,
+resolve,9,3,
+dispute,7,5,
+dispute,7,6,
+chargeback,7,6,
+dispute,3,7,
+resolve,3,7,
+";
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_reader(input.as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        let accounts: Vec<Account> = ledger.sorted_accounts().into_iter().cloned().collect();
+
+        assert_eq!(ledger.projection_report(), vec![
+            ProjectionRow {
+                client: 7,
+                open_disputes: 1,
+                available: dec!(2),
+                held: dec!(50),
+                total: dec!(52),
+                locked: true,
+                chargeback_available: dec!(2),
+                chargeback_held: dec!(0),
+                chargeback_total: dec!(2),
+                chargeback_locked: true,
+                resolve_available: dec!(52),
+                resolve_held: dec!(0),
+                resolve_total: dec!(52),
+                resolve_locked: true,
+            },
+            ProjectionRow {
+                client: 9,
+                open_disputes: 2,
+                available: dec!(95),
+                held: dec!(30.25),
+                total: dec!(125.25),
+                locked: false,
+                chargeback_available: dec!(105),
+                chargeback_held: dec!(0),
+                chargeback_total: dec!(105),
+                chargeback_locked: true,
+                resolve_available: dec!(115.25),
+                resolve_held: dec!(0),
+                resolve_total: dec!(115.25),
+                resolve_locked: false,
+            },
+        ]);
+        let accounts_after: Vec<Account> = ledger.sorted_accounts().into_iter().cloned().collect();
+        assert_eq!(accounts, accounts_after);
+
+        // Applying the scenario gives the projected balances
+        for transaction_id in [2, 4] {
+            ledger.process(&Transaction::new(TransactionType::Chargeback, 9, transaction_id, None)).unwrap();
+        }
+        assert_eq!(ledger.get_account(9).unwrap().available, dec!(105));
+        assert_eq!(ledger.get_account(9).unwrap().total, dec!(105));
+    }
+
+    #[test]
+    fn merge_test() {
+        let input = "type,client,tx,amount
+deposit,7,1,100.0
+deposit,7,2,20.0
+deposit,9,3,50.0
+deposit,9,4,5.0
+dispute,7,2,
+dispute,9,4,
+merge,7,5,9
+";
+        let resume = "type,client,tx,amount
+resolve,9,2,
+chargeback,9,4,
+deposit,7,6,1.0
+";
+        let mut ledger = Ledger { notices: Some(Vec::new()), ..Ledger::default() };
+        let mut reader = Reader::from_reader(input.as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        assert_eq!(ledger.notices.as_deref(), Some(&[Notice::Merged { client: 7, transaction: 5, target: 9 }][..]));
+        assert!(ledger.get_account(7).is_none());
+        let account = ledger.get_account(9).unwrap();
+        assert_eq!((account.available, account.held, account.total, account.locked), (dec!(150), dec!(25), dec!(175), false));
+        assert_eq!(ledger.holds_report(), vec![
+            HoldRow { client: 9, tx: 2, held: dec!(20.0), opened_row: 5, origin: TransactionType::Deposit },
+            HoldRow { client: 9, tx: 4, held: dec!(5.0), opened_row: 6, origin: TransactionType::Deposit },
+        ]);
+        assert_eq!(ledger.recompute_check(), vec![]);
+
+        // Disputes opened on either side are settled on the merged account,
+        // rows of the merged client are rejected by default
+        let mut reader = Reader::from_reader(resume.as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        assert!(ledger.get_account(7).is_none());
+        let account = ledger.get_account(9).unwrap();
+        assert_eq!((account.available, account.held, account.total, account.locked), (dec!(170), dec!(0), dec!(170), true));
+        assert_eq!(ledger.holds_report(), vec![]);
+        assert_eq!(ledger.recompute_check(), vec![]);
+
+        let mut ledger = Ledger { merged_clients: MergedClients::Redirect, ..Ledger::default() };
+        let input = format!("{}dispute,7,1,\nmerge,9,7,3\ndeposit,7,8,1.0\nresolve,3,1,\n", input);
+        let mut reader = Reader::from_reader(input.as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        assert!(ledger.get_account(9).is_none());
+        let account = ledger.get_account(3).unwrap();
+        assert_eq!((account.available, account.held, account.total), (dec!(151), dec!(25), dec!(176)));
+        assert_eq!(ledger.recompute_check(), vec![]);
+    }
+
+    #[test]
+    fn ledger_error_test() {
+        use TransactionType::*;
+        let row = |transaction_type, client_id, transaction_id, amount| Transaction::new(transaction_type, client_id, transaction_id, amount);
+        let mut ledger = Ledger::with_retention(RetentionPolicy { min_amount: Some(dec!(1.0)), clients: None });
+        ledger.near_duplicates = Some(NearDuplicateWindow::new(2, vec![NearDuplicateField::Client, NearDuplicateField::Type, NearDuplicateField::Amount]));
+
+        assert_eq!(ledger.process(&row(Deposit, 1, 1, Some(dec!(10.0)))), Ok(()));
+        assert_eq!(ledger.process(&row(Deposit, 1, 2, Some(dec!(10.0)))), Err(LedgerError::NearDuplicate { transaction: 2, client: 1, earlier: 1 }));
+        assert_eq!(ledger.process(&row(Deposit, 1, 3, None)), Err(LedgerError::MissingAmount(3)));
+        assert_eq!(ledger.process(&row(Withdrawal, 1, 4, None)), Err(LedgerError::MissingAmount(4)));
+        assert_eq!(ledger.process(&row(Withdrawal, 2, 5, Some(dec!(1.0)))), Err(LedgerError::UnknownAccount(2)));
+        assert_eq!(
+            ledger.process(&row(Withdrawal, 1, 6, Some(dec!(20.0)))),
+            Err(LedgerError::InsufficientFunds { operation: Withdrawal, client: 1, requested: dec!(20.0), available: dec!(10.0) }),
+        );
+        assert_eq!(ledger.process(&row(Deposit, 1, 7, Some(dec!(0.5)))), Ok(()));
+        assert_eq!(ledger.process(&row(Dispute, 1, 7, None)), Err(LedgerError::NotRetained { operation: Dispute, transaction: 7 }));
+        assert_eq!(ledger.process(&row(Resolve, 1, 8, None)), Err(LedgerError::UnknownTransaction { operation: Resolve, transaction: 8 }));
+        assert_eq!(
+            ledger.process(&row(Dispute, 2, 1, None)),
+            Err(LedgerError::ClientMismatch { operation: Dispute, transaction: 1, client: 2, owner: 1 }),
+        );
+        assert_eq!(
+            ledger.process(&row(Chargeback, 1, 1, None)),
+            Err(LedgerError::InvalidDisputeState { operation: Chargeback, transaction: 1, client: 1, state: DisputeState::Normal }),
+        );
+        assert_eq!(ledger.process(&row(Deposit, 1, 9, Some(dec!(30.0)))), Ok(()));
+        assert_eq!(ledger.process(&row(Withdrawal, 1, 17, Some(dec!(20.0)))), Ok(()));
+        assert_eq!(
+            ledger.process(&row(Dispute, 1, 9, None)),
+            Err(LedgerError::InsufficientFunds { operation: Dispute, client: 1, requested: dec!(30.0), available: dec!(20.5) }),
+        );
+        assert_eq!(ledger.process(&row(Dispute, 1, 1, None)), Ok(()));
+        ledger.account_mut(1).held = dec!(5.0);
+        assert_eq!(
+            ledger.process(&row(Resolve, 1, 1, None)),
+            Err(LedgerError::InsufficientHeldFunds { operation: Resolve, client: 1, requested: dec!(10.0), held: dec!(5.0) }),
+        );
+
+        assert_eq!(ledger.process(&row(Review, 1, 10, None)), Ok(()));
+        assert_eq!(
+            ledger.process(&row(Withdrawal, 1, 11, Some(dec!(1.0)))),
+            Err(LedgerError::UnderReview { client: 1, requested: dec!(1.0) }),
+        );
+        assert_eq!(
+            ledger.process(&row(Review, 1, 12, None)),
+            Err(LedgerError::Invalid { transaction: 12, transaction_type: Review, client: 1, reason: "account is already under review".to_string() }),
+        );
+        let unknown = Custom("bonus".to_string());
+        assert_eq!(ledger.process(&row(unknown.clone(), 1, 13, None)), Err(LedgerError::UnknownType { transaction: 13, transaction_type: unknown }));
+
+        assert_eq!(ledger.process(&row(Deposit, 3, 14, Some(dec!(1.0)))), Ok(()));
+        assert_eq!(ledger.process(&row(Merge, 3, 15, Some(dec!(1)))), Ok(()));
+        assert_eq!(ledger.process(&row(Deposit, 3, 16, Some(dec!(2.0)))), Err(LedgerError::MergedClient { transaction: 16, client: 3, target: 1 }));
+
+        assert_eq!(
+            LedgerError::InsufficientFunds { operation: Withdrawal, client: 1, requested: dec!(20.0), available: dec!(10.0) }.to_string(),
+            "withdrawal of 20.0 for client 1 is impossible due to insufficient available funds (10.0)",
+        );
+        assert_eq!(
+            LedgerError::NotRetained { operation: Chargeback, transaction: 7 }.to_string(),
+            "Transaction id 7 was not retained by policy, can't chargeback it",
+        );
+    }
+
+    #[test]
+    fn review_hold_test() {
+        let input = "type,client,tx,amount
+deposit,1,1,100.0
+deposit,1,2,10.0
+review,1,3,
+review,1,4,
+withdrawal,1,5,20.0
+deposit,1,6,5.0
+dispute,1,2,
+resolve,1,2,
+clear_review,1,7,
+clear_review,1,8,
+withdrawal,1,9,20.0
+review,1,10,
+dispute,1,6,
+chargeback,1,6,
+review,1,11,
+clear_review,1,12,
+review,2,13,
+";
+        let mut ledger = Ledger { notices: Some(Vec::new()), ..Ledger::default() };
+        let mut reader = Reader::from_reader(input.as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        let notices: Vec<String> = ledger.notices.as_ref().unwrap().iter()
+            .filter(|notice| matches!(notice, Notice::ReviewHold { .. }))
+            .map(Notice::to_string)
+            .collect();
+        assert_eq!(notices, [
+            "Client 1 put under review by transaction 3",
+            "Client 1 cleared from review by transaction 7",
+            "Client 1 put under review by transaction 10",
+        ]);
+        // Only the withdrawal made between clear_review and review went through
+        let account = ledger.get_account(1).unwrap();
+        assert_eq!((account.available, account.held, account.total), (dec!(90), dec!(0), dec!(90)));
+        assert!(account.locked);
+        assert!(!account.review_hold);
+        assert!(!ledger.transactions_by_id[&5].applied);
+        assert!(ledger.transactions_by_id[&9].applied);
+        assert!(ledger.get_account(2).is_none());
+        assert_eq!(ledger.recompute_check(), vec![]);
+
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_reader(&input.as_bytes()[..input.find("withdrawal,1,5").unwrap()]);
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+        let mut output = Vec::new();
+        write_extended_accounts(&ledger, true, None, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked,review_hold\n1,110,0,110,false,true\n");
+    }
+
+    #[test]
+    fn merge_validate_test() {
+        let input = "type,client,tx,amount
+deposit,7,1,10.0
+deposit,9,2,10.0
+deposit,3,3,10.0
+merge,7,4,7
+merge,7,5,9.5
+merge,7,6,
+merge,8,7,9
+merge,9,8,3
+merge,7,9,9
+";
+        let mut ledger = Ledger::default();
+        let mut reader = Reader::from_reader(input.as_bytes());
+        process_records(&mut ledger, &mut reader, &InputMapping::default(), &Budget::default()).unwrap();
+
+        // Only 9 into 3 is applied, 7 can't be merged into 9 after it
+        assert_eq!(ledger.get_account(7).unwrap().total, dec!(10.0));
+        assert!(ledger.get_account(9).is_none());
+        assert_eq!(ledger.get_account(3).unwrap().total, dec!(20.0));
+        assert_eq!(ledger.merged_into, HashMap::from([(9, 3)]));
+    }
+}
@@ -1,406 +1,1163 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use pieuvre::*;
 use std::fs::File;
-use csv::{Reader, Writer};
-use serde::{Serialize, Deserialize};
+use csv::{Reader, ReaderBuilder, Terminator, Trim};
 use rust_decimal::Decimal;
-use rust_decimal_macros::dec;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Cursor, IsTerminal, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    file: String,
-}
+    #[clap(subcommand)]
+    command: Option<Command>,
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-#[serde(rename_all = "lowercase")]
-enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
-}
+    /// Transactions files, processed in this order against the same accounts, - for the standard input
+    #[clap(required = true)]
+    files: Vec<String>,
+
+    /// Only retain transactions of at least this amount for later disputes
+    #[clap(long)]
+    retain_min_amount: Option<Decimal>,
+
+    /// Only retain transactions of the client ids listed in this file (one per line)
+    #[clap(long)]
+    retain_clients_file: Option<String>,
+
+    /// Write the sorted list of every client id found in the input to this file
+    #[clap(long)]
+    clients_seen: Option<String>,
+
+    /// Write per-client transaction counts and volumes to this file, busiest clients first
+    #[clap(long)]
+    activity_report: Option<String>,
+
+    /// Recompute every balance from the retained transactions at the end of the run and report mismatches
+    #[clap(long, conflicts_with_all = &["retain-min-amount", "retain-clients-file"])]
+    recompute_check: bool,
+
+    /// Stop reading after this many rows and emit partial results
+    #[clap(long)]
+    stop_after_rows: Option<u64>,
+
+    /// Stop reading after this much time (e.g. 90s, 10m, 2h) and emit partial results
+    #[clap(long, value_parser = parse_duration)]
+    stop_after_duration: Option<Duration>,
+
+    /// Abort with exit code 3 before the ledger's approximate memory usage exceeds this many bytes
+    #[clap(long)]
+    memory_budget: Option<usize>,
+
+    /// Compare the computed accounts against this external balances file at the end of the run
+    #[clap(long, requires = "reconcile-report")]
+    reconcile: Option<String>,
+
+    /// Largest absolute difference tolerated by --reconcile
+    #[clap(long, default_value = "0")]
+    reconcile_tolerance: Decimal,
+
+    /// Column names of the external balances file, e.g. client=cust_id,total=balance
+    #[clap(long, value_parser = parse_column_map)]
+    reconcile_columns: Option<HashMap<String, String>>,
+
+    /// Write the per-client differences found by --reconcile to this file
+    #[clap(long, requires = "reconcile")]
+    reconcile_report: Option<String>,
+
+    /// Append the accounts, tagged with --run-label, to this file instead of printing them
+    #[clap(long, requires = "run-label")]
+    output_append: Option<String>,
+
+    /// Format of the accounts, csv or json
+    #[clap(long, default_value = "csv", value_parser = parse_output_format, conflicts_with_all = &["review-column", "enrich", "output-append"])]
+    format: OutputFormat,
+
+    /// Byte separating the fields of the accounts, \t for a tab
+    #[clap(long, value_parser = parse_delimiter, conflicts_with_all = &["review-column", "enrich", "output-append", "format"])]
+    output_delimiter: Option<u8>,
+
+    /// Write what became of every input row, with its client's balances after it, to this file
+    #[clap(long)]
+    audit: Option<String>,
+
+    /// Write <client>.json, with the client's transactions of the run and closing account, to this directory for every client with an applied or rejected transaction
+    #[clap(long)]
+    client_exports: Option<String>,
+
+    /// Start from the accounts and transactions saved by --save-state in an earlier run
+    #[clap(long)]
+    load_state: Option<String>,
+
+    /// Save the accounts and transactions to this file once the input is processed, for --load-state
+    #[clap(long)]
+    save_state: Option<String>,
+
+    /// Write the accounts to this file instead of printing them, replacing it only once complete
+    #[clap(long, conflicts_with = "output-append")]
+    output: Option<String>,
+
+    /// Write the accounts, audit log and stats to this directory, published at once with a _SUCCESS marker once all are written
+    #[clap(long, conflicts_with_all = &["output", "output-append", "audit", "dry-run"])]
+    output_dir: Option<String>,
+
+    /// Value of the run_label column written by --output-append
+    #[clap(long, requires = "output-append")]
+    run_label: Option<String>,
+
+    /// Skip deposits and withdrawals matching one of this many previous rows under another tx id
+    #[clap(long)]
+    near_dup_window: Option<u64>,
+
+    /// Write one row per open hold (client, tx, held amount, opening row, origin type) to this file
+    #[clap(long)]
+    holds_report: Option<String>,
+
+    /// Write the balances of every account with open disputes if they all ended in chargeback or resolve to this file
+    #[clap(long)]
+    projection_report: Option<String>,
+
+    /// Fields compared by --near-dup-window, among client, type and amount
+    #[clap(long, value_delimiter = ',', default_value = "client,type,amount", value_parser = parse_near_dup_field)]
+    near_dup_fields: Vec<NearDuplicateField>,
+
+    /// Format of the input files, csv or jsonl with one JSON transaction per line
+    #[clap(long, default_value = "csv", value_parser = parse_input_format, conflicts_with_all = &["column-map", "qa-sample"])]
+    input_format: InputFormat,
+
+    #[clap(flatten)]
+    input: InputOptions,
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Transaction {
-    #[serde(rename = "type")]
-    transaction_type: TransactionType,
+    /// Write a reproducible sample of this many accounts, with their transactions, to --qa-file
+    #[clap(long, requires = "qa-file")]
+    qa_sample: Option<usize>,
 
-    #[serde(rename = "client")]
-    client_id: u16,
+    /// Seed of the --qa-sample selection
+    #[clap(long, default_value = "0")]
+    qa_seed: u64,
 
-    #[serde(rename(deserialize = "tx"))]
-    transaction_id: u32,
+    /// File written by --qa-sample
+    #[clap(long, requires = "qa-sample")]
+    qa_file: Option<String>,
 
-    amount: Option<Decimal>,
+    /// Print the approximate memory used by the ledger, per store, on stderr
+    #[clap(long)]
+    memory_report: bool,
 
-    // bool::default is false
-    #[serde(default)]
-    disputed: bool,
+    /// Print the rows read by type, applied and rejected by reason, and the accounts, locked and held, on stderr
+    #[clap(long)]
+    stats: bool,
+
+    /// Report rows processed, bytes read of the input size, rows per second and time left on stderr
+    #[clap(long)]
+    progress: bool,
+
+    /// Print every row that would not be applied, with its line and reason, instead of the accounts
+    #[clap(long, conflicts_with_all = &["output", "output-append", "save-state", "format", "output-delimiter", "review-column", "enrich"])]
+    dry_run: bool,
+
+    /// Stop at the first row that isn't a transaction, with its line and record, instead of skipping it
+    #[clap(long)]
+    strict: bool,
+
+    /// Append the --enrich-columns of this file, keyed by its client column, to the accounts output
+    #[clap(long, requires = "enrich-columns", conflicts_with = "output-append")]
+    enrich: Option<String>,
+
+    /// Columns of the --enrich file appended to the accounts output, e.g. country,segment
+    #[clap(long, value_delimiter = ',', requires = "enrich")]
+    enrich_columns: Vec<String>,
+
+    /// Keep the last --enrich row of a client listed twice instead of refusing the file
+    #[clap(long, requires = "enrich")]
+    enrich_last_wins: bool,
+
+    /// Add a review_hold column to the accounts output
+    #[clap(long, conflicts_with = "output-append")]
+    review_column: bool,
+
+    /// On a crash while processing, write a crash report and an accounts snapshot to this directory
+    #[clap(long)]
+    crash_dir: Option<String>,
+
+    /// What to do with the rows of a client merged into another, redirect or reject
+    #[clap(long, default_value = "reject", value_parser = parse_merged_clients)]
+    merged_clients: MergedClients,
+
+    /// What to do with rows whose client or tx id is 0, reject, ignore or allow
+    #[clap(long, default_value = "allow", value_parser = parse_sentinel_ids)]
+    sentinel_ids: SentinelIds,
 }
 
-#[derive(Serialize, Debug, Clone)]
-struct Account {
-    #[serde(rename = "client")]
-    client_id: u16,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
+// How the transactions files are read, by the run and by the subcommands
+// replaying them alike
+#[derive(clap::Args)]
+struct InputOptions {
+    /// Byte separating the fields of the input files, \t for a tab
+    #[clap(long, default_value = ",", value_parser = parse_delimiter)]
+    delimiter: u8,
+
+    /// The input files have no header, their columns being type,client,tx,amount
+    #[clap(long, conflicts_with = "column-map")]
+    no_headers: bool,
+
+    /// Column names of the input file, e.g. type=txn_type,client=customer,tx=reference,amount=value
+    #[clap(long, value_parser = parse_input_column_map)]
+    column_map: Option<HashMap<String, String>>,
+
+    /// Transaction type names of the input file mapped onto ours, e.g. CR=deposit,DR=withdrawal
+    #[clap(long, value_parser = parse_type_map)]
+    type_map: Option<HashMap<String, String>>,
+
+    /// Skip rows with a field longer than this many bytes
+    #[clap(long, default_value = "1024")]
+    max_field_bytes: usize,
+
+    /// Skip rows with a line longer than this many bytes, only this much of a line is ever read
+    #[clap(long, default_value = "8192")]
+    max_record_bytes: usize,
+
+    /// Replace invalid UTF-8 in the input rather than skipping its rows
+    #[clap(long)]
+    lossy_utf8: bool,
 }
 
-impl Account {
-    fn new(client_id: u16) -> Account {
-        Account {
-            client_id: client_id,
-            available: dec!(0),
-            held: dec!(0),
-            total: dec!(0),
-            locked: false,
+impl InputOptions {
+    fn mapping(&self) -> InputMapping {
+        InputMapping {
+            columns: self.column_map.clone().unwrap_or_default(),
+            types: self.type_map.clone().unwrap_or_default(),
+            lossy_utf8: self.lossy_utf8,
+            delimiter: self.delimiter,
+            no_headers: self.no_headers,
+        }
+    }
+
+    // The byte limits, with no budget of rows, time or memory
+    fn budget(&self) -> Budget {
+        Budget {
+            max_field_bytes: Some(self.max_field_bytes),
+            max_record_bytes: Some(self.max_record_bytes),
+            ..Budget::default()
         }
     }
 }
 
-#[derive(Default, Debug)]
-struct Ledger {
-    transactions_by_id: HashMap<u32, Transaction>,
-    account_by_id: HashMap<u16, Account>,
+#[derive(Subcommand)]
+enum Command {
+    /// Print a canonical SHA-256 of the accounts a transactions file produces
+    Checksum {
+        file: String,
+        #[clap(flatten)]
+        input: InputOptions,
+    },
+    /// Print the dispute state machine as a DOT or Mermaid diagram
+    Graph {
+        #[clap(long, default_value = "dot", value_parser = parse_graph_format)]
+        format: GraphFormat,
+    },
+    /// Rebuild the accounts output of a run that died while writing it
+    RepairOutput {
+        /// Truncated accounts output
+        #[clap(long = "in")]
+        partial: String,
+        /// Transactions file the run was processing
+        #[clap(long)]
+        transactions: String,
+        /// Where to write the complete accounts output
+        #[clap(long)]
+        out: String,
+        #[clap(flatten)]
+        input: InputOptions,
+    },
+    /// Sum the accounts outputs of several business units, per unit and overall
+    Consolidate {
+        /// Accounts output of one unit, repeated for each unit
+        #[clap(long = "state", required = true)]
+        states: Vec<String>,
+        /// Labels of the units in the order of --state, their file names by default
+        #[clap(long = "label", value_delimiter = ',')]
+        labels: Vec<String>,
+        /// Where to write the subtotals and grand total
+        #[clap(long)]
+        out: String,
+        /// Where to write the clients found in several units with their summed balances
+        #[clap(long)]
+        cross_unit: Option<String>,
+    },
+    /// Copy a transactions file with the outcome of each row and the balances after it, malformed rows included
+    Annotate {
+        file: String,
+        /// Where to write the annotated copy
+        #[clap(long)]
+        out: String,
+        #[clap(flatten)]
+        input: InputOptions,
+    },
+    /// Replay a transactions file up to one row and explain how that row was decided
+    Explain {
+        file: String,
+        /// Line of the row to explain
+        #[clap(long, required_unless_present = "tx", conflicts_with = "tx")]
+        line: Option<u64>,
+        /// Tx id of the row to explain, its first row if several
+        #[clap(long)]
+        tx: Option<u32>,
+        #[clap(long, default_value = "text", value_parser = parse_explain_format)]
+        format: ExplainFormat,
+        #[clap(flatten)]
+        input: InputOptions,
+    },
+    /// Compute the fees a fee schedule would have charged, without charging them
+    SimulateFees {
+        /// Transactions file to simulate the fees over
+        #[clap(long = "in")]
+        file: String,
+        /// Fee tiers, one row type,from,percent,fixed each
+        #[clap(long)]
+        fee_schedule: String,
+        /// Where to write the fees of each client and their total
+        #[clap(long)]
+        out: String,
+        #[clap(flatten)]
+        input: InputOptions,
+    },
+    /// Apply transaction rows sent over TCP, one per line, to accounts kept in memory
+    Serve {
+        /// Address to listen on, e.g. 127.0.0.1:7878
+        #[clap(long)]
+        listen: String,
+        /// Serve a JSON HTTP API instead of CSV lines
+        #[clap(long)]
+        http: bool,
+    },
 }
 
-impl Ledger {
-    fn process(&mut self, transaction: &Transaction) {
-        match transaction.transaction_type {
-            TransactionType::Deposit => {
-                self.deposit(transaction);
-            },
-            TransactionType::Withdrawal => {
-                self.withdraw(transaction);
-            },
-            TransactionType::Dispute => {
-                self.dispute(transaction);
-            },
-            TransactionType::Resolve => {
-                self.resolve(transaction);
-            },
-            TransactionType::Chargeback => {
-                self.chargeback(transaction);
+// Parses a comma separated list of key=value pairs
+fn parse_pairs(value: &str, expected: &str) -> Result<HashMap<String, String>, String> {
+    value.split(',')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() && !value.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.trim().to_string()))
             },
-        }
+            _ => Err(format!("invalid mapping {}, expected {}", pair, expected)),
+        })
+        .collect()
+}
+
+// Parses a comma separated list of name=column pairs
+fn parse_column_map(value: &str) -> Result<HashMap<String, String>, String> {
+    parse_pairs(value, "name=column")
+}
+
+fn parse_input_column_map(value: &str) -> Result<HashMap<String, String>, String> {
+    let columns = parse_column_map(value)?;
+    match columns.keys().find(|name| !INPUT_COLUMNS.contains(&name.as_str())) {
+        Some(name) => Err(format!("unknown column {}, expected one of {}", name, INPUT_COLUMNS.join(","))),
+        None => Ok(columns),
     }
+}
 
-    fn deposit(&mut self, transaction: &Transaction) {
-        self.transactions_by_id.insert(transaction.transaction_id, transaction.clone());
+fn parse_type_map(value: &str) -> Result<HashMap<String, String>, String> {
+    parse_pairs(value, "input_type=type")
+}
 
-        if let Some(account) = self.account_by_id.get_mut(&transaction.client_id) {
-            account.available += transaction.amount.unwrap();
-            account.total = account.available + account.held;
-        } else {
-            let mut account = Account::new(transaction.client_id);
-            account.available = transaction.amount.unwrap();
-            account.total = account.available;
-            self.account_by_id.insert(transaction.client_id, account);
-        }
+fn parse_graph_format(value: &str) -> Result<GraphFormat, String> {
+    match value {
+        "dot" => Ok(GraphFormat::Dot),
+        "mermaid" => Ok(GraphFormat::Mermaid),
+        _ => Err(format!("invalid graph format {}, expected dot or mermaid", value)),
     }
+}
 
-    fn withdraw(&mut self, transaction: &Transaction) {
-        self.transactions_by_id.insert(transaction.transaction_id, transaction.clone());
+fn parse_output_format(value: &str) -> Result<OutputFormat, String> {
+    match value {
+        "csv" => Ok(OutputFormat::Csv),
+        "json" => Ok(OutputFormat::Json),
+        _ => Err(format!("invalid output format {}, expected csv or json", value)),
+    }
+}
 
-        if let Some(account) = self.account_by_id.get_mut(&transaction.client_id) {
-            let amount = transaction.amount.unwrap();
-            if account.available < amount {
-                eprintln!(
-                    "Withdrawal of {} from client {} is impossible due to insufficient available funds ({})",
-                    transaction.amount.unwrap(),
-                    transaction.client_id,
-                    account.available,
-                );
-            } else {
-                account.available -= amount;
-                account.total -= amount;
-            }
-        }
+fn parse_input_format(value: &str) -> Result<InputFormat, String> {
+    match value {
+        "csv" => Ok(InputFormat::Csv),
+        "jsonl" => Ok(InputFormat::Jsonl),
+        _ => Err(format!("invalid input format {}, expected csv or jsonl", value)),
     }
+}
 
-    fn dispute(&mut self, transaction: &Transaction) {
-        if let Some(fetched_transaction) = self.transactions_by_id.get_mut(&transaction.transaction_id) {
-            if fetched_transaction.client_id == transaction.client_id {
-                if let Some(account) = self.account_by_id.get_mut(&transaction.client_id) {
-                    fetched_transaction.disputed = true;
-                    let transaction_amount = fetched_transaction.amount.unwrap();
-                    if account.available > transaction_amount {
-                        account.available -= transaction_amount;
-                        account.held += transaction_amount;
-                    } else {
-                        eprintln!(
-                            "Dispute of {} for client {} is impossible due to unsufficient available funds ({})",
-                            fetched_transaction.amount.unwrap(),
-                            transaction.client_id,
-                            account.available,
-                        );
-                    }
-                }
-            }
-        } else {
-            eprintln!("Can't find transaction id {} to dispute", transaction.transaction_id);
-        }
+fn parse_explain_format(value: &str) -> Result<ExplainFormat, String> {
+    match value {
+        "text" => Ok(ExplainFormat::Text),
+        "json" => Ok(ExplainFormat::Json),
+        _ => Err(format!("invalid explain format {}, expected text or json", value)),
     }
+}
 
-    fn resolve(&mut self, transaction: &Transaction) {
-        if let Some(fetched_transaction) = self.transactions_by_id.get_mut(&transaction.transaction_id) {
-            if fetched_transaction.client_id == transaction.client_id {
-                if let Some(account) = self.account_by_id.get_mut(&transaction.client_id) {
-                    let transaction_amount = fetched_transaction.amount.unwrap();
-                    if fetched_transaction.disputed && account.held >= transaction_amount {
-                            account.available += transaction_amount;
-                            account.held -= transaction_amount;
-                    } else {
-                        eprintln!(
-                            "Resolve {} for client {} is impossible due to unsufficient held funds ({}) or not disputed",
-                            fetched_transaction.amount.unwrap(),
-                            transaction.client_id,
-                            account.held,
-                        );
-                    }
-                    fetched_transaction.disputed = false;
-                }
-            }
-        } else {
-            eprintln!("Can't find transaction id {} to resolve", transaction.transaction_id);
-        }
+fn parse_merged_clients(value: &str) -> Result<MergedClients, String> {
+    match value {
+        "redirect" => Ok(MergedClients::Redirect),
+        "reject" => Ok(MergedClients::Reject),
+        _ => Err(format!("invalid merged clients policy {}, expected redirect or reject", value)),
     }
+}
 
-    fn chargeback(&mut self, transaction: &Transaction) {
-        if let Some(fetched_transaction) = self.transactions_by_id.get_mut(&transaction.transaction_id) {
-            if fetched_transaction.client_id == transaction.client_id {
-                if let Some(account) = self.account_by_id.get_mut(&transaction.client_id) {
-                    let transaction_amount = fetched_transaction.amount.unwrap();
-                    if fetched_transaction.disputed && account.held >= transaction_amount {
-                            account.total -= transaction_amount;
-                            account.held -= transaction_amount;
-                            account.locked = true;
-                    } else {
-                        eprintln!(
-                            "Chargeback {} for client {} is impossible due to unsufficient held funds ({}) or not disputed",
-                            fetched_transaction.amount.unwrap(),
-                            transaction.client_id,
-                            account.held,
-                        );
-                    }
-                    fetched_transaction.disputed = false;
-                }
-            }
-        } else {
-            eprintln!("Can't find transaction id {} to chargeback", transaction.transaction_id);
-        }
+fn parse_sentinel_ids(value: &str) -> Result<SentinelIds, String> {
+    match value {
+        "reject" => Ok(SentinelIds::Reject),
+        "ignore" => Ok(SentinelIds::Ignore),
+        "allow" => Ok(SentinelIds::Allow),
+        _ => Err(format!("invalid sentinel ids policy {}, expected reject, ignore or allow", value)),
     }
+}
 
-    fn get_account(&self, client_id: u16) -> Option<Account> {
-        self.account_by_id.get(&client_id).cloned()
+// A single byte separating fields, \t standing for a tab
+fn parse_delimiter(value: &str) -> Result<u8, String> {
+    match value.as_bytes() {
+        b"\\t" => Ok(b'\t'),
+        [delimiter] if *delimiter != b'"' && *delimiter != b'\n' && *delimiter != b'\r' => Ok(*delimiter),
+        _ => Err(format!("invalid delimiter {}, expected a single byte other than a quote or line break", value)),
     }
 }
 
-fn main() {
-    let args = Args::parse();
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number = number.parse::<u64>().map_err(|_| format!("invalid duration {}", value))?;
+    match unit {
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 3600)),
+        _ => Err(format!("invalid duration unit in {}, expected s, m or h", value)),
+    }
+}
 
-    let reader = File::open(&args.file)
-        .map(|file| { Reader::from_reader(file) })
-        .map_err(|err| {
-            eprintln!("Cannot read file {} properly: {}", args.file, err);
-        })
-        .ok();
+fn parse_near_dup_field(value: &str) -> Result<NearDuplicateField, String> {
+    match value.trim() {
+        "client" => Ok(NearDuplicateField::Client),
+        "type" => Ok(NearDuplicateField::Type),
+        "amount" => Ok(NearDuplicateField::Amount),
+        _ => Err(format!("unknown field {}, expected client, type or amount", value)),
+    }
+}
 
+// Exit code of a run aborted by --memory-budget
+const MEMORY_BUDGET_EXIT_CODE: i32 = 3;
 
-    let mut ledger = Ledger::default();
+// Exit code of a run refused because of the input header
+const INPUT_SCHEMA_EXIT_CODE: i32 = 4;
+
+// Exit code of a run whose accounts differ from the --reconcile balances
+const RECONCILE_EXIT_CODE: i32 = 5;
+
+// Exit code of a run refused because the input is not a text CSV
+const INPUT_FORMAT_EXIT_CODE: i32 = 6;
+
+// Exit code of a run that panicked while processing rows
+const CRASH_EXIT_CODE: i32 = 7;
+
+// Exit code of a --dry-run that found rows it would not apply
+const DRY_RUN_REJECTED_EXIT_CODE: i32 = 8;
+
+// Exit code of a --strict run stopped by a row that isn't a transaction
+const STRICT_EXIT_CODE: i32 = 9;
 
-    for r in reader.unwrap().deserialize::<Transaction>() {
-        let transaction = r.unwrap();
-        ledger.process(&transaction);
+// Bytes read from the start of the input to recognize its format
+const SNIFF_BYTES: u64 = 4096;
+
+// Message and location of the last panic, kept by the hook for the crash report
+static PANIC_MESSAGE: Mutex<Option<String>> = Mutex::new(None);
+
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Ok(mut message) = PANIC_MESSAGE.lock() {
+            *message = Some(info.to_string());
+        }
+        default_hook(info);
+    }));
+}
+
+// File argument standing for the standard input
+const STDIN: &str = "-";
+
+// Name of an input file in messages
+fn input_name(file: &str) -> &str {
+    if file == STDIN { "<stdin>" } else { file }
+}
+
+// Opens a file, or the standard input for -, exiting when it can't be read
+fn open_input(file: &str) -> Box<dyn Read> {
+    if file == STDIN {
+        return Box::new(std::io::stdin().lock());
+    }
+    match File::open(file) {
+        Ok(input) => Box::new(input),
+        Err(err) => {
+            eprintln!("Cannot read file {} properly: {}", file, err);
+            std::process::exit(1);
+        },
     }
+}
 
+// Opens a transactions file, exiting when it can't be read or its header
+// isn't a transactions header. Lines are cut past max_record_bytes.
+fn open_transactions(file: &str, mapping: &InputMapping, max_record_bytes: Option<usize>) -> Reader<RecordLimit<Box<dyn Read>>> {
+    transactions_reader(file, open_input(file), mapping, max_record_bytes)
+}
 
-    let mut wrtr = Writer::from_writer(std::io::stdout());
-    for account in ledger.account_by_id.values() {
-        wrtr.serialize(account).unwrap();
-    } 
+// open_transactions over an input already opened
+fn transactions_reader(
+    file: &str,
+    mut input: Box<dyn Read>,
+    mapping: &InputMapping,
+    max_record_bytes: Option<usize>,
+) -> Reader<RecordLimit<Box<dyn Read>>> {
+    // Sniffed then read again ahead of the rest, as stdin can't be reopened
+    let mut head = Vec::new();
+    if let Err(err) = input.by_ref().take(SNIFF_BYTES).read_to_end(&mut head) {
+        eprintln!("Cannot read file {} properly: {}", input_name(file), err);
+        std::process::exit(1);
+    }
+    if let Err(err) = sniff_input(&head) {
+        eprintln!("Invalid input file {}: {}", input_name(file), err);
+        std::process::exit(INPUT_FORMAT_EXIT_CODE);
+    }
+    let input: Box<dyn Read> = Box::new(Cursor::new(head).chain(input));
+    // Lines end at \n, the \r of a CRLF trimmed with the fields, so that
+    // positions count CRLF lines right
+    let mut reader = ReaderBuilder::new()
+        .delimiter(mapping.delimiter)
+        .terminator(Terminator::Any(b'\n'))
+        .trim(Trim::All)
+        .has_headers(!mapping.no_headers)
+        .from_reader(RecordLimit::new(input, max_record_bytes.unwrap_or(usize::MAX)));
+    if mapping.no_headers {
+        // Without headers csv only trims the first row if it was read
+        // ahead as a header, then handed out again as a record
+        if let Err(err) = reader.byte_headers() {
+            eprintln!("Cannot read the first row of file {} properly: {}", input_name(file), err);
+            std::process::exit(1);
+        }
+        return reader;
+    }
+    if let Err(err) = mapping.map_headers(&mut reader) {
+        eprintln!("Cannot read the header of file {} properly: {}", input_name(file), err);
+        std::process::exit(1);
+    }
+    if let Err(err) = mapping.validate_headers(reader.headers().unwrap()) {
+        eprintln!("Invalid input file {}: {}", input_name(file), err);
+        std::process::exit(INPUT_SCHEMA_EXIT_CODE);
+    }
+    reader
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Prints on stderr the rows skipped or rejected, reviews and merges the
+// ledger collected since the last call
+fn print_notices(ledger: &mut Ledger) {
+    for notice in ledger.notices.iter_mut().flat_map(|notices| notices.drain(..)) {
+        eprintln!("{}", notice);
+    }
+}
 
-    #[test]
-    fn deposit_test() {
-        let mut ledger = Ledger::default();
-        let mut transaction = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(dec!(1.5)),
-            disputed: false,
-        };
+// The accounts of the transactions file, processed with the default policies
+fn replay(file: &str, input: &InputOptions) -> Ledger {
+    let mapping = input.mapping();
+    let mut reader = open_transactions(file, &mapping, Some(input.max_record_bytes));
+    let mut ledger = Ledger::default();
+    ledger.notices = Some(Vec::new());
+    let processed = process_records(&mut ledger, &mut reader, &mapping, &input.budget());
+    print_notices(&mut ledger);
+    if let Err(err) = processed {
+        eprintln!("Cannot read {} properly: {}", input_name(file), err);
+        std::process::exit(1);
+    }
+    ledger
+}
 
-        ledger.deposit(&transaction);
-        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.5));
-        assert_eq!(ledger.get_account(1).unwrap().total, dec!(1.5));
+fn checksum(file: &str, input: &InputOptions) {
+    let ledger = replay(file, input);
+    println!("{}", accounts_checksum(&ledger));
+}
 
-        transaction.transaction_id = 2;
-        transaction.amount = Some(dec!(4.5));
+fn repair(partial: &str, transactions: &str, out: &str, input: &InputOptions) {
+    let partial_output = std::fs::read(partial).unwrap_or_else(|err| {
+        eprintln!("Cannot read file {} properly: {}", partial, err);
+        std::process::exit(1);
+    });
+    let ledger = replay(transactions, input);
 
-        ledger.deposit(&transaction);
-        assert_eq!(ledger.get_account(1).unwrap().available, dec!(6.0));
-        assert_eq!(ledger.get_account(1).unwrap().total, dec!(6.0));
+    let (output, report) = repair_output(&ledger, &partial_output);
+    if let Err(err) = std::fs::write(out, output) {
+        eprintln!("Cannot write repaired output {} properly: {}", out, err);
+        std::process::exit(1);
     }
+    eprintln!("{} rows salvaged from {}, {} rows regenerated", report.salvaged_rows, partial, report.regenerated_rows);
+}
 
-    #[test]
-    fn withdraw_test() {
-        let mut ledger = Ledger::default();
-        let transaction_deposit = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(dec!(1.5)),
-            disputed: false,
-        };
+fn consolidate_units(states: &[String], labels: &[String], out: &str, cross_unit: Option<&String>) {
+    if !labels.is_empty() && labels.len() != states.len() {
+        eprintln!("{} labels given for {} units", labels.len(), states.len());
+        std::process::exit(1);
+    }
+    let units: Vec<(String, Vec<Account>)> = states.iter()
+        .enumerate()
+        .map(|(index, path)| {
+            let accounts = Reader::from_path(path)
+                .and_then(|mut reader| read_accounts(&mut reader))
+                .unwrap_or_else(|err| {
+                    eprintln!("Cannot read accounts file {} properly: {}", path, err);
+                    std::process::exit(1);
+                });
+            (labels.get(index).unwrap_or(path).clone(), accounts)
+        })
+        .collect();
 
-        ledger.deposit(&transaction_deposit);
+    let (subtotals, cross_units) = consolidate(&units);
+    if let Err(err) = write_consolidation(out, &subtotals) {
+        eprintln!("Cannot write consolidation {} properly: {}", out, err);
+        std::process::exit(1);
+    }
+    if let Some(path) = cross_unit {
+        if let Err(err) = write_cross_unit_report(path, &cross_units) {
+            eprintln!("Cannot write cross-unit report {} properly: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+}
 
-        let mut transaction_withdrawal = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client_id: 1,
-            transaction_id: 2,
-            amount: Some(dec!(0.5)),
-            disputed: false,
-        };
+fn annotate_file(file: &str, out: &str, input: &InputOptions) {
+    let mapping = input.mapping();
+    let mut reader = open_transactions(file, &mapping, Some(input.max_record_bytes));
+    let mut ledger = Ledger::default();
+    let annotated = File::create(out)
+        .map_err(csv::Error::from)
+        .and_then(|output| annotate(&mut ledger, &mut reader, &mapping, &input.budget(), BufWriter::new(output)));
+    if let Err(err) = annotated {
+        eprintln!("Cannot write annotated file {} properly: {}", out, err);
+        std::process::exit(1);
+    }
+}
 
+fn explain_row(file: &str, target: ExplainTarget, format: ExplainFormat, input: &InputOptions) {
+    let mapping = input.mapping();
+    let mut reader = open_transactions(file, &mapping, Some(input.max_record_bytes));
+    let mut ledger = Ledger::default();
+    let explanation = explain(&mut ledger, &mut reader, &mapping, &input.budget(), target).unwrap_or_else(|err| {
+        eprintln!("Cannot read file {} properly: {}", input_name(file), err);
+        std::process::exit(1);
+    });
+    match (explanation, format) {
+        (Some(explanation), ExplainFormat::Text) => print!("{}", explanation),
+        (Some(explanation), ExplainFormat::Json) => print!("{}", explanation.to_json()),
+        (None, _) => {
+            match target {
+                ExplainTarget::Line(line) => eprintln!("No row at line {} of {}", line, input_name(file)),
+                ExplainTarget::Transaction(id) => eprintln!("No transaction {} in {}", id, input_name(file)),
+            }
+            std::process::exit(1);
+        },
+    }
+}
 
-        ledger.withdraw(&transaction_withdrawal);
-        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.0));
-        assert_eq!(ledger.get_account(1).unwrap().total, dec!(1.0));
+fn simulate(file: &str, fee_schedule: &str, out: &str, input: &InputOptions) {
+    let schedule = Reader::from_path(fee_schedule)
+        .map_err(|err| err.to_string())
+        .and_then(|mut reader| read_fee_schedule(&mut reader))
+        .unwrap_or_else(|err| {
+            eprintln!("Cannot read fee schedule {} properly: {}", fee_schedule, err);
+            std::process::exit(1);
+        });
+    let mapping = input.mapping();
+    let mut reader = open_transactions(file, &mapping, Some(input.max_record_bytes));
+    let mut ledger = Ledger::default();
+    let fees = simulate_fees(&mut ledger, &mut reader, &mapping, &input.budget(), &schedule).unwrap_or_else(|err| {
+        eprintln!("Cannot read file {} properly: {}", input_name(file), err);
+        std::process::exit(1);
+    });
+    if let Err(err) = write_fee_report(out, &fees) {
+        eprintln!("Cannot write fee report {} properly: {}", out, err);
+        std::process::exit(1);
+    }
+}
 
-        transaction_withdrawal.amount = Some(dec!(2.0));
+fn main() {
+    let args = Args::parse();
 
-        ledger.withdraw(&transaction_withdrawal);
-        assert_eq!(ledger.get_account(1).unwrap().available, dec!(1.0));
-        assert_eq!(ledger.get_account(1).unwrap().total, dec!(1.0));
+    match &args.command {
+        Some(Command::Checksum { file, input }) => {
+            checksum(file, input);
+            return;
+        },
+        Some(Command::Graph { format }) => {
+            print!("{}", dispute_graph(*format));
+            return;
+        },
+        Some(Command::RepairOutput { partial, transactions, out, input }) => {
+            repair(partial, transactions, out, input);
+            return;
+        },
+        Some(Command::Consolidate { states, labels, out, cross_unit }) => {
+            consolidate_units(states, labels, out, cross_unit.as_ref());
+            return;
+        },
+        Some(Command::Annotate { file, out, input }) => {
+            annotate_file(file, out, input);
+            return;
+        },
+        Some(Command::Explain { file, line, tx, format, input }) => {
+            let target = match (line, tx) {
+                (Some(line), _) => ExplainTarget::Line(*line),
+                (None, tx) => ExplainTarget::Transaction(tx.unwrap()),
+            };
+            explain_row(file, target, *format, input);
+            return;
+        },
+        Some(Command::SimulateFees { file, fee_schedule, out, input }) => {
+            simulate(file, fee_schedule, out, input);
+            return;
+        },
+        Some(Command::Serve { listen, http }) => {
+            let listener = std::net::TcpListener::bind(listen).unwrap_or_else(|err| {
+                eprintln!("Cannot listen on {}: {}", listen, err);
+                std::process::exit(1);
+            });
+            eprintln!("Listening on {}", listener.local_addr().unwrap());
+            if let Err(err) = serve(listener, Default::default(), *http) {
+                eprintln!("Stopped serving on {}: {}", listen, err);
+                std::process::exit(1);
+            }
+            return;
+        },
+        None => {},
     }
 
-    #[test]
-    fn dispute_test() {
-        let mut ledger = Ledger::default();
-        let mut transaction_deposit = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(dec!(1.5)),
-            disputed: false,
-        };
+    if args.files.iter().filter(|file| *file == STDIN).count() > 1 {
+        eprintln!("The standard input can only be read once");
+        std::process::exit(1);
+    }
+    // The QA sample reads the input again for the histories of its clients
+    if args.qa_sample.is_some() && args.files.iter().any(|file| file == STDIN) {
+        eprintln!("--qa-sample cannot read the standard input again");
+        std::process::exit(1);
+    }
 
-        ledger.deposit(&transaction_deposit);
+    let clients = args.retain_clients_file.as_ref().map(|path| {
+        read_clients_file(path).unwrap_or_else(|err| {
+            eprintln!("Cannot read clients file {} properly: {}", path, err);
+            std::process::exit(1);
+        })
+    });
 
-        transaction_deposit.transaction_id = 2;
-        transaction_deposit.amount = Some(dec!(10.0));
+    let enrichment = args.enrich.as_ref().map(|path| {
+        Reader::from_path(path)
+            .map_err(|err| err.to_string())
+            .and_then(|mut reader| read_enrichment(&mut reader, &args.enrich_columns, args.enrich_last_wins))
+            .unwrap_or_else(|err| {
+                eprintln!("Cannot read enrichment file {} properly: {}", path, err);
+                std::process::exit(1);
+            })
+    });
 
-        ledger.deposit(&transaction_deposit);
+    let mapping = args.input.mapping();
 
-        let dispute = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-            disputed: false,
+    let mut ledger = Ledger::with_retention(RetentionPolicy {
+        min_amount: args.retain_min_amount,
+        clients,
+    });
+    ledger.merged_clients = args.merged_clients;
+    ledger.sentinel_ids = args.sentinel_ids;
+    ledger.near_duplicates = args.near_dup_window
+        .map(|rows| NearDuplicateWindow::new(rows, args.near_dup_fields.clone()));
+    let staged = args.output_dir.as_ref().map(|directory| {
+        StagedOutputs::new(directory).unwrap_or_else(|err| {
+            eprintln!("Cannot write outputs to {} properly: {}", directory, err);
+            std::process::exit(1);
+        })
+    });
+    // --client-exports is built from the audit log, a temporary one without --audit
+    let audit_path = match (&args.audit, &args.client_exports) {
+        (Some(path), _) => Some(path.clone()),
+        (None, _) if staged.is_some() => Some(staged.as_ref().unwrap().path("audit.csv").to_string_lossy().into_owned()),
+        (None, Some(directory)) => {
+            if let Err(err) = std::fs::create_dir_all(directory) {
+                eprintln!("Cannot write client exports to {} properly: {}", directory, err);
+                std::process::exit(1);
+            }
+            Some(std::path::Path::new(directory).join(".audit.csv").to_string_lossy().into_owned())
+        },
+        (None, None) => None,
+    };
+    if let Some(path) = &audit_path {
+        let output = File::create(path).unwrap_or_else(|err| {
+            eprintln!("Cannot write audit log {} properly: {}", path, err);
+            std::process::exit(1);
+        });
+        ledger.audit = Some(AuditLog::new(Box::new(BufWriter::new(output))));
+    }
+    if args.dry_run {
+        ledger.rejections = Some(Vec::new());
+    }
+    ledger.notices = Some(Vec::new());
+    if args.progress {
+        // Unknown as soon as one input is the standard input
+        let total_bytes = args.files.iter()
+            .map(|file| if file == STDIN { None } else { std::fs::metadata(file).ok().map(|metadata| metadata.len()) })
+            .sum();
+        ledger.progress = Some(Progress::new(total_bytes, std::io::stderr().is_terminal()));
+    }
+    if let Some(path) = &args.load_state {
+        let loaded = File::open(path)
+            .map_err(|err| err.to_string())
+            .and_then(|file| ledger.load_state(BufReader::new(file)));
+        if let Err(err) = loaded {
+            eprintln!("Cannot load state {} properly: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+
+    install_panic_hook();
+    let started = Instant::now();
+    let mut progress = RunProgress {
+        rows: 0,
+        last_line: 0,
+        partial: false,
+        memory_exceeded: false,
+        cancelled: false,
+        malformed: None,
+    };
+    // Rows processed of each file, in order
+    let mut rows_by_file: Vec<(&String, u64)> = Vec::new();
+    let mut rejections: Vec<(String, Rejection)> = Vec::new();
+    for file in &args.files {
+        // The budget is shared by the files, what's left of it is spent on this one
+        let budget = Budget {
+            max_rows: args.stop_after_rows.map(|max_rows| max_rows.saturating_sub(progress.rows)),
+            max_duration: args.stop_after_duration.map(|max_duration| max_duration.saturating_sub(started.elapsed())),
+            max_memory: args.memory_budget,
+            cancel: None,
+            max_field_bytes: Some(args.input.max_field_bytes),
+            max_record_bytes: Some(args.input.max_record_bytes),
+            strict: args.strict,
         };
+        if budget.max_rows == Some(0) || budget.max_duration == Some(Duration::ZERO) {
+            progress.partial = true;
+            break;
+        }
+        let file_progress = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            // Only counted with --progress, so the bytes cost nothing otherwise
+            let input = match &ledger.progress {
+                Some(progress) => Box::new(progress.counting(open_input(file))),
+                None => open_input(file),
+            };
+            if args.input_format == InputFormat::Jsonl {
+                let mut reader = BufReader::new(RecordLimit::new(input, args.input.max_record_bytes));
+                process_json_lines(&mut ledger, &mut reader, &mapping, &budget)
+            } else {
+                let mut reader = transactions_reader(file, input, &mapping, Some(args.input.max_record_bytes));
+                process_records(&mut ledger, &mut reader, &mapping, &budget)
+            }
+        }))
+        .unwrap_or_else(|_| {
+            print_notices(&mut ledger);
+            let panic = PANIC_MESSAGE.lock().ok().and_then(|message| message.clone()).unwrap_or_default();
+            let panic = format!("while processing {}: {}", input_name(file), panic);
+            eprintln!("Crashed {}", panic);
+            if let Some(directory) = &args.crash_dir {
+                match write_crash_artifacts(&ledger, directory, &panic) {
+                    Ok(()) => eprintln!("Crash report and accounts snapshot written to {}", directory),
+                    Err(err) => eprintln!("Cannot write crash artifacts to {} properly: {}", directory, err),
+                }
+            }
+            // The rows audited before the crash, best effort
+            if let Some(audit) = ledger.audit.as_mut() {
+                let _ = audit.finish();
+            }
+            std::process::exit(CRASH_EXIT_CODE);
+        })
+        .unwrap_or_else(|err| {
+            print_notices(&mut ledger);
+            eprintln!("Cannot read {} properly: {}", input_name(file), err);
+            // The rows audited before the error, best effort
+            if let Some(audit) = ledger.audit.as_mut() {
+                let _ = audit.finish();
+            }
+            std::process::exit(1);
+        });
+        print_notices(&mut ledger);
+        if let Some(malformed) = &file_progress.malformed {
+            eprintln!("Stopped by --strict in {}: {}", input_name(file), malformed);
+            // The rows audited up to it, best effort
+            if let Some(audit) = ledger.audit.as_mut() {
+                let _ = audit.finish();
+            }
+            std::process::exit(STRICT_EXIT_CODE);
+        }
+        rows_by_file.push((file, file_progress.rows));
+        for rejection in ledger.rejections.iter_mut().flat_map(|rejections| rejections.drain(..)) {
+            rejections.push((input_name(file).to_string(), rejection));
+        }
+        progress.rows += file_progress.rows;
+        progress.last_line = file_progress.last_line;
+        progress.partial = file_progress.partial;
+        progress.memory_exceeded = file_progress.memory_exceeded;
+        if progress.partial || progress.memory_exceeded {
+            break;
+        }
+    }
+    if let Some(progress) = &ledger.progress {
+        progress.finish();
+    }
+    // File of the last processed row
+    let last_file = rows_by_file.last().map_or(&args.files[0], |(file, _)| *file);
+
+    if progress.memory_exceeded {
+        eprintln!(
+            "Memory budget of {} bytes would be exceeded: stopped after {} rows, last processed line {} of {}, {}",
+            args.memory_budget.unwrap(),
+            progress.rows,
+            progress.last_line,
+            input_name(last_file),
+            ledger.memory_report(),
+        );
+        std::process::exit(MEMORY_BUDGET_EXIT_CODE);
+    }
 
-        ledger.dispute(&dispute);
+    if let (Some(path), Some(audit)) = (&audit_path, ledger.audit.as_mut()) {
+        if let Err(err) = audit.finish() {
+            eprintln!("Cannot write audit log {} properly: {}", path, err);
+            std::process::exit(1);
+        }
+    }
 
-        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10.0));
-        assert_eq!(ledger.get_account(1).unwrap().held, dec!(1.5));
-        assert_eq!(ledger.get_account(1).unwrap().total, dec!(11.5));
+    if let (Some(directory), Some(path)) = (&args.client_exports, &audit_path) {
+        let exported = File::open(path)
+            .map_err(csv::Error::from)
+            .and_then(|audit| write_client_exports(&ledger, BufReader::new(audit), directory));
+        if args.audit.is_none() && staged.is_none() {
+            let _ = std::fs::remove_file(path);
+        }
+        if let Err(err) = exported {
+            eprintln!("Cannot write client exports to {} properly: {}", directory, err);
+            std::process::exit(1);
+        }
     }
 
-    #[test]
-    fn resolve_test() {
-        let mut ledger = Ledger::default();
-        let mut transaction_deposit = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(dec!(1.5)),
-            disputed: false,
-        };
+    if let Some(path) = &args.save_state {
+        if let Err(err) = write_atomically(path, |file| ledger.save_state(BufWriter::new(file))) {
+            eprintln!("Cannot save state {} properly: {}", path, err);
+            std::process::exit(1);
+        }
+    }
 
-        ledger.deposit(&transaction_deposit);
+    if ledger.sentinel_rows > 0 {
+        eprintln!("{} row(s) with a sentinel client or tx id 0", ledger.sentinel_rows);
+    }
 
-        transaction_deposit.transaction_id = 2;
-        transaction_deposit.amount = Some(dec!(10.0));
+    if args.memory_report {
+        eprintln!("Memory: {}", ledger.memory_report());
+    }
 
-        ledger.deposit(&transaction_deposit);
+    if args.stats {
+        eprintln!("{}", ledger.stats());
+    }
 
-        let dispute = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-            disputed: false,
-        };
+    if let Some(path) = &args.clients_seen {
+        if let Err(err) = write_clients_seen(path, &ledger.clients_seen) {
+            eprintln!("Cannot write clients seen file {} properly: {}", path, err);
+        }
+    }
 
-        ledger.dispute(&dispute);
+    if let Some(path) = &args.holds_report {
+        if let Err(err) = write_holds_report(path, &ledger.holds_report()) {
+            eprintln!("Cannot write holds report {} properly: {}", path, err);
+        }
+    }
 
-        let resolve = Transaction {
-            transaction_type: TransactionType::Resolve,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-            disputed: false,
-        };
+    if let Some(path) = &args.projection_report {
+        if let Err(err) = write_projection_report(path, &ledger.projection_report()) {
+            eprintln!("Cannot write projection report {} properly: {}", path, err);
+        }
+    }
 
-        ledger.resolve(&resolve);
+    if let Some(path) = &args.activity_report {
+        let activities = ledger.activity_report();
+        if let Err(err) = write_activity_report(path, &activities) {
+            eprintln!("Cannot write activity report {} properly: {}", path, err);
+        }
+        let mut counts: Vec<u64> = activities.iter().map(|activity| activity.tx_count).collect();
+        counts.sort_unstable();
+        eprintln!(
+            "Transactions per client: p50 {}, p99 {}, max {}",
+            percentile(&counts, 50),
+            percentile(&counts, 99),
+            percentile(&counts, 100),
+        );
+    }
 
-        assert_eq!(ledger.get_account(1).unwrap().available, dec!(11.5));
-        assert_eq!(ledger.get_account(1).unwrap().held, dec!(0));
-        assert_eq!(ledger.get_account(1).unwrap().total, dec!(11.5));
+    if let (Some(size), Some(path)) = (args.qa_sample, &args.qa_file) {
+        let sample = ledger.qa_sample(size, args.qa_seed);
+        let clients: HashSet<u16> = sample.iter().map(|(_, client_id)| *client_id).collect();
+        let mut histories: HashMap<u16, Vec<Transaction>> = HashMap::new();
+        let written = rows_by_file.iter()
+            .try_for_each(|(file, rows)| {
+                let mut reader = open_transactions(file, &mapping, Some(args.input.max_record_bytes));
+                for (client_id, transactions) in read_histories(&mut reader, &mapping, &args.input.budget(), *rows, &clients)? {
+                    histories.entry(client_id).or_default().extend(transactions);
+                }
+                Ok(())
+            })
+            .and_then(|()| write_qa_sample(path, &ledger, &sample, &histories));
+        if let Err(err) = written {
+            eprintln!("Cannot write QA sample {} properly: {}", path, err);
+        }
     }
 
-    #[test]
-    fn chargeback_test() {
-        let mut ledger = Ledger::default();
-        let mut transaction_deposit = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(dec!(1.5)),
-            disputed: false,
+    if args.dry_run {
+        if let Err(err) = write_rejections(&rejections, std::io::stdout()) {
+            eprintln!("Cannot write rejections properly: {}", err);
+            std::process::exit(1);
+        }
+    } else if let Some(path) = &args.output_append {
+        if let Err(err) = append_accounts(&ledger, path, args.run_label.as_ref().unwrap()) {
+            eprintln!("Cannot append accounts to {} properly: {}", path, err);
+            std::process::exit(1);
+        }
+    } else {
+        for client_id in enrichment.iter().flat_map(|enrichment| unseen_enriched_clients(enrichment, &ledger.clients_seen)) {
+            eprintln!("Enrichment row for client {} never seen in the input", client_id);
+        }
+        let write = |writer: &mut dyn Write| if args.format == OutputFormat::Json {
+            write_accounts_json(&ledger, writer)
+        } else if enrichment.is_some() || args.review_column {
+            write_extended_accounts(&ledger, args.review_column, enrichment.as_ref(), writer)
+        } else {
+            write_delimited_accounts(&ledger, args.output_delimiter.unwrap_or(b','), writer)
         };
+        match (&args.output, &staged) {
+            (Some(path), _) => {
+                if let Err(err) = write_atomically(path, |file| write(&mut BufWriter::new(file))) {
+                    eprintln!("Cannot write accounts to {} properly: {}", path, err);
+                    std::process::exit(1);
+                }
+            },
+            (None, Some(staged)) => {
+                let path = staged.path(if args.format == OutputFormat::Json { "accounts.json" } else { "accounts.csv" });
+                let written = File::create(&path)
+                    .map_err(csv::Error::from)
+                    .and_then(|file| write(&mut BufWriter::new(file)));
+                if let Err(err) = written {
+                    eprintln!("Cannot write accounts to {} properly: {}, outputs left in {}", path.display(), err, staged.staging().display());
+                    std::process::exit(1);
+                }
+            },
+            (None, None) => {
+                if let Err(err) = write(&mut std::io::stdout()) {
+                    eprintln!("Cannot write accounts properly: {}", err);
+                    std::process::exit(1);
+                }
+            },
+        }
+    }
 
-        ledger.deposit(&transaction_deposit);
+    if let (Some(staged), Some(directory)) = (staged, &args.output_dir) {
+        let staging = staged.staging().to_path_buf();
+        let published = std::fs::write(staged.path("stats.txt"), format!("{}\n", ledger.stats()))
+            .and_then(|()| staged.publish());
+        if let Err(err) = published {
+            eprintln!("Cannot publish outputs to {} properly: {}, outputs left in {}", directory, err, staging.display());
+            std::process::exit(1);
+        }
+    }
 
-        transaction_deposit.transaction_id = 2;
-        transaction_deposit.amount = Some(dec!(10.0));
+    if progress.partial {
+        eprintln!(
+            "partial=true: stopped on budget after {} rows, last processed line {} of {}",
+            progress.rows,
+            progress.last_line,
+            input_name(last_file),
+        );
+    }
 
-        ledger.deposit(&transaction_deposit);
+    if args.recompute_check {
+        let mismatches = ledger.recompute_check();
+        for mismatch in &mismatches {
+            eprintln!(
+                "Recompute mismatch for client {}: available delta {}, held delta {}, total delta {}",
+                mismatch.client_id,
+                mismatch.available_delta,
+                mismatch.held_delta,
+                mismatch.total_delta,
+            );
+        }
+        if !mismatches.is_empty() {
+            std::process::exit(1);
+        }
+    }
 
-        let dispute = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-            disputed: false,
-        };
+    if let Some(external) = &args.reconcile {
+        let column_map = args.reconcile_columns.clone().unwrap_or_default();
+        let deltas = Reader::from_path(external)
+            .map_err(|err| err.to_string())
+            .and_then(|mut reader| reconcile(&ledger, &mut reader, &column_map, args.reconcile_tolerance))
+            .unwrap_or_else(|err| {
+                eprintln!("Cannot reconcile against {} properly: {}", external, err);
+                std::process::exit(1);
+            });
+        let report = args.reconcile_report.as_ref().unwrap();
+        if let Err(err) = write_reconcile_report(report, &deltas) {
+            eprintln!("Cannot write reconcile report {} properly: {}", report, err);
+        }
+        if !deltas.is_empty() {
+            eprintln!("{} balance(s) differ from {} beyond tolerance {}", deltas.len(), external, args.reconcile_tolerance);
+            std::process::exit(RECONCILE_EXIT_CODE);
+        }
+    }
 
-        ledger.dispute(&dispute);
+    if !rejections.is_empty() {
+        eprintln!("{} row(s) would not be applied", rejections.len());
+        std::process::exit(DRY_RUN_REJECTED_EXIT_CODE);
+    }
+}
 
-        let chargeback = Transaction {
-            transaction_type: TransactionType::Chargeback,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-            disputed: false,
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        ledger.chargeback(&chargeback);
+    #[test]
+    fn args_test() {
+        use clap::CommandFactory;
+        Args::command().debug_assert();
+
+        let args = Args::parse_from(["pieuvre", "input.csv", "--near-dup-window", "5", "--near-dup-fields", "client,amount"]);
+        assert_eq!(args.near_dup_fields, vec![NearDuplicateField::Client, NearDuplicateField::Amount]);
+        let args = Args::parse_from(["pieuvre", "input.csv"]);
+        assert_eq!(args.near_dup_fields.len(), 3);
+    }
 
-        assert_eq!(ledger.get_account(1).unwrap().available, dec!(10));
-        assert_eq!(ledger.get_account(1).unwrap().held, dec!(0));
-        assert_eq!(ledger.get_account(1).unwrap().total, dec!(10));
-        assert!(ledger.get_account(1).unwrap().locked);
+    #[test]
+    fn parsers_test() {
+        assert_eq!(
+            parse_column_map(" client = cust_id,total=balance"),
+            Ok(HashMap::from([("client".to_string(), "cust_id".to_string()), ("total".to_string(), "balance".to_string())])),
+        );
+        assert!(parse_column_map("client=").is_err());
+        assert!(parse_input_column_map("kind=txn_type").is_err());
+        assert_eq!(parse_type_map("CR=deposit").map(|types| types.len()), Ok(1));
+        assert!(parse_sentinel_ids("skip").is_err());
+        assert_eq!(parse_delimiter("\\t"), Ok(b'\t'));
+        assert_eq!(parse_delimiter(";"), Ok(b';'));
+        assert!(parse_delimiter("\"").is_err());
+        assert!(parse_near_dup_field("timestamp").is_err());
+    }
+
+    #[test]
+    fn parse_duration_test() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+        assert!(parse_duration("10d").is_err());
+        assert!(parse_duration("m").is_err());
     }
 }
@@ -0,0 +1,345 @@
+use std::process::Command;
+
+fn pieuvre() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_pieuvre"))
+}
+
+#[test]
+fn accounts_output_as_input() {
+    let output = pieuvre().arg("data/transactions_complete.csv").output().unwrap();
+    assert!(output.status.success());
+
+    let path = std::env::temp_dir().join(format!("pieuvre-accounts-{}.csv", std::process::id()));
+    std::fs::write(&path, &output.stdout).unwrap();
+
+    let output = pieuvre().arg(&path).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(4));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("ACCOUNTS_OUTPUT_AS_INPUT"));
+    assert!(stderr.contains("looks like accounts output of pieuvre"));
+}
+
+#[test]
+fn reconcile_exit_code() {
+    let directory = std::env::temp_dir();
+    let external = directory.join(format!("pieuvre-external-{}.csv", std::process::id()));
+    let report = directory.join(format!("pieuvre-reconcile-{}.csv", std::process::id()));
+
+    std::fs::write(&external, "cust_id,balance\n1,1.5\n2,2.0\n3,13.5\n4,123.5\n5,1110.00005\n").unwrap();
+    let output = pieuvre()
+        .args(["data/transactions_complete.csv", "--reconcile-tolerance", "0.0001"])
+        .args(["--reconcile-columns", "client=cust_id,total=balance"])
+        .arg("--reconcile").arg(&external)
+        .arg("--reconcile-report").arg(&report)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_to_string(&report).unwrap(), "client,field,computed,external,delta\n");
+
+    std::fs::write(&external, "cust_id,balance\n1,1.5\n2,2.0\n3,13.5\n4,120\n5,1110\n").unwrap();
+    let output = pieuvre()
+        .args(["data/transactions_complete.csv", "--reconcile-tolerance", "0.0001"])
+        .args(["--reconcile-columns", "client=cust_id,total=balance"])
+        .arg("--reconcile").arg(&external)
+        .arg("--reconcile-report").arg(&report)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(
+        std::fs::read_to_string(&report).unwrap(),
+        "client,field,computed,external,delta\n4,total,123.5,120,3.5\n",
+    );
+
+    std::fs::remove_file(&external).unwrap();
+    std::fs::remove_file(&report).unwrap();
+}
+
+#[test]
+fn input_format_exit_code() {
+    let inputs: [(&str, &[u8], &str); 6] = [
+        ("gz", &[0x1f, 0x8b, 0x08, 0x00, 0x00], "[COMPRESSED_INPUT] this is a gzip compressed file, decompress it first (e.g. with gunzip)"),
+        ("zst", &[0x28, 0xb5, 0x2f, 0xfd, 0x00], "[COMPRESSED_INPUT] this is a zstd compressed file, decompress it first (e.g. with unzstd)"),
+        ("parquet", b"PAR1\x15\x04\x15\x10", "[COLUMNAR_INPUT] this file is in the Parquet format, export it to CSV first"),
+        ("avro", b"Obj\x01\x04\x14avro.codec", "[COLUMNAR_INPUT] this file is in the Avro format, export it to CSV first"),
+        ("utf16", b"\xff\xfet\0y\0p\0e\0,\0", "[UTF16_INPUT] this file is UTF-16 encoded, convert it to UTF-8 first"),
+        ("bin", b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0", "[BINARY_INPUT] not a text CSV"),
+    ];
+
+    for (extension, content, message) in inputs {
+        let path = std::env::temp_dir().join(format!("pieuvre-format-{}.{}", std::process::id(), extension));
+        std::fs::write(&path, content).unwrap();
+        let output = pieuvre().arg(&path).output().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(output.status.code(), Some(6), "{}", extension);
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains(message), "{}: {}", extension, stderr);
+    }
+}
+
+#[test]
+fn malformed_rows() {
+    let directory = std::env::temp_dir().join(format!("pieuvre-malformed-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let cases = [
+        ("deposit,2,2,abc", "[UNPARSABLE_ROW] line 3 (deposit,2,2,abc) is not a transaction: invalid value: string \"abc\""),
+        ("deposit,2,2", "[UNPARSABLE_ROW] line 3 (deposit,2,2) is not a transaction: expected 4 fields, found 3"),
+        ("transfer,2,2,2.0", "[UNKNOWN_TYPE] line 3 (transfer,2,2,2.0): Unknown transaction type transfer"),
+    ];
+    for (row, context) in cases {
+        let input = directory.join("transactions.csv");
+        std::fs::write(&input, format!("type,client,tx,amount\ndeposit,1,1,1.0\n{}\ndeposit,1,3,1.0\n", row)).unwrap();
+
+        let output = pieuvre().arg(&input).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "client,available,held,total,locked\n1,2,0,2,false\n");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.starts_with(context) && stderr.ends_with(", skipped\n"), "{}", stderr);
+
+        let output = pieuvre().arg(&input).arg("--strict").output().unwrap();
+        assert_eq!(output.status.code(), Some(9));
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.starts_with(&format!("Stopped by --strict in {}: {}", input.display(), context)), "{}", stderr);
+        assert!(!stderr.contains("skipped"), "{}", stderr);
+    }
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn multiple_files() {
+    let directory = std::env::temp_dir().join(format!("pieuvre-files-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let complete = std::fs::read_to_string("data/transactions_complete.csv").unwrap();
+    let lines: Vec<&str> = complete.lines().collect();
+    // Disputes of the third day reference deposits of the first two
+    let days: Vec<std::path::PathBuf> = [&lines[1..7], &lines[7..10], &lines[10..]].iter()
+        .enumerate()
+        .map(|(day, rows)| {
+            let path = directory.join(format!("day{}.csv", day + 1));
+            std::fs::write(&path, format!("{}\n{}\n", lines[0], rows.join("\n"))).unwrap();
+            path
+        })
+        .collect();
+
+    let expected = pieuvre().arg("data/transactions_complete.csv").output().unwrap();
+    let output = pieuvre().args(&days).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected.stdout);
+
+    std::fs::write(&days[1], "type,client,tx,amount\ndeposit,4,8,13.5\ndeposit,one,9,110.0\n").unwrap();
+    let output = pieuvre().args(&days).arg("--strict").output().unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(output.status.code(), Some(9));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(&format!("Stopped by --strict in {}: [UNPARSABLE_ROW] line 3", days[1].display())), "{}", stderr);
+}
+
+#[test]
+fn json_lines_input() {
+    let expected = pieuvre().arg("data/transactions_complete.csv").output().unwrap();
+    let output = pieuvre().args(["--input-format", "jsonl", "data/transactions_complete.jsonl"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected.stdout);
+
+    let path = std::env::temp_dir().join(format!("pieuvre-jsonl-{}.jsonl", std::process::id()));
+    std::fs::write(&path, "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.5\"}\n{\"type\":\"deposit\",\"client\":1,\n{\"type\":\"deposit\",\"client\":\"one\",\"tx\":2,\"amount\":\"2\"}\n").unwrap();
+    let output = pieuvre().arg("--input-format").arg("jsonl").arg(&path).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "client,available,held,total,locked\n1,1.5,0,1.5,false\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("[UNPARSABLE_JSON] line 2 is not a transaction"), "{}", stderr);
+    assert!(stderr.contains("[UNPARSABLE_JSON] line 3 is not a transaction"), "{}", stderr);
+}
+
+#[test]
+fn delimiters() {
+    let expected = pieuvre().arg("data/transactions_complete.csv").output().unwrap();
+    let output = pieuvre().args(["--delimiter", ";", "data/transactions_semicolon.csv"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected.stdout);
+
+    let output = pieuvre().args(["--delimiter", ";", "--output-delimiter", "\\t", "data/transactions_semicolon.csv"]).output().unwrap();
+    assert!(output.status.success());
+    let expected = String::from_utf8(expected.stdout).unwrap().replace(',', "\t");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+
+    let output = pieuvre().args(["--delimiter", ";;", "data/transactions_semicolon.csv"]).output().unwrap();
+    assert!(!output.status.success());
+
+    // The subcommands replaying a file read it the same way
+    let expected = pieuvre().args(["checksum", "data/transactions_complete.csv"]).output().unwrap();
+    let output = pieuvre().args(["checksum", "--delimiter", ";", "data/transactions_semicolon.csv"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected.stdout);
+    let expected = pieuvre().args(["explain", "data/transactions_complete.csv", "--tx", "4"]).output().unwrap();
+    let output = pieuvre().args(["explain", "--delimiter", ";", "data/transactions_semicolon.csv", "--tx", "4"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected.stdout);
+}
+
+#[test]
+fn standard_input() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let expected = pieuvre().arg("data/transactions_complete.csv").output().unwrap();
+    let mut child = pieuvre().arg("-").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(&std::fs::read("data/transactions_complete.csv").unwrap()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected.stdout);
+
+    let mut child = pieuvre().arg("-").stdin(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"kind,client\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Invalid input file <stdin>"), "{}", stderr);
+}
+
+#[test]
+fn ragged_inputs() {
+    let expected = "client,available,held,total,locked\n1,0.5,0,0.5,false\n2,1,2,3,false\n";
+    for args in [
+        &["data/transactions_crlf.csv"][..],
+        &["--no-headers", "data/transactions_headerless_crlf.csv"],
+        &["data/transactions_blank_lines.csv"],
+        &["data/transactions_no_final_newline.csv"],
+    ] {
+        let output = pieuvre().args(args).output().unwrap();
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected, "{:?}", args);
+        assert!(output.stderr.is_empty(), "{:?}", args);
+    }
+
+    let output = pieuvre().arg("data/transactions_truncated.csv").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr, "[TRUNCATED_RECORD] last line 7, at byte 108, is missing fields, skipped\n");
+}
+
+#[test]
+fn serve() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::Stdio;
+
+    // Killed even when an assertion fails
+    struct Server(std::process::Child);
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    let mut server = Server(pieuvre().args(["serve", "--listen", "127.0.0.1:0"]).stderr(Stdio::piped()).spawn().unwrap());
+    let mut stderr = BufReader::new(server.0.stderr.take().unwrap());
+    let mut listening = String::new();
+    stderr.read_line(&mut listening).unwrap();
+    let address = listening.trim().strip_prefix("Listening on ").unwrap().to_string();
+
+    let mut stream = std::net::TcpStream::connect(&address).unwrap();
+    let mut replies = BufReader::new(stream.try_clone().unwrap());
+    let mut reply = |line: &str| {
+        writeln!(stream, "{}", line).unwrap();
+        let mut reply = String::new();
+        replies.read_line(&mut reply).unwrap();
+        reply
+    };
+    assert_eq!(reply("deposit,1,1,10.0"), "OK\n");
+    assert_eq!(reply("deposit, 1, 2, 5.0"), "OK\n");
+    assert_eq!(reply("withdrawal,1,3,3.5"), "OK\n");
+    assert_eq!(reply("dispute,1,2"), "OK\n");
+    assert!(reply("withdrawal,1,4,100").starts_with("ERROR [INSUFFICIENT_FUNDS]"));
+    assert!(reply("deposit,one,5,1").starts_with("ERROR [UNPARSABLE_ROW]"));
+
+    // Rows from another connection apply to the same accounts
+    let mut other = std::net::TcpStream::connect(&address).unwrap();
+    writeln!(other, "deposit,2,6,1.5\nACCOUNTS").unwrap();
+    let mut accounts = String::new();
+    let mut other_replies = BufReader::new(other);
+    loop {
+        let mut line = String::new();
+        other_replies.read_line(&mut line).unwrap();
+        if line == "\n" {
+            break;
+        }
+        accounts.push_str(&line);
+    }
+    assert_eq!(accounts, "OK\nclient,available,held,total,locked\n1,6.5,5,11.5,false\n2,1.5,0,1.5,false\n");
+}
+
+#[test]
+fn dry_run() {
+    let output = pieuvre().args(["data/transactions_two_rejections.csv", "--dry-run"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(8));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "input,line,reason,message\n\
+         data/transactions_two_rejections.csv,4,INSUFFICIENT_FUNDS,withdrawal of 5 for client 1 is impossible due to insufficient available funds (2)\n\
+         data/transactions_two_rejections.csv,7,UNKNOWN_TRANSACTION,Can't find transaction id 9 to resolve\n",
+    );
+
+    // Stopped before the first rejected row
+    let output = pieuvre().args(["data/transactions_two_rejections.csv", "--dry-run", "--stop-after-rows", "2"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "input,line,reason,message\n");
+}
+
+#[test]
+fn output_dir() {
+    let parent = std::env::temp_dir().join(format!("pieuvre-output-dir-{}", std::process::id()));
+    std::fs::create_dir_all(&parent).unwrap();
+    let target = parent.join("run");
+
+    // The row that isn't a transaction stops the run once the audit log is started
+    let output = pieuvre().arg("data/transactions_mixed.csv").arg("--output-dir").arg(&target).arg("--strict").output().unwrap();
+    assert_eq!(output.status.code(), Some(9));
+    assert!(!target.exists());
+    let staging: Vec<_> = std::fs::read_dir(&parent).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert_eq!(staging.len(), 1);
+    assert!(staging[0].join("audit.csv").exists());
+    assert!(!staging[0].join("_SUCCESS").exists());
+    std::fs::remove_dir_all(&staging[0]).unwrap();
+
+    let output = pieuvre().arg("data/transactions_complete.csv").arg("--output-dir").arg(&target).output().unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let mut published: Vec<String> = std::fs::read_dir(&target).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    published.sort();
+    assert_eq!(published, ["_SUCCESS", "accounts.csv", "audit.csv", "stats.txt"]);
+    assert_eq!(std::fs::read_dir(&parent).unwrap().count(), 1);
+
+    std::fs::remove_dir_all(&parent).unwrap();
+}
+
+#[test]
+fn closed_stdout() {
+    use std::io::Read;
+    use std::process::Stdio;
+
+    // More accounts than a pipe buffers, so the run is still writing when
+    // the reader goes away
+    let input = std::env::temp_dir().join(format!("pieuvre-closed-stdout-{}.csv", std::process::id()));
+    let rows: String = (1..=60000).map(|id| format!("deposit,{},{},1.0\n", id, id)).collect();
+    std::fs::write(&input, format!("type,client,tx,amount\n{}", rows)).unwrap();
+
+    let mut child = pieuvre().arg(&input).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    let mut header = [0; 8];
+    child.stdout.take().unwrap().read_exact(&mut header).unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&input).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("Cannot write accounts properly: "), "{}", stderr);
+}
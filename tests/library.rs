@@ -0,0 +1,53 @@
+use pieuvre::{Ledger, LedgerError, Transaction, TransactionHandler, TransactionType};
+use rust_decimal_macros::dec;
+
+// Credits existing, unlocked accounts like a deposit, through the public API only
+#[derive(Debug)]
+struct BonusHandler;
+
+impl TransactionHandler for BonusHandler {
+    fn validate(&self, ledger: &Ledger, transaction: &Transaction) -> Result<(), String> {
+        match ledger.get_account(transaction.client_id) {
+            Some(account) if !account.locked => Ok(()),
+            Some(_) => Err("account is locked".to_string()),
+            None => Err("bonuses only go to existing accounts".to_string()),
+        }
+    }
+
+    fn apply(&self, ledger: &mut Ledger, transaction: &Transaction) -> Result<(), LedgerError> {
+        let amount = transaction.amount.ok_or(LedgerError::MissingAmount(transaction.transaction_id))?;
+        ledger.check_unique(transaction)?;
+        ledger.retain_credit(transaction, amount);
+        let account = ledger.account_mut(transaction.client_id);
+        account.available += amount;
+        account.total += amount;
+        account.round();
+        Ok(())
+    }
+}
+
+#[test]
+fn custom_handler() {
+    let bonus = || TransactionType::Custom("bonus".to_string());
+    let mut ledger = Ledger::new();
+    ledger.register_handler(bonus(), BonusHandler);
+
+    ledger.process(&Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(10)))).unwrap();
+    ledger.process(&Transaction::new(bonus(), 1, 2, Some(dec!(2.5)))).unwrap();
+    assert_eq!(ledger.get_account(1).unwrap().available, dec!(12.5));
+
+    let err = ledger.process(&Transaction::new(bonus(), 2, 3, Some(dec!(2.5)))).unwrap_err();
+    assert_eq!(err.code(), "INVALID_TRANSACTION");
+    assert!(ledger.get_account(2).is_none());
+    let err = ledger.process(&Transaction::new(bonus(), 1, 1, Some(dec!(1)))).unwrap_err();
+    assert_eq!(err.code(), "DUPLICATE_TRANSACTION");
+
+    // A retained bonus is disputed and charged back like a deposit
+    ledger.process(&Transaction::new(TransactionType::Dispute, 1, 2, None)).unwrap();
+    let account = ledger.get_account(1).unwrap();
+    assert_eq!((account.available, account.held, account.total), (dec!(10), dec!(2.5), dec!(12.5)));
+    ledger.process(&Transaction::new(TransactionType::Chargeback, 1, 2, None)).unwrap();
+    let account = ledger.get_account(1).unwrap();
+    assert_eq!((account.available, account.held, account.total, account.locked), (dec!(10), dec!(0), dec!(10), true));
+    assert!(ledger.process(&Transaction::new(bonus(), 1, 4, Some(dec!(1)))).is_err());
+}